@@ -1,12 +1,59 @@
 ﻿use crate::assets::AssetManager;
-use crate::components::{CustomWindowFrame, TextEditor};
+use crate::components::text_editor::{CaretShape, Conversion, LineCommand, SortOrder};
+use crate::components::{CustomWindowFrame, PreviewAction, PrintPreview, TextEditor};
+use crate::document::{self, DocumentMetadata, ImageStorage, LineBreak};
+use crate::export;
+use crate::export::page::{PageLayout, PageSetup};
+use crate::export::print::PrintJob;
+use crate::import;
 use eframe::egui;
-use eframe::emath::Vec2;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+
+enum FileAction {
+    New,
+    Open,
+    Import,
+    Save,
+    SaveAs,
+    ConvertToBundle,
+    ConvertToSidecar,
+    ExportHtml,
+    ExportPdf,
+    ExportDocx,
+    ExportOdt,
+    /// Opens the print dialog.
+    Print,
+    /// Sends the document to the printer chosen in the print dialog.
+    SendToPrinter,
+    PrintPreview,
+    /// Lays out the print preview again after the page setup changed.
+    RefreshPreview,
+}
+
+/// The page setup window either confirms settings or leads to a PDF export
+/// or a print job.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PageSetupDialog {
+    PageSetup,
+    ExportPdf,
+    Print,
+}
 
 pub struct NotepadApp {
     assets: AssetManager,
     text_editor: TextEditor,
-    text_content: String
+    text_content: String,
+    file_path: Option<PathBuf>,
+    image_storage: ImageStorage,
+    page_setup: PageSetup,
+    page_setup_dialog: Option<PageSetupDialog>,
+    print_preview: Option<PrintPreview>,
+    print_job: PrintJob,
+    /// Installed printers, `None` until they have been read.
+    printers: Option<Vec<String>>,
+    printer_list: Option<Receiver<Vec<String>>>,
 }
 
 impl Default for NotepadApp {
@@ -14,19 +61,359 @@ impl Default for NotepadApp {
         Self {
             assets: AssetManager::new(),
             text_editor: TextEditor::new(),
-            text_content: String::new()
+            text_content: String::new(),
+            file_path: None,
+            image_storage: ImageStorage::default(),
+            page_setup: PageSetup::default(),
+            page_setup_dialog: None,
+            print_preview: None,
+            print_job: PrintJob::default(),
+            printers: None,
+            printer_list: None,
         }
     }
 }
 
 impl NotepadApp {
     pub fn get_window_title(&self) -> String {
-        "Notula".to_string()
+        match self.file_path.as_ref().and_then(|path| path.file_name()) {
+            Some(name) => format!("{} - Notula", name.to_string_lossy()),
+            None => "Notula".to_string(),
+        }
+    }
+
+    fn new_file(&mut self) {
+        self.text_content.clear();
+        self.file_path = None;
+        self.image_storage = ImageStorage::default();
+        self.text_editor.load_document(&DocumentMetadata::default());
+        self.text_editor.set_line_break(LineBreak::default());
+    }
+
+    fn open_file(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter(
+                "Notula Documents",
+                &["txt", "md", document::BUNDLE_EXTENSION],
+            )
+            .add_filter("All Files", &["*"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        match document::load(&path) {
+            Ok(loaded) => {
+                self.text_content = loaded.text;
+                self.text_editor.load_document(&loaded.metadata);
+                self.text_editor.set_line_break(loaded.line_break);
+                self.image_storage = loaded.storage;
+                self.file_path = Some(path);
+            }
+            Err(err) => log::error!("Failed to open {}: {err}", path.display()),
+        }
+    }
+
+    /// Converts a word processor document into a new, unsaved note.
+    fn import_file(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Word and OpenDocument Text", &import::IMPORT_EXTENSIONS)
+            .pick_file()
+        else {
+            return;
+        };
+
+        match import::import(&path) {
+            Ok((text, metadata)) => {
+                self.text_content = text;
+                self.text_editor.load_document(&metadata);
+                self.text_editor.set_line_break(LineBreak::default());
+                self.image_storage = ImageStorage::default();
+                self.file_path = None;
+            }
+            Err(err) => log::error!("Failed to import {}: {err}", path.display()),
+        }
+    }
+
+    fn save_file(&mut self) {
+        match self.file_path.clone() {
+            Some(path) => self.write_file(path),
+            None => self.save_file_as(),
+        }
+    }
+
+    fn save_file_as(&mut self) {
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Text Documents", &["txt"])
+            .add_filter("Markdown", &["md"])
+            .add_filter("Notula Bundle", &[document::BUNDLE_EXTENSION])
+            .save_file()
+        {
+            self.write_file(path);
+        }
+    }
+
+    /// Saves the document next to the current file in the other storage
+    /// format and continues editing the converted copy.
+    ///
+    /// Bundles keep the text file's extension in their name, `notes.md.notula`,
+    /// so converting back restores `notes.md`. An existing file is never
+    /// overwritten without asking; a save dialog picks the name instead.
+    fn convert_file(&mut self, to_bundle: bool) {
+        let Some(path) = self.file_path.clone() else {
+            self.save_file_as();
+            return;
+        };
+        if document::is_bundle(&path) == to_bundle {
+            return;
+        }
+
+        let target = if to_bundle {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".");
+            name.push(document::BUNDLE_EXTENSION);
+            PathBuf::from(name)
+        } else {
+            let inner = path.with_extension("");
+            let is_text = inner
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("txt"));
+            if is_text || document::is_markdown(&inner) {
+                inner
+            } else {
+                path.with_extension("txt")
+            }
+        };
+
+        if !target.exists() {
+            self.write_file(target);
+            return;
+        }
+
+        let mut dialog = rfd::FileDialog::new();
+        if let Some(directory) = target.parent() {
+            dialog = dialog.set_directory(directory);
+        }
+        if let Some(name) = target.file_name() {
+            dialog = dialog.set_file_name(name.to_string_lossy());
+        }
+        dialog = if to_bundle {
+            dialog.add_filter("Notula Bundle", &[document::BUNDLE_EXTENSION])
+        } else {
+            dialog
+                .add_filter("Text Documents", &["txt"])
+                .add_filter("Markdown", &["md"])
+        };
+        if let Some(target) = dialog.save_file() {
+            self.write_file(target);
+        }
+    }
+
+    /// Name used for files exported from this document.
+    fn document_stem(&self) -> String {
+        self.file_path
+            .as_ref()
+            .and_then(|path| path.file_stem())
+            .map_or_else(
+                || "Untitled".to_string(),
+                |stem| stem.to_string_lossy().into_owned(),
+            )
+    }
+
+    /// File name shown in printed headers and footers.
+    fn document_name(&self) -> String {
+        self.file_path
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map_or_else(
+                || "Untitled".to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            )
+    }
+
+    fn page_layout(&self) -> io::Result<PageLayout> {
+        let metadata = self.text_editor.document_metadata(&self.text_content);
+        export::page::layout(
+            &self.document_name(),
+            &self.text_content,
+            &metadata,
+            &self.page_setup,
+        )
+    }
+
+    fn render_pdf(&self) -> io::Result<Vec<u8>> {
+        let layout = self.page_layout()?;
+        export::pdf::to_pdf(&self.document_stem(), &layout)
+    }
+
+    /// Shows the page setup with the printer choice, reading the installed
+    /// printers in the background the first time.
+    fn open_print_dialog(&mut self, ctx: &egui::Context) {
+        self.page_setup_dialog = Some(PageSetupDialog::Print);
+        if self.printers.is_some() || self.printer_list.is_some() {
+            return;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            if sender.send(export::print::printers()).is_ok() {
+                ctx.request_repaint();
+            }
+        });
+        self.printer_list = Some(receiver);
+    }
+
+    fn print(&mut self) {
+        let result = self
+            .render_pdf()
+            .and_then(|pdf| export::print::print_pdf(&pdf, &self.document_stem(), &self.print_job));
+        if let Err(err) = result {
+            log::error!("Failed to print: {err}");
+        }
+    }
+
+    fn open_print_preview(&mut self) {
+        match self.page_layout() {
+            Ok(layout) => match &mut self.print_preview {
+                Some(preview) => preview.set_layout(layout, self.page_setup.font),
+                None => self.print_preview = Some(PrintPreview::new(layout, self.page_setup.font)),
+            },
+            Err(err) => log::error!("Failed to lay out pages: {err}"),
+        }
+    }
+
+    fn export_html(&mut self) {
+        let stem = self.document_stem();
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("HTML Document", &["html", "htm"])
+            .set_file_name(format!("{stem}.html"))
+            .save_file()
+        else {
+            return;
+        };
+
+        let metadata = self.text_editor.document_metadata(&self.text_content);
+        let markdown = self.file_path.as_deref().is_some_and(document::is_markdown);
+        let html = export::html::to_html(&stem, &self.text_content, &metadata, markdown);
+        if let Err(err) = std::fs::write(&path, html) {
+            log::error!("Failed to export {}: {err}", path.display());
+        }
+    }
+
+    /// Exports to a word processor format: `docx` or `odt`.
+    fn export_office(&mut self, extension: &str) {
+        let stem = self.document_stem();
+        let filter = match extension {
+            "docx" => "Word Document",
+            _ => "OpenDocument Text",
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter(filter, &[extension])
+            .set_file_name(format!("{stem}.{extension}"))
+            .save_file()
+        else {
+            return;
+        };
+
+        let metadata = self.text_editor.document_metadata(&self.text_content);
+        let markdown = self.file_path.as_deref().is_some_and(document::is_markdown);
+        let result = match extension {
+            "docx" => export::docx::to_docx(&self.text_content, &metadata, markdown),
+            _ => export::odt::to_odt(&self.text_content, &metadata, markdown),
+        }
+        .and_then(|bytes| std::fs::write(&path, bytes));
+        if let Err(err) = result {
+            log::error!("Failed to export {}: {err}", path.display());
+        }
+    }
+
+    fn export_pdf(&mut self) {
+        let stem = self.document_stem();
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("PDF Document", &["pdf"])
+            .set_file_name(format!("{stem}.pdf"))
+            .save_file()
+        else {
+            return;
+        };
+
+        let result = self.render_pdf().and_then(|pdf| std::fs::write(&path, pdf));
+        if let Err(err) = result {
+            log::error!("Failed to export {}: {err}", path.display());
+        }
+    }
+
+    /// Page settings, also shown before choosing where to save a PDF.
+    fn show_page_setup(
+        &mut self,
+        ctx: &egui::Context,
+        dialog: PageSetupDialog,
+    ) -> Option<FileAction> {
+        let mut action = None;
+        let mut open = true;
+        let mut cancelled = false;
+        let (title, confirm, confirm_action) = match dialog {
+            PageSetupDialog::PageSetup => ("Page Setup", "OK", FileAction::RefreshPreview),
+            PageSetupDialog::ExportPdf => ("Export PDF", "Export...", FileAction::ExportPdf),
+            PageSetupDialog::Print => ("Print", "Print", FileAction::SendToPrinter),
+        };
+        if let Some(printers) = self
+            .printer_list
+            .as_ref()
+            .and_then(|list| list.try_recv().ok())
+        {
+            self.printers = Some(printers);
+            self.printer_list = None;
+        }
+
+        egui::Window::new(title)
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                if dialog == PageSetupDialog::Print {
+                    self.print_job.ui(ui, self.printers.as_deref());
+                    ui.separator();
+                }
+                self.page_setup.ui(ui);
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button(confirm).clicked() {
+                        action = Some(confirm_action);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        cancelled = true;
+                    }
+                });
+            });
+
+        if !open || cancelled || action.is_some() {
+            self.page_setup_dialog = None;
+        }
+        action
+    }
+
+    fn write_file(&mut self, path: PathBuf) {
+        let metadata = self.text_editor.document_metadata(&self.text_content);
+        match document::save(
+            &path,
+            &self.text_content,
+            &metadata,
+            self.image_storage,
+            self.text_editor.line_break(),
+        ) {
+            Ok(()) => self.file_path = Some(path),
+            Err(err) => log::error!("Failed to save {}: {err}", path.display()),
+        }
     }
 }
 
 impl eframe::App for NotepadApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut file_action = None;
+
         CustomWindowFrame::show(ctx, &self.get_window_title(), &self.assets, |ui| {
             // Vertical layout for toolbar + text area
             ui.vertical(|ui| {
@@ -34,24 +421,204 @@ impl eframe::App for NotepadApp {
                 ui.horizontal(|ui| {
                     ui.menu_button("File", |ui| {
                         if ui.button("New").clicked() {
-                            self.text_content.clear();
+                            file_action = Some(FileAction::New);
                             ui.close_menu();
                         }
                         if ui.button("Open...").clicked() {
-                            // TODO: File dialog
+                            file_action = Some(FileAction::Open);
+                            ui.close_menu();
+                        }
+                        if ui.button("Import...").clicked() {
+                            file_action = Some(FileAction::Import);
                             ui.close_menu();
                         }
                         if ui.button("Save").clicked() {
-                            // TODO: Save file
+                            file_action = Some(FileAction::Save);
                             ui.close_menu();
                         }
                         if ui.button("Save As...").clicked() {
-                            // TODO: Save As dialog
+                            file_action = Some(FileAction::SaveAs);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        if ui.button("Page Setup...").clicked() {
+                            self.page_setup_dialog = Some(PageSetupDialog::PageSetup);
+                            ui.close_menu();
+                        }
+                        if ui.button("Print Preview...").clicked() {
+                            file_action = Some(FileAction::PrintPreview);
+                            ui.close_menu();
+                        }
+                        if ui.button("Print...").clicked() {
+                            file_action = Some(FileAction::Print);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        ui.menu_button("Export", |ui| {
+                            if ui.button("HTML...").clicked() {
+                                file_action = Some(FileAction::ExportHtml);
+                                ui.close_menu();
+                            }
+                            if ui.button("PDF...").clicked() {
+                                self.page_setup_dialog = Some(PageSetupDialog::ExportPdf);
+                                ui.close_menu();
+                            }
+                            if ui.button("Word Document (.docx)...").clicked() {
+                                file_action = Some(FileAction::ExportDocx);
+                                ui.close_menu();
+                            }
+                            if ui.button("OpenDocument Text (.odt)...").clicked() {
+                                file_action = Some(FileAction::ExportOdt);
+                                ui.close_menu();
+                            }
+                        });
+
+                        ui.separator();
+
+                        let is_bundle = self.file_path.as_deref().is_some_and(document::is_bundle);
+                        if ui
+                            .add_enabled(
+                                !is_bundle,
+                                egui::Button::new("Convert to Bundle (.notula)"),
+                            )
+                            .on_hover_text("Save text and images together in a single file")
+                            .clicked()
+                        {
+                            file_action = Some(FileAction::ConvertToBundle);
                             ui.close_menu();
                         }
+                        if ui
+                            .add_enabled(is_bundle, egui::Button::new("Convert to Text + Sidecar"))
+                            .on_hover_text("Save as a plain text file with a .meta file next to it")
+                            .clicked()
+                        {
+                            file_action = Some(FileAction::ConvertToSidecar);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        let mut keep_orphaned = self.text_editor.keep_orphaned_images();
+                        if ui
+                            .checkbox(&mut keep_orphaned, "Keep Orphaned Images")
+                            .on_hover_text("Keep images whose placeholder was deleted when saving")
+                            .changed()
+                        {
+                            self.text_editor.set_keep_orphaned_images(keep_orphaned);
+                        }
+
+                        let mut use_assets = self.image_storage == ImageStorage::AssetsFolder;
+                        if ui
+                            .add_enabled(
+                                !is_bundle,
+                                egui::Checkbox::new(&mut use_assets, "Store Images in Assets Folder"),
+                            )
+                            .on_hover_text(
+                                "Save images as real files in a <name>.assets folder instead of the .meta file",
+                            )
+                            .changed()
+                        {
+                            self.image_storage = if use_assets {
+                                ImageStorage::AssetsFolder
+                            } else {
+                                ImageStorage::Sidecar
+                            };
+                        }
                     });
 
                     ui.menu_button("Edit", |ui| {
+                        if ui
+                            .add_enabled(self.text_editor.can_undo(), egui::Button::new("Undo"))
+                            .clicked()
+                        {
+                            self.text_editor.undo(&mut self.text_content);
+                            ui.close_menu();
+                        }
+                        if ui
+                            .add_enabled(self.text_editor.can_redo(), egui::Button::new("Redo"))
+                            .clicked()
+                        {
+                            self.text_editor.redo(&mut self.text_content);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        if ui.button("Insert Sketch").clicked() {
+                            self.text_editor.insert_sketch(&mut self.text_content);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        for (label, shortcut, command) in [
+                            ("Duplicate Line", "Ctrl+Shift+D", LineCommand::Duplicate),
+                            ("Move Line Up", "Alt+Up", LineCommand::MoveUp),
+                            ("Move Line Down", "Alt+Down", LineCommand::MoveDown),
+                            ("Delete Current Line", "Ctrl+Shift+K", LineCommand::Delete),
+                            ("Join Lines", "Ctrl+J", LineCommand::Join),
+                        ] {
+                            if ui
+                                .add(egui::Button::new(label).shortcut_text(shortcut))
+                                .clicked()
+                            {
+                                self.text_editor
+                                    .line_command(&mut self.text_content, command);
+                                ui.close_menu();
+                            }
+                        }
+
+                        ui.menu_button("Convert", |ui| {
+                            for conversion in Conversion::ALL {
+                                if ui.button(conversion.name()).clicked() {
+                                    self.text_editor
+                                        .convert_selection(&mut self.text_content, conversion);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                        if ui
+                            .add(egui::Button::new("Insert Date/Time").shortcut_text("F5"))
+                            .clicked()
+                        {
+                            self.text_editor.insert_date_time(&mut self.text_content);
+                            ui.close_menu();
+                        }
+
+                        ui.separator();
+
+                        // These work on a block of selected lines
+                        let has_lines = self.text_editor.has_line_selection();
+                        ui.add_enabled_ui(has_lines, |ui| {
+                            ui.menu_button("Sort Lines", |ui| {
+                                for order in SortOrder::ALL {
+                                    if ui.button(order.name()).clicked() {
+                                        self.text_editor.line_command(
+                                            &mut self.text_content,
+                                            LineCommand::Sort(order),
+                                        );
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                            for (label, command) in [
+                                ("Remove Duplicate Lines", LineCommand::RemoveDuplicates),
+                                ("Reverse Lines", LineCommand::Reverse),
+                            ] {
+                                if ui.button(label).clicked() {
+                                    self.text_editor
+                                        .line_command(&mut self.text_content, command);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
+                        ui.separator();
+
                         if ui.button("Select All").clicked() {
                             // TODO: Select all text
                             ui.close_menu();
@@ -66,6 +633,103 @@ impl eframe::App for NotepadApp {
                         }
                     });
 
+                    ui.menu_button("View", |ui| {
+                        let mut animate_images = self.text_editor.animate_images();
+                        if ui.checkbox(&mut animate_images, "Animate Images").changed() {
+                            self.text_editor.set_animate_images(animate_images);
+                        }
+
+                        let mut show_whitespace = self.text_editor.show_whitespace();
+                        if ui
+                            .checkbox(&mut show_whitespace, "Show Whitespace")
+                            .on_hover_text("Mark spaces, tabs, invisible characters and line endings")
+                            .changed()
+                        {
+                            self.text_editor.set_show_whitespace(show_whitespace);
+                        }
+
+                        let mut highlight = self.text_editor.highlight_current_line();
+                        if ui.checkbox(&mut highlight, "Highlight Current Line").changed() {
+                            self.text_editor.set_highlight_current_line(highlight);
+                        }
+
+                        ui.separator();
+
+                        let mut blink = self.text_editor.caret_blinks();
+                        if ui
+                            .checkbox(&mut blink, "Blink Caret")
+                            .on_hover_text("At the system's blink rate, stopping when idle")
+                            .changed()
+                        {
+                            self.text_editor.set_caret_blinks(blink);
+                        }
+
+                        ui.menu_button("Caret Style", |ui| {
+                            let shape = self.text_editor.caret_shape();
+                            for option in CaretShape::ALL {
+                                if ui.radio(shape == option, option.name()).clicked() {
+                                    self.text_editor.set_caret_shape(option);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
+                        ui.menu_button("Caret Width", |ui| {
+                            let caret_width = self.text_editor.caret_width();
+                            for width in [1.0, 2.0, 3.0] {
+                                if ui
+                                    .radio(caret_width == width, format!("{width} px"))
+                                    .clicked()
+                                {
+                                    self.text_editor.set_caret_width(width);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
+                        ui.separator();
+
+                        let mut spaces = self.text_editor.indent_with_spaces();
+                        if ui.checkbox(&mut spaces, "Indent Using Spaces").changed() {
+                            self.text_editor.set_indent_with_spaces(spaces);
+                        }
+
+                        ui.menu_button("Tab Width", |ui| {
+                            let tab_width = self.text_editor.tab_width();
+                            for width in [2, 4, 8] {
+                                if ui.radio(tab_width == width, width.to_string()).clicked() {
+                                    self.text_editor.set_tab_width(width);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+
+                        let mut visual = self.text_editor.visual_cursor_movement();
+                        if ui
+                            .checkbox(&mut visual, "Visual Cursor Movement")
+                            .on_hover_text("Arrow keys follow right-to-left text as displayed")
+                            .changed()
+                        {
+                            self.text_editor.set_visual_cursor_movement(visual);
+                        }
+
+                        ui.separator();
+
+                        ui.menu_button("Image Memory Budget", |ui| {
+                            let budget = self.text_editor.texture_budget();
+                            for megabytes in [64, 128, 256, 512, 1024] {
+                                let bytes = megabytes * 1024 * 1024;
+                                if ui
+                                    .radio(budget == bytes, format!("{megabytes} MB"))
+                                    .clicked()
+                                {
+                                    self.text_editor.set_texture_budget(bytes);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                    });
+
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                         egui::widgets::global_theme_preference_buttons(ui);
                     });
@@ -73,12 +737,50 @@ impl eframe::App for NotepadApp {
 
                 ui.separator();
 
-                let image_data = egui::ColorImage::new([64, 64], egui::Color32::from_rgb(255, 100, 100));
-                let texture = ctx.load_texture("test_image", image_data, egui::TextureOptions::default());
-                self.text_editor.add_image(1, texture, Vec2::new(64.0, 64.0));
-
                 self.text_editor.show(ui, &mut self.text_content)
             });
         });
+
+        if let Some(preview) = &mut self.print_preview {
+            let mut open = true;
+            let action = preview.show(ctx, &mut open);
+            if !open {
+                self.print_preview = None;
+            }
+            match action {
+                Some(PreviewAction::PageSetup) => {
+                    self.page_setup_dialog = Some(PageSetupDialog::PageSetup);
+                }
+                Some(PreviewAction::Print) => file_action = Some(FileAction::Print),
+                Some(PreviewAction::SavePdf) => file_action = Some(FileAction::ExportPdf),
+                None => {}
+            }
+        }
+
+        if let Some(dialog) = self.page_setup_dialog {
+            file_action = self.show_page_setup(ctx, dialog).or(file_action);
+        }
+
+        // File dialogs block, so run them outside of the frame's UI closure
+        match file_action {
+            Some(FileAction::New) => self.new_file(),
+            Some(FileAction::Open) => self.open_file(),
+            Some(FileAction::Import) => self.import_file(),
+            Some(FileAction::Save) => self.save_file(),
+            Some(FileAction::SaveAs) => self.save_file_as(),
+            Some(FileAction::ConvertToBundle) => self.convert_file(true),
+            Some(FileAction::ConvertToSidecar) => self.convert_file(false),
+            Some(FileAction::ExportHtml) => self.export_html(),
+            Some(FileAction::ExportPdf) => self.export_pdf(),
+            Some(FileAction::ExportDocx) => self.export_office("docx"),
+            Some(FileAction::ExportOdt) => self.export_office("odt"),
+            Some(FileAction::Print) => self.open_print_dialog(ctx),
+            Some(FileAction::SendToPrinter) => self.print(),
+            Some(FileAction::PrintPreview) => self.open_print_preview(),
+            Some(FileAction::RefreshPreview) if self.print_preview.is_some() => {
+                self.open_print_preview();
+            }
+            Some(FileAction::RefreshPreview) | None => {}
+        }
     }
 }
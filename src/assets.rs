@@ -1,18 +1,11 @@
 ﻿// src/assets.rs
 use crate::icons::Icons;
 
+#[derive(Default)]
 pub struct AssetManager {
     pub icons: Icons,
 }
 
-impl Default for AssetManager {
-    fn default() -> Self {
-        Self {
-            icons: Icons::default(),
-        }
-    }
-}
-
 impl AssetManager {
     pub fn new() -> Self {
         Self::default()
@@ -1,5 +1,8 @@
 ﻿pub mod text_editor;
 pub use text_editor::TextEditor;
 
+pub mod print_preview;
+pub use print_preview::{PreviewAction, PrintPreview};
+
 pub mod window_frame;
 pub use window_frame::CustomWindowFrame;
\ No newline at end of file
@@ -0,0 +1,176 @@
+﻿use crate::components::text_editor::{decode_still, paint_sketch};
+use crate::export::page::{PageFont, PageItem, PageLayout};
+use eframe::egui::{self, Color32, ColorImage, FontFamily, FontId, Pos2, Rect, Stroke, Vec2};
+use std::collections::HashMap;
+
+const PAGE_SHADOW: f32 = 4.0;
+
+/// What the user asked for from the preview window.
+pub enum PreviewAction {
+    PageSetup,
+    Print,
+    SavePdf,
+}
+
+/// A window showing the document one page at a time, as it will be printed.
+pub struct PrintPreview {
+    layout: PageLayout,
+    family: FontFamily,
+    page: usize,
+    /// Images are uploaded when first shown; `None` when they failed to decode.
+    textures: HashMap<usize, Option<egui::TextureHandle>>,
+}
+
+impl PrintPreview {
+    pub fn new(layout: PageLayout, font: PageFont) -> Self {
+        Self {
+            layout,
+            family: font_family(font),
+            page: 0,
+            textures: HashMap::new(),
+        }
+    }
+
+    /// Replaces the pages after the page setup changed, staying on the same
+    /// page where possible.
+    pub fn set_layout(&mut self, layout: PageLayout, font: PageFont) {
+        self.page = self.page.min(layout.pages.len().saturating_sub(1));
+        self.layout = layout;
+        self.family = font_family(font);
+        self.textures.clear();
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) -> Option<PreviewAction> {
+        let mut action = None;
+
+        egui::Window::new("Print Preview")
+            .open(open)
+            .collapsible(false)
+            .default_size([520.0, 720.0])
+            .show(ctx, |ui| {
+                let page_count = self.layout.pages.len();
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(self.page > 0, egui::Button::new("◀"))
+                        .clicked()
+                    {
+                        self.page -= 1;
+                    }
+                    ui.label(format!("Page {} of {page_count}", self.page + 1));
+                    if ui
+                        .add_enabled(self.page + 1 < page_count, egui::Button::new("▶"))
+                        .clicked()
+                    {
+                        self.page += 1;
+                    }
+
+                    ui.separator();
+                    if ui.button("Page Setup...").clicked() {
+                        action = Some(PreviewAction::PageSetup);
+                    }
+                    if ui.button("Print...").clicked() {
+                        action = Some(PreviewAction::Print);
+                    }
+                    if ui.button("Save as PDF...").clicked() {
+                        action = Some(PreviewAction::SavePdf);
+                    }
+                });
+                ui.separator();
+
+                self.paint_page(ui);
+            });
+
+        action
+    }
+
+    fn paint_page(&mut self, ui: &mut egui::Ui) {
+        let available = ui.available_size() - Vec2::splat(PAGE_SHADOW);
+        let scale = (available.x / self.layout.width)
+            .min(available.y / self.layout.height)
+            .max(0.1);
+        let size = Vec2::new(self.layout.width, self.layout.height) * scale;
+        let (rect, _) =
+            ui.allocate_exact_size(size + Vec2::splat(PAGE_SHADOW), egui::Sense::hover());
+        let page_rect = Rect::from_min_size(rect.min, size);
+
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(
+            page_rect.translate(Vec2::splat(PAGE_SHADOW)),
+            0.0,
+            Color32::from_black_alpha(60),
+        );
+        painter.rect_filled(page_rect, 0.0, Color32::WHITE);
+
+        let to_screen = |x: f32, y: f32| page_rect.min + Vec2::new(x, y) * scale;
+        let Some(page) = self.layout.pages.get(self.page) else {
+            return;
+        };
+
+        for item in &page.items {
+            match item {
+                PageItem::Text {
+                    position,
+                    text,
+                    weak,
+                    ..
+                } => {
+                    let color = if *weak { Color32::GRAY } else { Color32::BLACK };
+                    painter.text(
+                        to_screen(position[0], position[1] - self.layout.ascent),
+                        egui::Align2::LEFT_TOP,
+                        text,
+                        FontId::new(self.layout.font_size * scale, self.family.clone()),
+                        color,
+                    );
+                }
+                PageItem::Image { rect, image } => {
+                    let texture = self.textures.entry(*image).or_insert_with(|| {
+                        let bytes = &self.layout.images[*image].bytes;
+                        match decode_still(bytes) {
+                            Ok(decoded) => Some(ui.ctx().load_texture(
+                                format!("print_preview_{image}"),
+                                ColorImage::from_rgba_unmultiplied(
+                                    [decoded.width() as usize, decoded.height() as usize],
+                                    decoded.as_raw(),
+                                ),
+                                Default::default(),
+                            )),
+                            Err(err) => {
+                                log::warn!("Cannot preview image {image}: {err}");
+                                None
+                            }
+                        }
+                    });
+                    let screen_rect = Rect::from_min_size(
+                        to_screen(rect[0], rect[1]),
+                        Vec2::new(rect[2], rect[3]) * scale,
+                    );
+                    if let Some(texture) = texture {
+                        painter.image(
+                            texture.id(),
+                            screen_rect,
+                            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+                            Color32::WHITE,
+                        );
+                    }
+                }
+                PageItem::Sketch { rect, sketch } => {
+                    let screen_rect = Rect::from_min_size(
+                        to_screen(rect[0], rect[1]),
+                        Vec2::new(rect[2], rect[3]) * scale,
+                    );
+                    // A thin outline like the printed sketch
+                    let border = Stroke::new(1.0, Color32::LIGHT_GRAY);
+                    paint_sketch(&painter, screen_rect, sketch, border);
+                }
+            }
+        }
+    }
+}
+
+fn font_family(font: PageFont) -> FontFamily {
+    match font {
+        PageFont::Monospace => FontFamily::Monospace,
+        PageFont::Proportional => FontFamily::Proportional,
+    }
+}
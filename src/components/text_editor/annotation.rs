@@ -0,0 +1,402 @@
+﻿use super::image::decode_still;
+use crate::document::Annotation;
+use crate::fonts;
+use ab_glyph::{Font, FontArc, OutlineCurve, PxScale, PxScaleFont, ScaleFont};
+use egui::emath::Rot2;
+use egui::{Align2, Color32, FontId, Painter, Pos2, Rect, Shape, Stroke, StrokeKind, Vec2};
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage};
+use std::io::Cursor;
+use tiny_skia::{ColorU8, FillRule, LineCap, LineJoin, Paint, PathBuilder, Pixmap, Transform};
+
+pub const CALLOUT_RADIUS: f32 = 12.0;
+const CALLOUT_FONT_SIZE: f32 = 14.0;
+const ARROW_HEAD_ANGLE: f32 = 0.45;
+
+/// Paints `annotations` over an image drawn in `image_rect`, where `scale`
+/// converts image pixels to points.
+pub fn paint_annotations(
+    painter: &Painter,
+    image_rect: Rect,
+    scale: f32,
+    annotations: &[Annotation],
+) {
+    let to_screen = |point: &[f32; 2]| image_rect.min + Vec2::new(point[0], point[1]) * scale;
+
+    for annotation in annotations {
+        match annotation {
+            Annotation::Arrow {
+                from,
+                to,
+                color,
+                width,
+            } => {
+                let (from, to) = (to_screen(from), to_screen(to));
+                let stroke = Stroke::new(width * scale, to_color32(*color));
+                painter.line_segment([from, to], stroke);
+                for barb in arrow_head(from, to, width * scale) {
+                    painter.line_segment([to, barb], stroke);
+                }
+            }
+            Annotation::Rectangle {
+                min,
+                max,
+                color,
+                width,
+            } => {
+                painter.rect_stroke(
+                    Rect::from_two_pos(to_screen(min), to_screen(max)),
+                    0.0,
+                    Stroke::new(width * scale, to_color32(*color)),
+                    StrokeKind::Middle,
+                );
+            }
+            Annotation::Highlight {
+                points,
+                color,
+                width,
+            } => {
+                painter.add(Shape::line(
+                    points.iter().map(to_screen).collect(),
+                    Stroke::new(width * scale, to_color32(*color)),
+                ));
+            }
+            Annotation::Callout {
+                center,
+                number,
+                color,
+            } => {
+                let center = to_screen(center);
+                painter.circle_filled(center, CALLOUT_RADIUS * scale, to_color32(*color));
+                painter.text(
+                    center,
+                    Align2::CENTER_CENTER,
+                    number.to_string(),
+                    FontId::proportional(CALLOUT_FONT_SIZE * scale),
+                    Color32::WHITE,
+                );
+            }
+            Annotation::Label {
+                position,
+                text,
+                color,
+                size,
+            } => {
+                painter.text(
+                    to_screen(position),
+                    Align2::LEFT_TOP,
+                    text,
+                    FontId::proportional(size * scale),
+                    to_color32(*color),
+                );
+            }
+        }
+    }
+}
+
+/// Encodes the image with its annotations burned into the pixels.
+///
+/// Images without annotations are returned untouched, so animated and SVG
+/// images keep their original format.
+pub fn flatten(source: &[u8], annotations: &[Annotation]) -> ImageResult<Vec<u8>> {
+    if annotations.is_empty() {
+        return Ok(source.to_vec());
+    }
+
+    let image = decode_still(source)?;
+    let Some(mut pixmap) = Pixmap::new(image.width(), image.height()) else {
+        return Ok(source.to_vec());
+    };
+    for (dst, src) in pixmap.pixels_mut().iter_mut().zip(image.pixels()) {
+        *dst = ColorU8::from_rgba(src[0], src[1], src[2], src[3]).premultiply();
+    }
+
+    let fonts = label_fonts();
+    for annotation in annotations {
+        draw_annotation(&mut pixmap, &fonts, annotation);
+    }
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let flattened = RgbaImage::from_raw(image.width(), image.height(), pixels)
+        .expect("pixmap has the same size as the image");
+
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgba8(flattened).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+/// Moves `annotations` along with the pixels under them when an image is
+/// cropped, rotated or flipped, `map` taking a point of the old image to the
+/// new one. Annotations left entirely outside the new image of `size` pixels
+/// are dropped.
+pub fn map_annotations(
+    annotations: &[Annotation],
+    map: impl Fn([f32; 2]) -> [f32; 2],
+    size: Vec2,
+) -> Vec<Annotation> {
+    let bounds = Rect::from_min_size(Pos2::ZERO, size);
+    let mut kept = Vec::with_capacity(annotations.len());
+
+    for annotation in annotations {
+        let mut annotation = annotation.clone();
+        let points = match &mut annotation {
+            Annotation::Arrow { from, to, .. } => vec![from, to],
+            Annotation::Rectangle { min, max, .. } => vec![min, max],
+            Annotation::Highlight { points, .. } => points.iter_mut().collect(),
+            Annotation::Callout { center, .. } => vec![center],
+            Annotation::Label { position, .. } => vec![position],
+        };
+
+        let mut extent = Rect::NOTHING;
+        for point in points {
+            *point = map(*point);
+            extent.extend_with(Pos2::new(point[0], point[1]));
+        }
+        // Rotating and flipping can swap the corners of a box
+        if let Annotation::Rectangle { min, max, .. } = &mut annotation {
+            *min = [extent.min.x, extent.min.y];
+            *max = [extent.max.x, extent.max.y];
+        }
+
+        if extent.intersects(bounds) {
+            kept.push(annotation);
+        }
+    }
+    kept
+}
+
+pub fn to_color32(color: [u8; 4]) -> Color32 {
+    Color32::from_rgba_unmultiplied(color[0], color[1], color[2], color[3])
+}
+
+/// The two barb end points of an arrow head pointing at `to`.
+fn arrow_head(from: Pos2, to: Pos2, width: f32) -> [Pos2; 2] {
+    let length = (width * 4.0).max(10.0);
+    let back = -(to - from).normalized() * length;
+    [
+        to + Rot2::from_angle(ARROW_HEAD_ANGLE) * back,
+        to + Rot2::from_angle(-ARROW_HEAD_ANGLE) * back,
+    ]
+}
+
+fn draw_annotation(pixmap: &mut Pixmap, fonts: &[FontArc], annotation: &Annotation) {
+    let stroke = |width: f32| tiny_skia::Stroke {
+        width,
+        line_cap: LineCap::Round,
+        line_join: LineJoin::Round,
+        ..Default::default()
+    };
+
+    match annotation {
+        Annotation::Arrow {
+            from,
+            to,
+            color,
+            width,
+        } => {
+            let (from, to) = (Pos2::new(from[0], from[1]), Pos2::new(to[0], to[1]));
+            let mut builder = PathBuilder::new();
+            builder.move_to(from.x, from.y);
+            builder.line_to(to.x, to.y);
+            for barb in arrow_head(from, to, *width) {
+                builder.move_to(to.x, to.y);
+                builder.line_to(barb.x, barb.y);
+            }
+            if let Some(path) = builder.finish() {
+                pixmap.stroke_path(
+                    &path,
+                    &paint(*color),
+                    &stroke(*width),
+                    Transform::identity(),
+                    None,
+                );
+            }
+        }
+        Annotation::Rectangle {
+            min,
+            max,
+            color,
+            width,
+        } => {
+            let rect = Rect::from_two_pos(Pos2::new(min[0], min[1]), Pos2::new(max[0], max[1]));
+            if let Some(rect) =
+                tiny_skia::Rect::from_ltrb(rect.left(), rect.top(), rect.right(), rect.bottom())
+            {
+                let mut line_stroke = stroke(*width);
+                line_stroke.line_cap = LineCap::Square;
+                pixmap.stroke_path(
+                    &PathBuilder::from_rect(rect),
+                    &paint(*color),
+                    &line_stroke,
+                    Transform::identity(),
+                    None,
+                );
+            }
+        }
+        Annotation::Highlight {
+            points,
+            color,
+            width,
+        } => {
+            let mut builder = PathBuilder::new();
+            for (index, point) in points.iter().enumerate() {
+                if index == 0 {
+                    builder.move_to(point[0], point[1]);
+                } else {
+                    builder.line_to(point[0], point[1]);
+                }
+            }
+            if let Some(path) = builder.finish() {
+                pixmap.stroke_path(
+                    &path,
+                    &paint(*color),
+                    &stroke(*width),
+                    Transform::identity(),
+                    None,
+                );
+            }
+        }
+        Annotation::Callout {
+            center,
+            number,
+            color,
+        } => {
+            if let Some(circle) = PathBuilder::from_circle(center[0], center[1], CALLOUT_RADIUS) {
+                pixmap.fill_path(
+                    &circle,
+                    &paint(*color),
+                    FillRule::Winding,
+                    Transform::identity(),
+                    None,
+                );
+            }
+            if !fonts.is_empty() {
+                let text = number.to_string();
+                let (width, height) = text_size(fonts, &text, CALLOUT_FONT_SIZE);
+                let origin = Pos2::new(center[0] - width / 2.0, center[1] - height / 2.0);
+                fill_text(pixmap, fonts, &text, CALLOUT_FONT_SIZE, origin, [255; 4]);
+            }
+        }
+        Annotation::Label {
+            position,
+            text,
+            color,
+            size,
+        } => {
+            if !fonts.is_empty() {
+                let origin = Pos2::new(position[0], position[1]);
+                fill_text(pixmap, fonts, text, *size, origin, *color);
+            }
+        }
+    }
+}
+
+fn paint(color: [u8; 4]) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(color[0], color[1], color[2], color[3]);
+    paint.anti_alias = true;
+    paint
+}
+
+/// The proportional fonts egui uses for labels in fallback order, so exports
+/// match the editor.
+fn label_fonts() -> Vec<FontArc> {
+    fonts::family_fonts(&egui::FontFamily::Proportional)
+        .into_iter()
+        .map(|font| font.font)
+        .collect()
+}
+
+/// The font of `fonts` drawing `c`, scaled to `size`.
+fn scaled_font(fonts: &[FontArc], c: char, size: f32) -> PxScaleFont<&FontArc> {
+    fonts[fonts::font_for(fonts, c)].as_scaled(PxScale::from(size))
+}
+
+fn text_size(fonts: &[FontArc], text: &str, size: f32) -> (f32, f32) {
+    let width = text
+        .chars()
+        .map(|c| {
+            let scaled = scaled_font(fonts, c, size);
+            scaled.h_advance(scaled.glyph_id(c))
+        })
+        .sum();
+    let scaled = fonts[0].as_scaled(PxScale::from(size));
+    (width, scaled.ascent() - scaled.descent())
+}
+
+/// Fills `text` as glyph outlines with its top-left corner at `origin`.
+fn fill_text(
+    pixmap: &mut Pixmap,
+    fonts: &[FontArc],
+    text: &str,
+    size: f32,
+    origin: Pos2,
+    color: [u8; 4],
+) {
+    let baseline = origin.y + fonts[0].as_scaled(PxScale::from(size)).ascent();
+
+    let mut builder = PathBuilder::new();
+    let mut x = origin.x;
+    for c in text.chars() {
+        let scaled = scaled_font(fonts, c, size);
+        let factor = scaled.scale_factor();
+        let glyph_id = scaled.glyph_id(c);
+        if let Some(outline) = scaled.font.outline(glyph_id) {
+            // Font units are y-up, pixels are y-down
+            let map = |point: ab_glyph::Point| {
+                (
+                    x + point.x * factor.horizontal,
+                    baseline - point.y * factor.vertical,
+                )
+            };
+
+            let mut last = None;
+            for curve in &outline.curves {
+                let start = match curve {
+                    OutlineCurve::Line(p0, _)
+                    | OutlineCurve::Quad(p0, _, _)
+                    | OutlineCurve::Cubic(p0, _, _, _) => map(*p0),
+                };
+                if last != Some(start) {
+                    builder.move_to(start.0, start.1);
+                }
+
+                let end = match curve {
+                    OutlineCurve::Line(_, p1) => {
+                        let p1 = map(*p1);
+                        builder.line_to(p1.0, p1.1);
+                        p1
+                    }
+                    OutlineCurve::Quad(_, p1, p2) => {
+                        let (p1, p2) = (map(*p1), map(*p2));
+                        builder.quad_to(p1.0, p1.1, p2.0, p2.1);
+                        p2
+                    }
+                    OutlineCurve::Cubic(_, p1, p2, p3) => {
+                        let (p1, p2, p3) = (map(*p1), map(*p2), map(*p3));
+                        builder.cubic_to(p1.0, p1.1, p2.0, p2.1, p3.0, p3.1);
+                        p3
+                    }
+                };
+                last = Some(end);
+            }
+        }
+        x += scaled.h_advance(glyph_id);
+    }
+
+    if let Some(path) = builder.finish() {
+        pixmap.fill_path(
+            &path,
+            &paint(color),
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+    }
+}
@@ -0,0 +1,269 @@
+﻿use super::annotation::{CALLOUT_RADIUS, paint_annotations};
+use super::image::decode_still;
+use crate::document::Annotation;
+use egui::{Color32, ColorImage, Context, Pos2, Rect, Sense, TextureHandle, Vec2};
+use image::ImageResult;
+
+const PREVIEW_MAX_SIZE: Vec2 = Vec2::new(560.0, 420.0);
+const HIGHLIGHT_ALPHA: u8 = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnnotationTool {
+    Arrow,
+    Rectangle,
+    Highlight,
+    Callout,
+    Label,
+}
+
+pub enum AnnotationEditorResult {
+    Apply(Vec<Annotation>),
+    Cancel,
+}
+
+/// Overlay window for drawing annotations over one image of the document.
+pub struct AnnotationEditor {
+    image_id: usize,
+    image_size: Vec2,
+    preview: TextureHandle,
+    annotations: Vec<Annotation>,
+    tool: AnnotationTool,
+    color: Color32,
+    width: f32,
+    label_text: String,
+    label_size: f32,
+    /// Annotation being drawn by the current drag.
+    pending: Option<Annotation>,
+}
+
+impl AnnotationEditor {
+    pub fn open(
+        ctx: &Context,
+        image_id: usize,
+        source: &[u8],
+        annotations: Vec<Annotation>,
+    ) -> ImageResult<Self> {
+        let image = decode_still(source)?;
+        let image_size = Vec2::new(image.width() as f32, image.height() as f32);
+        let preview = ctx.load_texture(
+            "annotation_editor_preview",
+            ColorImage::from_rgba_unmultiplied(
+                [image.width() as usize, image.height() as usize],
+                image.as_raw(),
+            ),
+            Default::default(),
+        );
+
+        Ok(Self {
+            image_id,
+            image_size,
+            preview,
+            annotations,
+            tool: AnnotationTool::Arrow,
+            color: Color32::from_rgb(220, 50, 50),
+            width: 3.0,
+            label_text: String::new(),
+            label_size: 18.0,
+            pending: None,
+        })
+    }
+
+    /// Id of the image being annotated.
+    pub fn image_id(&self) -> usize {
+        self.image_id
+    }
+
+    pub fn show(&mut self, ctx: &Context) -> Option<AnnotationEditorResult> {
+        let mut result = None;
+        let mut open = true;
+
+        egui::Window::new("Annotate Image")
+            .collapsible(false)
+            .resizable(false)
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.tool, AnnotationTool::Arrow, "Arrow");
+                    ui.selectable_value(&mut self.tool, AnnotationTool::Rectangle, "Box");
+                    ui.selectable_value(&mut self.tool, AnnotationTool::Highlight, "Highlighter");
+                    ui.selectable_value(&mut self.tool, AnnotationTool::Callout, "Callout");
+                    ui.selectable_value(&mut self.tool, AnnotationTool::Label, "Text");
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Color");
+                    egui::color_picker::color_edit_button_srgba(
+                        ui,
+                        &mut self.color,
+                        egui::color_picker::Alpha::Opaque,
+                    );
+                    match self.tool {
+                        AnnotationTool::Label => {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut self.label_text)
+                                    .hint_text("Label text"),
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut self.label_size, 8.0..=72.0).text("Size"),
+                            );
+                        }
+                        AnnotationTool::Callout => {}
+                        _ => {
+                            ui.add(egui::Slider::new(&mut self.width, 1.0..=24.0).text("Width"));
+                        }
+                    }
+                });
+
+                ui.label(match self.tool {
+                    AnnotationTool::Callout => "Click to place the next numbered callout.",
+                    AnnotationTool::Label => "Click where the text should start.",
+                    _ => "Drag on the image to draw.",
+                });
+                self.show_canvas(ui);
+
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(
+                            !self.annotations.is_empty(),
+                            egui::Button::new("Remove Last"),
+                        )
+                        .clicked()
+                    {
+                        self.annotations.pop();
+                    }
+                    if ui
+                        .add_enabled(!self.annotations.is_empty(), egui::Button::new("Clear All"))
+                        .clicked()
+                    {
+                        self.annotations.clear();
+                    }
+
+                    ui.separator();
+
+                    if ui.button("Apply").clicked() {
+                        result = Some(AnnotationEditorResult::Apply(std::mem::take(
+                            &mut self.annotations,
+                        )));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        result = Some(AnnotationEditorResult::Cancel);
+                    }
+                });
+            });
+
+        if !open {
+            result = Some(AnnotationEditorResult::Cancel);
+        }
+        result
+    }
+
+    fn show_canvas(&mut self, ui: &mut egui::Ui) {
+        let scale = (PREVIEW_MAX_SIZE.x / self.image_size.x)
+            .min(PREVIEW_MAX_SIZE.y / self.image_size.y)
+            .min(1.0);
+
+        let (rect, response) =
+            ui.allocate_exact_size(self.image_size * scale, Sense::click_and_drag());
+        ui.painter().image(
+            self.preview.id(),
+            rect,
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+            Color32::WHITE,
+        );
+
+        let to_image = |pos: Pos2| {
+            let point = ((pos - rect.min) / scale).clamp(Vec2::ZERO, self.image_size);
+            [point.x, point.y]
+        };
+        let pointer = response.interact_pointer_pos().map(to_image);
+        let color = self.color.to_array();
+
+        if response.clicked()
+            && let Some(point) = pointer
+        {
+            match self.tool {
+                AnnotationTool::Callout => {
+                    let number = self
+                        .annotations
+                        .iter()
+                        .filter(|annotation| matches!(annotation, Annotation::Callout { .. }))
+                        .count() as u32
+                        + 1;
+                    self.annotations.push(Annotation::Callout {
+                        center: point,
+                        number,
+                        color,
+                    });
+                }
+                AnnotationTool::Label if !self.label_text.trim().is_empty() => {
+                    self.annotations.push(Annotation::Label {
+                        position: point,
+                        text: self.label_text.clone(),
+                        color,
+                        size: self.label_size,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        if response.drag_started()
+            && let Some(point) = pointer
+        {
+            self.pending = match self.tool {
+                AnnotationTool::Arrow => Some(Annotation::Arrow {
+                    from: point,
+                    to: point,
+                    color,
+                    width: self.width,
+                }),
+                AnnotationTool::Rectangle => Some(Annotation::Rectangle {
+                    min: point,
+                    max: point,
+                    color,
+                    width: self.width,
+                }),
+                AnnotationTool::Highlight => Some(Annotation::Highlight {
+                    points: vec![point],
+                    color: [color[0], color[1], color[2], HIGHLIGHT_ALPHA],
+                    width: self.width * 4.0,
+                }),
+                AnnotationTool::Callout | AnnotationTool::Label => None,
+            };
+        }
+
+        if response.dragged()
+            && let Some(point) = pointer
+        {
+            match &mut self.pending {
+                Some(Annotation::Arrow { to, .. }) => *to = point,
+                Some(Annotation::Rectangle { max, .. }) => *max = point,
+                Some(Annotation::Highlight { points, .. }) => points.push(point),
+                _ => {}
+            }
+        }
+
+        if response.drag_stopped()
+            && let Some(annotation) = self.pending.take()
+        {
+            self.annotations.push(annotation);
+        }
+
+        let painter = ui.painter_at(rect);
+        paint_annotations(&painter, rect, scale, &self.annotations);
+        if let Some(pending) = &self.pending {
+            paint_annotations(&painter, rect, scale, std::slice::from_ref(pending));
+        }
+
+        // Preview where the next callout will land
+        if self.tool == AnnotationTool::Callout
+            && let Some(hover) = response.hover_pos()
+        {
+            painter.circle_stroke(
+                hover,
+                CALLOUT_RADIUS * scale,
+                egui::Stroke::new(1.0, self.color),
+            );
+        }
+    }
+}
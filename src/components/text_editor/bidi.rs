@@ -0,0 +1,169 @@
+﻿use std::ops::Range;
+use unicode_bidi::ParagraphBidiInfo;
+
+/// Display order of the characters of one line after Unicode bidirectional
+/// reordering.
+///
+/// Lines are still laid out from the left edge. A logical column, the caret
+/// position between two characters, maps to a visual slot, the boundary
+/// between two characters as drawn, counted from the left.
+pub struct BidiLine {
+    /// Logical index of each character, in display order.
+    visual: Vec<usize>,
+    /// Display position of each character, by logical index.
+    positions: Vec<usize>,
+    /// Whether each character, by logical index, is in a right-to-left run.
+    rtl: Vec<bool>,
+}
+
+impl BidiLine {
+    /// Reorders `line`, or returns `None` when it has no right-to-left text
+    /// and is drawn as it is.
+    pub fn new(line: &str) -> Option<Self> {
+        if !line.chars().any(is_rtl_char) {
+            return None;
+        }
+
+        let info = ParagraphBidiInfo::new(line, None);
+        if info.is_pure_ltr {
+            return None;
+        }
+        let levels = info.reordered_levels_per_char(0..line.len());
+        let visual = ParagraphBidiInfo::reorder_visual(&levels);
+
+        let mut positions = vec![0; visual.len()];
+        for (position, &logical) in visual.iter().enumerate() {
+            positions[logical] = position;
+        }
+        Some(Self {
+            visual,
+            positions,
+            rtl: levels.iter().map(|level| level.is_rtl()).collect(),
+        })
+    }
+
+    /// The line's characters in display order, with brackets in right-to-left
+    /// runs mirrored.
+    pub fn visual_text(&self, line: &str) -> String {
+        let chars: Vec<char> = line.chars().collect();
+        self.visual
+            .iter()
+            .map(|&logical| {
+                let c = chars[logical];
+                if self.rtl[logical] { mirror(c) } else { c }
+            })
+            .collect()
+    }
+
+    /// Logical index of the character drawn at display `position`.
+    pub fn logical(&self, position: usize) -> usize {
+        self.visual[position]
+    }
+
+    /// The visual slot where a caret at `column` is drawn: behind the
+    /// character before it in reading direction.
+    pub fn slot(&self, column: usize) -> usize {
+        let count = self.visual.len();
+        let column = column.min(count);
+        if column > 0 {
+            let before = column - 1;
+            if self.rtl[before] {
+                self.positions[before]
+            } else {
+                self.positions[before] + 1
+            }
+        } else if count == 0 {
+            0
+        } else if self.rtl[0] {
+            self.positions[0] + 1
+        } else {
+            self.positions[0]
+        }
+    }
+
+    /// The logical column of a caret drawn at visual `slot`, taken from the
+    /// character on its right, or on its left at the end of the line.
+    pub fn column(&self, slot: usize) -> usize {
+        let count = self.visual.len();
+        if count == 0 {
+            return 0;
+        }
+        if slot < count {
+            let logical = self.visual[slot];
+            if self.rtl[logical] {
+                logical + 1
+            } else {
+                logical
+            }
+        } else {
+            let logical = self.visual[count - 1];
+            if self.rtl[logical] {
+                logical
+            } else {
+                logical + 1
+            }
+        }
+    }
+
+    /// The column and slot one visual step left or right of `column`, `None`
+    /// at the edge of the line.
+    ///
+    /// A caret between two runs can be drawn at two slots; `slot` tells which
+    /// one the caret is at, so stepping does not jump over the runs.
+    pub fn step(&self, column: usize, slot: Option<usize>, right: bool) -> Option<(usize, usize)> {
+        let mut slot = slot
+            .filter(|&slot| self.column(slot) == column)
+            .unwrap_or_else(|| self.slot(column));
+        loop {
+            slot = if right {
+                (slot < self.visual.len()).then_some(slot + 1)?
+            } else {
+                slot.checked_sub(1)?
+            };
+            let next = self.column(slot);
+            if next != column {
+                return Some((next, slot));
+            }
+        }
+    }
+
+    /// Runs of visual slots covering the characters in logical `columns`,
+    /// left to right.
+    pub fn visual_runs(&self, columns: Range<usize>) -> Vec<Range<usize>> {
+        let mut selected: Vec<usize> = columns
+            .filter_map(|logical| self.positions.get(logical).copied())
+            .collect();
+        selected.sort_unstable();
+
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for position in selected {
+            match runs.last_mut() {
+                Some(run) if run.end == position => run.end += 1,
+                _ => runs.push(position..position + 1),
+            }
+        }
+        runs
+    }
+}
+
+fn is_rtl_char(c: char) -> bool {
+    use unicode_bidi::BidiClass::{AL, AN, R, RLE, RLI, RLO};
+    matches!(unicode_bidi::bidi_class(c), R | AL | AN | RLE | RLI | RLO)
+}
+
+/// Paired punctuation is drawn mirrored in right-to-left text.
+fn mirror(c: char) -> char {
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        _ => c,
+    }
+}
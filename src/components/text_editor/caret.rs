@@ -0,0 +1,182 @@
+﻿use egui::{Color32, Painter, Rangef, Rect};
+use std::sync::{Once, OnceLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaretShape {
+    Line,
+    /// Covers the character after the caret.
+    Block,
+    Underline,
+}
+
+impl CaretShape {
+    pub const ALL: [CaretShape; 3] = [CaretShape::Line, CaretShape::Block, CaretShape::Underline];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CaretShape::Line => "Line",
+            CaretShape::Block => "Block",
+            CaretShape::Underline => "Underline",
+        }
+    }
+}
+
+/// How the caret blinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaretBlink {
+    /// Seconds the caret is shown, and then hidden, in each blink.
+    pub half_period: f64,
+    /// Seconds without input after which the caret stops blinking and stays
+    /// shown, so an idle editor does not repaint.
+    pub idle_timeout: f64,
+}
+
+impl Default for CaretBlink {
+    fn default() -> Self {
+        Self {
+            half_period: 0.53,
+            idle_timeout: 10.0,
+        }
+    }
+}
+
+/// The system's blink setting, once [`CaretBlink::read_system`] has read it.
+static SYSTEM_BLINK: OnceLock<Option<CaretBlink>> = OnceLock::new();
+
+impl CaretBlink {
+    /// Starts reading the system's blink setting on a background thread the
+    /// first time it is called, since that can mean running settings commands.
+    pub fn read_system(ctx: &egui::Context) {
+        static READ: Once = Once::new();
+        READ.call_once(|| {
+            let ctx = ctx.clone();
+            std::thread::spawn(move || {
+                if SYSTEM_BLINK.set(Self::query_system()).is_ok() {
+                    ctx.request_repaint();
+                }
+            });
+        });
+    }
+
+    /// The blink rate set in the operating system, `None` when blinking is
+    /// turned off there. The usual blink until the setting has been read.
+    pub fn system() -> Option<Self> {
+        SYSTEM_BLINK.get().copied().unwrap_or(Some(Self::default()))
+    }
+
+    fn query_system() -> Option<Self> {
+        let mut blink = Self::default();
+        #[cfg(windows)]
+        {
+            // Half the blink period in milliseconds, INFINITE when turned off
+            let time = unsafe { winapi::um::winuser::GetCaretBlinkTime() };
+            if time == 0 || time == u32::MAX {
+                return None;
+            }
+            blink.half_period = f64::from(time) / 1000.0;
+        }
+        #[cfg(target_os = "macos")]
+        if let Some(on) = read_setting(
+            "defaults",
+            &["read", "-g", "NSTextInsertionPointBlinkPeriodOn"],
+        ) {
+            blink.half_period = on / 1000.0;
+        }
+        #[cfg(all(unix, not(target_os = "macos")))]
+        {
+            let gnome =
+                |key| read_setting("gsettings", &["get", "org.gnome.desktop.interface", key]);
+            if read_text(
+                "gsettings",
+                &["get", "org.gnome.desktop.interface", "cursor-blink"],
+            )
+            .as_deref()
+                == Some("false")
+            {
+                return None;
+            }
+            // The whole period in milliseconds
+            if let Some(time) = gnome("cursor-blink-time") {
+                blink.half_period = time / 2000.0;
+            }
+            if let Some(timeout) = gnome("cursor-blink-timeout") {
+                blink.idle_timeout = timeout;
+            }
+        }
+        (blink.half_period > 0.0).then_some(blink)
+    }
+
+    /// Whether the caret is shown `idle` seconds after the last input, and
+    /// how many seconds until that changes.
+    pub fn phase(&self, idle: f64) -> (bool, Option<f64>) {
+        if idle >= self.idle_timeout {
+            return (true, None);
+        }
+        let phase = idle % (2.0 * self.half_period);
+        let shown = phase < self.half_period;
+        (shown, Some(self.half_period - phase % self.half_period))
+    }
+}
+
+/// Output of a settings command, trimmed.
+#[cfg(unix)]
+fn read_text(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A number printed by a settings command, like `1200` or `uint32 1200`.
+#[cfg(unix)]
+fn read_setting(program: &str, args: &[&str]) -> Option<f64> {
+    read_text(program, args)?
+        .split_whitespace()
+        .last()?
+        .parse()
+        .ok()
+}
+
+/// How carets and the cursor line are drawn in a frame.
+#[derive(Debug, Clone, Copy)]
+pub struct CaretPaint {
+    pub shape: CaretShape,
+    /// Thickness of line and underline carets, in points.
+    pub width: f32,
+    /// Off during the hidden half of a blink.
+    pub visible: bool,
+    /// Whether the primary cursor's line gets a background band.
+    pub highlight_line: bool,
+}
+
+/// Draws a caret at `x` in the row spanning `y`, `cell` being the extent of
+/// the character after it. Returns the caret's rectangle.
+pub fn paint_caret(
+    painter: &Painter,
+    caret: &CaretPaint,
+    x: f32,
+    cell: Rangef,
+    y: Rangef,
+    color: Color32,
+) -> Rect {
+    let rect = match caret.shape {
+        CaretShape::Line => {
+            Rect::from_x_y_ranges((x - caret.width / 2.0)..=(x + caret.width / 2.0), y)
+        }
+        CaretShape::Block => Rect::from_x_y_ranges(cell, y),
+        CaretShape::Underline => Rect::from_x_y_ranges(cell, (y.max - caret.width)..=y.max),
+    };
+    if caret.visible {
+        let color = if caret.shape == CaretShape::Block {
+            color.gamma_multiply(0.4)
+        } else {
+            color
+        };
+        painter.rect_filled(rect, 0.0, color);
+    }
+    rect
+}
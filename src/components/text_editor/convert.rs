@@ -0,0 +1,245 @@
+﻿use super::util::{expand_tabs, placeholder_ranges};
+
+/// Text conversions of Edit → Convert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conversion {
+    Upper,
+    Lower,
+    TitleCase,
+    SentenceCase,
+    SnakeCase,
+    CamelCase,
+    TrimTrailingWhitespace,
+    TabsToSpaces,
+    /// Only indentation is converted, spaces inside a line are left alone.
+    SpacesToTabs,
+}
+
+impl Conversion {
+    pub const ALL: [Conversion; 9] = [
+        Conversion::Upper,
+        Conversion::Lower,
+        Conversion::TitleCase,
+        Conversion::SentenceCase,
+        Conversion::SnakeCase,
+        Conversion::CamelCase,
+        Conversion::TrimTrailingWhitespace,
+        Conversion::TabsToSpaces,
+        Conversion::SpacesToTabs,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Conversion::Upper => "UPPER CASE",
+            Conversion::Lower => "lower case",
+            Conversion::TitleCase => "Title Case",
+            Conversion::SentenceCase => "Sentence case",
+            Conversion::SnakeCase => "snake_case",
+            Conversion::CamelCase => "camelCase",
+            Conversion::TrimTrailingWhitespace => "Trim Trailing Whitespace",
+            Conversion::TabsToSpaces => "Tabs to Spaces",
+            Conversion::SpacesToTabs => "Spaces to Tabs",
+        }
+    }
+
+    /// Converts `text`, line by line where that matters. Image and sketch
+    /// placeholders are left as they are, and lines holding one are not
+    /// turned into identifiers.
+    pub fn apply(self, text: &str, tab_width: usize) -> String {
+        match self {
+            Conversion::Upper => map_around_placeholders(text, str::to_uppercase),
+            Conversion::Lower => map_around_placeholders(text, str::to_lowercase),
+            Conversion::TitleCase => map_around_placeholders(text, title_case),
+            Conversion::SentenceCase => map_around_placeholders(text, sentence_case),
+            Conversion::SnakeCase => {
+                map_lines(text, |line| map_identifier(line, |words| words.join("_")))
+            }
+            Conversion::CamelCase => map_lines(text, |line| {
+                map_identifier(line, |words| {
+                    words
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, word)| {
+                            if idx == 0 {
+                                word.clone()
+                            } else {
+                                capitalize(word)
+                            }
+                        })
+                        .collect()
+                })
+            }),
+            Conversion::TrimTrailingWhitespace => {
+                map_lines(text, |line| line.trim_end().to_string())
+            }
+            Conversion::TabsToSpaces => {
+                map_lines(text, |line| expand_tabs(line, tab_width).into_owned())
+            }
+            Conversion::SpacesToTabs => map_lines(text, |line| tabify_indent(line, tab_width)),
+        }
+    }
+}
+
+const PLACEHOLDER_MASK: char = '\u{FFFC}';
+
+fn map_lines(text: &str, convert: impl Fn(&str) -> String) -> String {
+    text.split('\n').map(convert).collect::<Vec<_>>().join("\n")
+}
+
+/// Converts `text` with each placeholder stood in for by an object
+/// replacement character, which no conversion changes, and puts the
+/// placeholders back afterwards. Text already holding that character is
+/// converted piece by piece between the placeholders instead.
+fn map_around_placeholders(text: &str, convert: impl Fn(&str) -> String) -> String {
+    let ranges = placeholder_ranges(text);
+    if text.contains(PLACEHOLDER_MASK) {
+        let mut result = String::with_capacity(text.len());
+        let mut converted_until = 0;
+        for range in ranges {
+            result.push_str(&convert(&text[converted_until..range.start]));
+            result.push_str(&text[range.clone()]);
+            converted_until = range.end;
+        }
+        result.push_str(&convert(&text[converted_until..]));
+        return result;
+    }
+
+    let mut masked = String::with_capacity(text.len());
+    let mut copied_until = 0;
+    for range in &ranges {
+        masked.push_str(&text[copied_until..range.start]);
+        masked.push(PLACEHOLDER_MASK);
+        copied_until = range.end;
+    }
+    masked.push_str(&text[copied_until..]);
+
+    let mut placeholders = ranges.into_iter().map(|range| &text[range]);
+    convert(&masked).split(PLACEHOLDER_MASK).enumerate().fold(
+        String::with_capacity(text.len()),
+        |mut result, (idx, part)| {
+            if idx > 0 {
+                result.push_str(placeholders.next().unwrap_or_default());
+            }
+            result.push_str(part);
+            result
+        },
+    )
+}
+
+/// Upper-cases the first letter of every word and lower-cases the rest.
+fn title_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if in_word {
+                result.extend(c.to_lowercase());
+            } else {
+                result.extend(c.to_uppercase());
+            }
+            in_word = true;
+        } else {
+            result.push(c);
+            // "don't" is one word
+            in_word = in_word && c == '\'';
+        }
+    }
+    result
+}
+
+/// Lower-cases everything but the first letter of each sentence.
+fn sentence_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut sentence_start = true;
+    let mut after_stop = false;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if sentence_start {
+                result.extend(c.to_uppercase());
+            } else {
+                result.extend(c.to_lowercase());
+            }
+            sentence_start = false;
+            after_stop = false;
+        } else {
+            result.push(c);
+            if matches!(c, '.' | '!' | '?') {
+                after_stop = true;
+            } else if c.is_whitespace() && after_stop {
+                sentence_start = true;
+            }
+        }
+    }
+    result
+}
+
+/// Rebuilds the identifier in `line` from its lower-case words, keeping the
+/// line's indentation.
+fn map_identifier(line: &str, join: impl Fn(&[String]) -> String) -> String {
+    if !placeholder_ranges(line).is_empty() {
+        return line.to_string();
+    }
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+    let words = identifier_words(content);
+    if words.is_empty() {
+        return line.to_string();
+    }
+    format!("{indent}{}", join(&words))
+}
+
+/// Splits text like "HTTPServer error-count" into lower-case words at
+/// punctuation, spaces and changes of case.
+fn identifier_words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (idx, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+
+        if c.is_uppercase() && !word.is_empty() {
+            let previous = chars[idx - 1];
+            let next_is_lower = chars.get(idx + 1).is_some_and(|next| next.is_lowercase());
+            // "fooBar" and the "S" of "HTTPServer" start a new word
+            if previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_is_lower)
+            {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Replaces the indentation of `line` with tabs, keeping spaces that do not
+/// fill a whole tab stop.
+fn tabify_indent(line: &str, tab_width: usize) -> String {
+    let tab_width = tab_width.max(1);
+    let content = line.trim_start_matches([' ', '\t']);
+    let indent = &line[..line.len() - content.len()];
+    let columns = expand_tabs(indent, tab_width).len();
+    format!(
+        "{}{}{content}",
+        "\t".repeat(columns / tab_width),
+        " ".repeat(columns % tab_width)
+    )
+}
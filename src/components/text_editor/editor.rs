@@ -1,45 +1,135 @@
-﻿use super::renderer::TextEditorRenderer;
+﻿use super::annotation::{flatten, map_annotations};
+use super::annotation_editor::{AnnotationEditor, AnnotationEditorResult};
+use super::bidi::BidiLine;
+use super::caret::{CaretBlink, CaretPaint, CaretShape};
+use super::convert::Conversion;
+use super::history::{EditKind, UndoHistory};
+use super::image::{EditorImage, LoadState};
+use super::image_editor::{ImageEditor, ImageEditorResult};
+use super::lines::{LineCommand, join_lines, sort_lines};
+use super::loader::ImageLoader;
+use super::renderer::TextEditorRenderer;
+use super::sketch::{SKETCH_SIZE, SketchPen, SketchTool, erase_at, touches_stroke};
 
-use crate::components::text_editor::util::calculate_line_height;
+use crate::components::text_editor::util::{
+    calculate_line_height, column_x, expand_tabs, extract_image_id, extract_placeholder_id,
+    extract_sketch_id, image_line, line_byte_range, line_image_id, next_word_boundary,
+    previous_word_boundary,
+};
+use crate::document::{
+    Annotation, DocumentMetadata, LineBreak, Sketch, content_hash, image_extension,
+};
 use eframe::epaint::StrokeKind;
-use egui::WidgetType::TextEdit;
-use egui::{Color32, EventFilter, FontId, Key, Pos2, Rect, Response, Stroke, Ui, Vec2};
+use egui::{EventFilter, FontId, Key, Pos2, Rect, Response, Stroke, Ui, Vec2};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
 
-pub type TextEditorImageMap = std::collections::HashMap<usize, (egui::TextureHandle, Vec2)>;
+pub type TextEditorImageMap = std::collections::HashMap<usize, EditorImage>;
+pub type TextEditorSketchMap = std::collections::HashMap<usize, Sketch>;
+/// Start and end `(line, column)` of a selection, in text order.
+pub type TextSelection = ((usize, usize), (usize, usize));
 
 pub const IMAGE_PADDING: f32 = 8.0;
 
+pub const DEFAULT_TEXTURE_BUDGET: usize = 256 * 1024 * 1024;
+
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
 #[derive(Debug, Clone)]
 pub struct FontMetrics {
     pub font_id: FontId,
-    pub font_size: f32,
-    pub char_width: f32,
-    pub line_height: f32,
 }
 
 impl FontMetrics {
     pub fn new(font_size: f32) -> Self {
         Self {
             font_id: FontId::monospace(font_size),
-            font_size,
-            char_width: 0.0,
-            line_height: 0.0,
         }
     }
+}
+
+/// A cursor together with the other end of its selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Caret {
+    position: (usize, usize),
+    anchor: Option<(usize, usize)>,
+}
 
-    pub fn calculate(&mut self, ui: &mut Ui) {
-        // self.line_number_width = ui.fonts(|f| f.glyph_width(&self.font_id, '0')) * (digits + 1) as f32;
+impl Caret {
+    /// Start and end of the selection in text order, `None` when nothing is
+    /// selected.
+    fn selection(&self) -> Option<TextSelection> {
+        let anchor = self.anchor?;
+        match anchor.cmp(&self.position) {
+            std::cmp::Ordering::Less => Some((anchor, self.position)),
+            std::cmp::Ordering::Greater => Some((self.position, anchor)),
+            std::cmp::Ordering::Equal => None,
+        }
     }
 }
 
+/// A pen or eraser drag on a sketch.
+struct SketchDrag {
+    line: usize,
+    rect: Rect,
+    /// The sketch copy receiving this drag's changes, created on the first change.
+    sketch_id: Option<usize>,
+}
+
 pub struct TextEditor {
     renderer: TextEditorRenderer,
     font_metrics: FontMetrics,
-    hint_text: String,
     margin: f32,
     images: TextEditorImageMap,
+    sketches: TextEditorSketchMap,
+    sketch_pen: SketchPen,
+    sketch_drag: Option<SketchDrag>,
+    /// Sketches a pen or eraser drag replaced with an edited copy. Only undo
+    /// can bring them back, so they are not saved as orphans.
+    superseded_sketches: HashSet<usize>,
+    image_loader: ImageLoader,
+    image_editor: Option<ImageEditor>,
+    annotation_editor: Option<AnnotationEditor>,
+    texture_budget: usize,
+    history: UndoHistory,
     cursor_line: usize,
     cursor_column: usize,
+    /// The other end of the selection, which runs from here to the cursor.
+    selection_anchor: Option<(usize, usize)>,
+    /// Cursors added with Alt+click, Ctrl+Alt+Up/Down or a box selection,
+    /// besides the primary one above.
+    extra_carets: Vec<Caret>,
+    /// Line and horizontal offset where an Alt+drag box selection started.
+    box_origin: Option<(usize, f32)>,
+    /// Text being composed with an input method, shown at the cursor but not
+    /// yet part of the text.
+    ime_preedit: String,
+    context_image_line: Option<usize>,
+    /// Visible height of the text area during the last frame, used for Page Up/Down.
+    page_height: f32,
+    /// Horizontal position, in points, that Up/Down and Page Up/Down keep
+    /// across shorter lines, valid while the cursor stays at the position the
+    /// last vertical move left it.
+    preferred_x: Option<(f32, (usize, usize))>,
+    /// Visual slot the last Left/Right move through right-to-left text left
+    /// the cursor at, valid while the cursor stays there.
+    visual_slot: Option<(usize, (usize, usize))>,
+    animate_images: bool,
+    keep_orphaned_images: bool,
+    tab_width: usize,
+    indent_with_spaces: bool,
+    visual_cursor_movement: bool,
+    show_whitespace: bool,
+    /// Line break of the file being edited, which Show Whitespace marks.
+    line_break: LineBreak,
+    highlight_current_line: bool,
+    caret_shape: CaretShape,
+    caret_width: f32,
+    /// Whether the caret blinks, `None` following the system setting.
+    caret_blink: Option<bool>,
+    /// Time of the last input, when the caret's blink restarts shown.
+    blink_start: f64,
 }
 
 impl TextEditor {
@@ -47,16 +137,798 @@ impl TextEditor {
         Self {
             renderer: TextEditorRenderer::new(),
             font_metrics: FontMetrics::new(14.0),
-            hint_text: "Start typing...".to_string(),
             margin: 8.0,
             images: Default::default(),
+            sketches: Default::default(),
+            sketch_pen: SketchPen::default(),
+            sketch_drag: None,
+            superseded_sketches: HashSet::new(),
+            image_loader: ImageLoader::new(),
+            image_editor: None,
+            annotation_editor: None,
+            texture_budget: DEFAULT_TEXTURE_BUDGET,
+            history: UndoHistory::default(),
             cursor_line: 0,
             cursor_column: 0,
+            selection_anchor: None,
+            extra_carets: Vec::new(),
+            box_origin: None,
+            ime_preedit: String::new(),
+            context_image_line: None,
+            page_height: 0.0,
+            preferred_x: None,
+            visual_slot: None,
+            animate_images: true,
+            keep_orphaned_images: false,
+            tab_width: DEFAULT_TAB_WIDTH,
+            indent_with_spaces: true,
+            visual_cursor_movement: false,
+            show_whitespace: false,
+            line_break: LineBreak::default(),
+            highlight_current_line: true,
+            caret_shape: CaretShape::Line,
+            caret_width: 1.0,
+            caret_blink: None,
+            blink_start: 0.0,
+        }
+    }
+
+    pub fn animate_images(&self) -> bool {
+        self.animate_images
+    }
+
+    pub fn set_animate_images(&mut self, animate: bool) {
+        self.animate_images = animate;
+    }
+
+    /// Whether saving keeps images whose placeholder is no longer in the text.
+    pub fn keep_orphaned_images(&self) -> bool {
+        self.keep_orphaned_images
+    }
+
+    pub fn set_keep_orphaned_images(&mut self, keep: bool) {
+        self.keep_orphaned_images = keep;
+    }
+
+    /// Maximum GPU memory, in bytes, that image textures may use before
+    /// far-offscreen images are evicted.
+    pub fn texture_budget(&self) -> usize {
+        self.texture_budget
+    }
+
+    pub fn set_texture_budget(&mut self, bytes: usize) {
+        self.texture_budget = bytes;
+    }
+
+    /// Number of columns between tab stops.
+    pub fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// Whether Tab indents with spaces rather than tab characters.
+    pub fn indent_with_spaces(&self) -> bool {
+        self.indent_with_spaces
+    }
+
+    pub fn set_indent_with_spaces(&mut self, spaces: bool) {
+        self.indent_with_spaces = spaces;
+    }
+
+    /// Whether Left and Right move the cursor in display order through
+    /// right-to-left text rather than in reading order.
+    pub fn visual_cursor_movement(&self) -> bool {
+        self.visual_cursor_movement
+    }
+
+    pub fn set_visual_cursor_movement(&mut self, visual: bool) {
+        self.visual_cursor_movement = visual;
+    }
+
+    /// Whether spaces, tabs, invisible characters and line endings are marked.
+    pub fn show_whitespace(&self) -> bool {
+        self.show_whitespace
+    }
+
+    pub fn set_show_whitespace(&mut self, show: bool) {
+        self.show_whitespace = show;
+    }
+
+    /// The line break the document is saved with.
+    pub fn line_break(&self) -> LineBreak {
+        self.line_break
+    }
+
+    pub fn set_line_break(&mut self, line_break: LineBreak) {
+        self.line_break = line_break;
+    }
+
+    /// Whether the cursor line gets a background band.
+    pub fn highlight_current_line(&self) -> bool {
+        self.highlight_current_line
+    }
+
+    pub fn set_highlight_current_line(&mut self, highlight: bool) {
+        self.highlight_current_line = highlight;
+    }
+
+    pub fn caret_shape(&self) -> CaretShape {
+        self.caret_shape
+    }
+
+    pub fn set_caret_shape(&mut self, shape: CaretShape) {
+        self.caret_shape = shape;
+    }
+
+    /// Thickness of line and underline carets, in points.
+    pub fn caret_width(&self) -> f32 {
+        self.caret_width
+    }
+
+    pub fn set_caret_width(&mut self, width: f32) {
+        self.caret_width = width.max(1.0);
+    }
+
+    pub fn caret_blinks(&self) -> bool {
+        self.blink().is_some()
+    }
+
+    /// Turns blinking on at the system's blink rate, or the usual rate when
+    /// the system has blinking turned off.
+    pub fn set_caret_blinks(&mut self, blink: bool) {
+        self.caret_blink = Some(blink);
+    }
+
+    /// How the caret blinks, `None` for a steady caret.
+    fn blink(&self) -> Option<CaretBlink> {
+        match self.caret_blink {
+            None => CaretBlink::system(),
+            Some(true) => Some(CaretBlink::system().unwrap_or_default()),
+            Some(false) => None,
+        }
+    }
+
+    /// Replaces all images with the ones stored in `metadata` and resets the cursor.
+    ///
+    /// Images are only decoded once they come close to the viewport.
+    pub fn load_document(&mut self, metadata: &DocumentMetadata) {
+        self.images.clear();
+        self.sketches = metadata
+            .sketches
+            .iter()
+            .map(|(id, sketch)| (*id, sketch.clone()))
+            .collect();
+        self.sketch_drag = None;
+        self.superseded_sketches.clear();
+        self.image_loader = ImageLoader::new();
+        self.image_editor = None;
+        self.annotation_editor = None;
+        self.history.clear();
+        self.cursor_line = 0;
+        self.cursor_column = 0;
+        self.selection_anchor = None;
+        self.extra_carets.clear();
+
+        // Images sharing a blob also share their source bytes in memory
+        let mut sources: HashMap<String, Arc<[u8]>> = HashMap::new();
+
+        for (id, image) in &metadata.images {
+            let bytes = match metadata.image_bytes(*id) {
+                Some(Ok(bytes)) => bytes,
+                Some(Err(err)) => {
+                    log::warn!("Skipping image {id}: invalid base64 data: {err}");
+                    continue;
+                }
+                None => {
+                    log::warn!("Skipping image {id}: missing blob {}", image.hash);
+                    continue;
+                }
+            };
+
+            // Inline images from before blobs existed have no stored hash
+            let source = sources
+                .entry(content_hash(&bytes))
+                .or_insert_with(|| bytes.into())
+                .clone();
+            let size = Vec2::new(image.width as f32, image.height as f32);
+            let mut editor_image = EditorImage::unloaded(format!("image_{id}"), source, size);
+            editor_image.set_annotations(image.annotations.clone());
+            editor_image.set_markdown(image.markdown.clone());
+            self.images.insert(*id, editor_image);
+        }
+    }
+
+    /// Builds the metadata for the images and sketches referenced by `text`.
+    ///
+    /// Images without a placeholder are dropped unless orphaned images are kept.
+    /// Sketch versions replaced while drawing are dropped either way.
+    pub fn document_metadata(&self, text: &str) -> DocumentMetadata {
+        let referenced: HashSet<usize> = text.lines().filter_map(extract_image_id).collect();
+        let referenced_sketches: HashSet<usize> =
+            text.lines().filter_map(extract_sketch_id).collect();
+
+        let mut metadata = DocumentMetadata::default();
+        for (id, image) in &self.images {
+            if !self.keep_orphaned_images && !referenced.contains(id) {
+                continue;
+            }
+
+            let size = image.size();
+            let entry = metadata.add_image(
+                *id,
+                image.hash(),
+                image.source(),
+                size.x as u32,
+                size.y as u32,
+            );
+            entry.annotations = image.annotations().to_vec();
+            entry.markdown = image.markdown().cloned();
+        }
+        for (id, sketch) in &self.sketches {
+            let orphan_kept = self.keep_orphaned_images && !self.superseded_sketches.contains(id);
+            if orphan_kept || referenced_sketches.contains(id) {
+                metadata.sketches.insert(*id, sketch.clone());
+            }
+        }
+        metadata
+    }
+
+    /// Decodes `bytes` and inserts it as a new image line below the cursor line.
+    pub fn insert_image(
+        &mut self,
+        ctx: &egui::Context,
+        text: &mut String,
+        bytes: Arc<[u8]>,
+    ) -> image::ImageResult<()> {
+        let id = self.next_image_id();
+
+        // Reuse the bytes of an identical image that is already in the document
+        let hash = content_hash(&bytes);
+        let bytes = self
+            .images
+            .values()
+            .find(|image| image.hash() == hash)
+            .map_or(bytes, |image| image.source().clone());
+
+        let editor_image = EditorImage::decode(ctx, format!("image_{id}"), bytes)?;
+        self.images.insert(id, editor_image);
+        self.insert_block_line(text, &format!("[image({id})]"));
+
+        Ok(())
+    }
+
+    /// Inserts a blank sketch as a new line below the cursor line.
+    pub fn insert_sketch(&mut self, text: &mut String) {
+        let id = self.sketches.keys().max().map_or(1, |id| id + 1);
+        self.sketches
+            .insert(id, Sketch::new(SKETCH_SIZE.x, SKETCH_SIZE.y));
+        self.insert_block_line(text, &format!("[sketch({id})]"));
+    }
+
+    /// Puts a placeholder `tag` on its own line after the cursor line and moves the cursor behind it.
+    fn insert_block_line(&mut self, text: &mut String, tag: &str) {
+        self.history.record(text, self.cursor(), EditKind::Other);
+        self.selection_anchor = None;
+        self.extra_carets.clear();
+
+        let line_length = text
+            .lines()
+            .nth(self.cursor_line)
+            .map_or(0, |line| line.chars().count());
+        self.cursor_column = line_length;
+
+        if line_length == 0 {
+            self.insert_text_at_cursor(text, tag);
+        } else {
+            self.insert_text_at_cursor(text, &format!("\n{tag}"));
+            self.cursor_line += 1;
+        }
+        self.cursor_column = tag.chars().count();
+    }
+
+    /// Replaces the image on `line_idx` with a new image decoded from `bytes`,
+    /// moving its annotations through `map` from the old pixels to the new.
+    ///
+    /// The new image gets its own id so the previous one is restored by undo.
+    pub fn replace_image(
+        &mut self,
+        ctx: &egui::Context,
+        text: &mut String,
+        line_idx: usize,
+        bytes: Arc<[u8]>,
+        map: impl Fn([f32; 2]) -> [f32; 2],
+    ) -> image::ImageResult<()> {
+        let Some(old) = line_image_id(text, line_idx).and_then(|id| self.images.get(&id)) else {
+            return Ok(());
+        };
+        let old_annotations = old.annotations().to_vec();
+        let markdown = old.markdown().cloned();
+
+        let id = self.next_image_id();
+        let mut editor_image = EditorImage::decode(ctx, format!("image_{id}"), bytes)?;
+        let annotations = map_annotations(&old_annotations, map, editor_image.size());
+        if annotations.len() < old_annotations.len() {
+            log::warn!(
+                "Dropped {} annotations cropped out of the image",
+                old_annotations.len() - annotations.len()
+            );
+        }
+        editor_image.set_annotations(annotations);
+        // Markdown images stay Markdown, with the edited pixels kept in the sidecar
+        editor_image.set_markdown(markdown);
+        self.swap_line_image(text, line_idx, id, editor_image);
+        Ok(())
+    }
+
+    /// Replaces the annotations of the image on `line_idx`, keeping its pixels.
+    ///
+    /// Like [`Self::replace_image`], the result is a new image so undo restores the old markup.
+    pub fn annotate_image(
+        &mut self,
+        text: &mut String,
+        line_idx: usize,
+        annotations: Vec<Annotation>,
+    ) {
+        let Some(image) = line_image_id(text, line_idx).and_then(|id| self.images.get(&id)) else {
+            return;
+        };
+
+        let id = self.next_image_id();
+        let mut editor_image = image.duplicate(format!("image_{id}"));
+        editor_image.set_annotations(annotations);
+        self.swap_line_image(text, line_idx, id, editor_image);
+    }
+
+    /// Points the placeholder on `line_idx` at `image`, inserted under the new `id`.
+    fn swap_line_image(
+        &mut self,
+        text: &mut String,
+        line_idx: usize,
+        id: usize,
+        image: EditorImage,
+    ) {
+        if self.swap_placeholder(text, line_idx, "image", id) {
+            self.images.insert(id, image);
+        }
+    }
+
+    /// Copies the sketch on `line_idx` to a new id and points the line at the copy,
+    /// so the unchanged original is restored by undo.
+    fn begin_sketch_edit(&mut self, text: &mut String, line_idx: usize) -> Option<usize> {
+        let old_id = text.lines().nth(line_idx).and_then(extract_sketch_id)?;
+        let sketch = self.sketches.get(&old_id)?.clone();
+
+        let id = self.sketches.keys().max().map_or(1, |id| id + 1);
+        if !self.swap_placeholder(text, line_idx, "sketch", id) {
+            return None;
+        }
+        self.sketches.insert(id, sketch);
+        self.superseded_sketches.insert(old_id);
+        Some(id)
+    }
+
+    /// Replaces the id of the `[tag(id)]` placeholder on `line_idx` with `new_id` as one undo step.
+    fn swap_placeholder(
+        &mut self,
+        text: &mut String,
+        line_idx: usize,
+        tag: &str,
+        new_id: usize,
+    ) -> bool {
+        let Some(range) = line_byte_range(text, line_idx) else {
+            return false;
+        };
+        let Some(old_id) = extract_placeholder_id(&text[range.clone()], tag) else {
+            return false;
+        };
+
+        self.history.record(text, self.cursor(), EditKind::Other);
+
+        let line = text[range.clone()].replacen(
+            &format!("[{tag}({old_id})]"),
+            &format!("[{tag}({new_id})]"),
+            1,
+        );
+        text.replace_range(range, &line);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    pub fn undo(&mut self, text: &mut String) {
+        if let Some((line, column)) = self.history.undo(text, self.cursor()) {
+            self.cursor_line = line;
+            self.cursor_column = column;
+            self.selection_anchor = None;
+            self.extra_carets.clear();
+        }
+    }
+
+    pub fn redo(&mut self, text: &mut String) {
+        if let Some((line, column)) = self.history.redo(text, self.cursor()) {
+            self.cursor_line = line;
+            self.cursor_column = column;
+            self.selection_anchor = None;
+            self.extra_carets.clear();
+        }
+    }
+
+    /// Whether the selection spans several lines, which sorting and the other
+    /// commands for a block of lines need.
+    pub fn has_line_selection(&self) -> bool {
+        self.selected_lines().is_some()
+    }
+
+    /// Applies a line command to the cursor line or the selected lines as one
+    /// undo step. Image and sketch lines move like any other line, their
+    /// images stay attached to the placeholders.
+    pub fn line_command(&mut self, text: &mut String, command: LineCommand) {
+        let span = self.selected_lines();
+        if command.needs_selection() && span.is_none() {
+            return;
+        }
+        let span = span.unwrap_or(self.cursor_line..=self.cursor_line);
+
+        let mut lines: Vec<String> = text.split('\n').map(str::to_string).collect();
+        // The empty line after a trailing newline stays last
+        let movable = if text.ends_with('\n') {
+            lines.len() - 1
+        } else {
+            lines.len()
+        };
+        let (first, last) = (*span.start(), *span.end());
+        if last >= movable {
+            return;
+        }
+        let count = last - first + 1;
+
+        // How far the cursor and anchor move, or the lines left selected
+        let mut shift: isize = 0;
+        let mut selected = None;
+        let mut cursor = None;
+        match command {
+            LineCommand::Duplicate => {
+                let copy = lines[first..=last].to_vec();
+                lines.splice(last + 1..last + 1, copy);
+                shift = count as isize;
+            }
+            LineCommand::MoveUp => {
+                if first == 0 {
+                    return;
+                }
+                let line = lines.remove(first - 1);
+                lines.insert(last, line);
+                shift = -1;
+            }
+            LineCommand::MoveDown => {
+                if last + 1 >= movable {
+                    return;
+                }
+                let line = lines.remove(last + 1);
+                lines.insert(first, line);
+                shift = 1;
+            }
+            LineCommand::Delete => {
+                lines.drain(first..=last);
+                cursor = Some((first, 0));
+            }
+            LineCommand::Join => {
+                let end = if first == last { last + 1 } else { last };
+                if end >= movable {
+                    return;
+                }
+                let joined = join_lines(&lines[first..=end]);
+                // The cursor goes where the first line ended
+                let column = lines[first].trim_end().chars().count();
+                lines.splice(first..=end, [joined]);
+                cursor = Some((first, column));
+            }
+            LineCommand::Sort(order) => {
+                sort_lines(&mut lines[first..=last], order);
+                selected = Some((first, last));
+            }
+            LineCommand::RemoveDuplicates => {
+                let mut seen = HashSet::new();
+                let kept: Vec<String> = lines[first..=last]
+                    .iter()
+                    .filter(|line| seen.insert(line.as_str()))
+                    .cloned()
+                    .collect();
+                selected = Some((first, first + kept.len() - 1));
+                lines.splice(first..=last, kept);
+            }
+            LineCommand::Reverse => {
+                lines[first..=last].reverse();
+                selected = Some((first, last));
+            }
+        }
+
+        let new_text = lines.join("\n");
+        if new_text == *text {
+            return;
+        }
+        self.history.record(text, self.cursor(), EditKind::Other);
+        *text = new_text;
+        self.extra_carets.clear();
+
+        if let Some((first, last)) = selected {
+            self.selection_anchor = Some((first, 0));
+            self.cursor_line = last;
+            self.cursor_column = line_length(text, last);
+        } else if let Some((line, column)) = cursor {
+            self.selection_anchor = None;
+            self.cursor_line = line.min(last_line(text));
+            self.cursor_column = column.min(line_length(text, self.cursor_line));
+        } else {
+            let shift =
+                |(line, column): (usize, usize)| (line.saturating_add_signed(shift), column);
+            (self.cursor_line, self.cursor_column) = shift(self.cursor());
+            self.selection_anchor = self.selection_anchor.map(shift);
+        }
+    }
+
+    /// Converts the selected text, or the cursor line when nothing is
+    /// selected, at every cursor as one undo step. The converted text stays
+    /// selected.
+    pub fn convert_selection(&mut self, text: &mut String, conversion: Conversion) {
+        let tab_width = self.tab_width;
+        self.edit_carets(text, EditKind::Other, true, |text, _, caret| {
+            let range = match caret.selection() {
+                Some(_) => selection_range(text, caret),
+                None => line_byte_range(text, caret.position.0)?,
+            };
+            let converted = conversion.apply(&text[range.clone()], tab_width);
+            (converted != text[range.clone()]).then_some((range, converted))
+        });
+    }
+
+    /// Inserts the current time and date at every cursor, formatted like
+    /// Notepad does.
+    pub fn insert_date_time(&mut self, text: &mut String) {
+        let now = chrono::Local::now()
+            .format("%-I:%M %p %-m/%-d/%Y")
+            .to_string();
+        self.edit_carets(text, EditKind::Other, false, |text, _, caret| {
+            Some((selection_range(text, caret), now.clone()))
+        });
+    }
+
+    fn cursor(&self) -> (usize, usize) {
+        (self.cursor_line, self.cursor_column)
+    }
+
+    /// Start and end of the primary selection in text order, `None` when
+    /// nothing is selected.
+    fn selection(&self) -> Option<TextSelection> {
+        self.caret().selection()
+    }
+
+    /// The primary cursor and its selection.
+    fn caret(&self) -> Caret {
+        Caret {
+            position: self.cursor(),
+            anchor: self.selection_anchor,
+        }
+    }
+
+    /// All cursors in text order.
+    fn carets(&self) -> Vec<Caret> {
+        let mut carets = self.extra_carets.clone();
+        carets.push(self.caret());
+        carets.sort_by_key(|caret| caret.position);
+        carets
+    }
+
+    /// Makes `primary` the primary cursor and the rest extra ones, dropping
+    /// cursors that ended up at the same position.
+    fn set_carets(&mut self, primary: Caret, carets: Vec<Caret>) {
+        (self.cursor_line, self.cursor_column) = primary.position;
+        self.selection_anchor = primary.anchor;
+        self.extra_carets.clear();
+        for caret in carets {
+            if caret.position != primary.position
+                && !self
+                    .extra_carets
+                    .iter()
+                    .any(|extra| extra.position == caret.position)
+            {
+                self.extra_carets.push(caret);
+            }
+        }
+    }
+
+    fn next_image_id(&self) -> usize {
+        self.images.keys().max().map_or(1, |id| id + 1)
+    }
+
+    fn open_image_editor(&mut self, ctx: &egui::Context, text: &str, line_idx: usize) {
+        let Some((id, image)) = line_image_id(text, line_idx)
+            .and_then(|id| self.images.get(&id).map(|image| (id, image)))
+        else {
+            return;
+        };
+
+        match ImageEditor::open(ctx, id, image.source()) {
+            Ok(image_editor) => self.image_editor = Some(image_editor),
+            Err(err) => log::error!("Failed to open image for editing: {err}"),
+        }
+    }
+
+    fn open_annotation_editor(&mut self, ctx: &egui::Context, text: &str, line_idx: usize) {
+        let Some((id, image)) = line_image_id(text, line_idx)
+            .and_then(|id| self.images.get(&id).map(|image| (id, image)))
+        else {
+            return;
+        };
+
+        match AnnotationEditor::open(ctx, id, image.source(), image.annotations().to_vec()) {
+            Ok(annotation_editor) => self.annotation_editor = Some(annotation_editor),
+            Err(err) => log::error!("Failed to open image for annotating: {err}"),
+        }
+    }
+
+    /// Saves the image on `line_idx`, with its annotations flattened, to a user-chosen file.
+    fn export_image(&self, text: &str, line_idx: usize) {
+        let Some(image) = line_image_id(text, line_idx).and_then(|id| self.images.get(&id)) else {
+            return;
+        };
+
+        let bytes = match flatten(image.source(), image.annotations()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::error!("Failed to flatten image annotations: {err}");
+                return;
+            }
+        };
+        let extension = image_extension(&bytes);
+
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Image", &[extension])
+            .set_file_name(format!("image.{extension}"))
+            .save_file()
+            && let Err(err) = std::fs::write(&path, bytes)
+        {
+            log::error!("Failed to export image to {}: {err}", path.display());
+        }
+    }
+
+    fn show_annotation_editor(&mut self, response: &mut Response, ui: &Ui, text: &mut String) {
+        let Some(annotation_editor) = &mut self.annotation_editor else {
+            return;
+        };
+
+        match annotation_editor.show(ui.ctx()) {
+            Some(AnnotationEditorResult::Apply(annotations)) => {
+                let id = annotation_editor.image_id();
+                self.annotation_editor = None;
+                let Some(line_idx) = image_line(text, id) else {
+                    log::warn!("The annotated image was changed or removed in the meantime");
+                    return;
+                };
+                self.annotate_image(text, line_idx, annotations);
+                response.mark_changed();
+            }
+            Some(AnnotationEditorResult::Cancel) => self.annotation_editor = None,
+            None => {}
+        }
+    }
+
+    fn show_image_editor(&mut self, response: &mut Response, ui: &Ui, text: &mut String) {
+        let Some(image_editor) = &mut self.image_editor else {
+            return;
+        };
+
+        match image_editor.show(ui.ctx()) {
+            Some(ImageEditorResult::Apply { bytes, map }) => {
+                let id = image_editor.image_id();
+                self.image_editor = None;
+                let Some(line_idx) = image_line(text, id) else {
+                    log::warn!("The edited image was changed or removed in the meantime");
+                    return;
+                };
+                match self.replace_image(ui.ctx(), text, line_idx, bytes.into(), |point| {
+                    map.apply(point)
+                }) {
+                    Ok(()) => response.mark_changed(),
+                    Err(err) => log::error!("Failed to apply image edit: {err}"),
+                }
+            }
+            Some(ImageEditorResult::Cancel) => self.image_editor = None,
+            None => {}
         }
     }
 
-    pub fn add_image(&mut self, line_index: usize, texture: egui::TextureHandle, size: Vec2) {
-        self.images.insert(line_index, (texture, size));
+    fn show_context_menu(&mut self, response: &mut Response, ui: &Ui, text: &mut String) {
+        let image_line = response
+            .interact_pointer_pos()
+            .and_then(|pos| self.renderer.image_line_at(pos));
+
+        if response.double_clicked()
+            && let Some(line_idx) = image_line
+        {
+            self.open_image_editor(ui.ctx(), text, line_idx);
+        }
+
+        if response.secondary_clicked() {
+            self.context_image_line = image_line;
+        }
+
+        let mut changed = false;
+        response.context_menu(|ui| {
+            if ui
+                .add_enabled(self.can_undo(), egui::Button::new("Undo"))
+                .clicked()
+            {
+                self.undo(text);
+                changed = true;
+                ui.close_menu();
+            }
+            if ui
+                .add_enabled(self.can_redo(), egui::Button::new("Redo"))
+                .clicked()
+            {
+                self.redo(text);
+                changed = true;
+                ui.close_menu();
+            }
+
+            ui.separator();
+
+            if ui.button("Insert Sketch").clicked() {
+                self.insert_sketch(text);
+                changed = true;
+                ui.close_menu();
+            }
+            if ui
+                .add_enabled(
+                    self.context_image_line.is_some(),
+                    egui::Button::new("Edit Image..."),
+                )
+                .clicked()
+            {
+                if let Some(line_idx) = self.context_image_line {
+                    self.open_image_editor(ui.ctx(), text, line_idx);
+                }
+                ui.close_menu();
+            }
+            if ui
+                .add_enabled(
+                    self.context_image_line.is_some(),
+                    egui::Button::new("Annotate Image..."),
+                )
+                .clicked()
+            {
+                if let Some(line_idx) = self.context_image_line {
+                    self.open_annotation_editor(ui.ctx(), text, line_idx);
+                }
+                ui.close_menu();
+            }
+            if ui
+                .add_enabled(
+                    self.context_image_line.is_some(),
+                    egui::Button::new("Export Image..."),
+                )
+                .clicked()
+            {
+                if let Some(line_idx) = self.context_image_line {
+                    self.export_image(text, line_idx);
+                }
+                ui.close_menu();
+            }
+        });
+
+        if changed {
+            response.mark_changed();
+        }
     }
 
     pub fn show(&mut self, ui: &mut Ui, text: &mut String) -> Response {
@@ -66,7 +938,8 @@ impl TextEditor {
             available_rect.height().max(100.0),
         );
 
-        let (rect, mut response) = ui.allocate_at_least(desired_size, egui::Sense::click());
+        let (rect, mut response) =
+            ui.allocate_at_least(desired_size, egui::Sense::click_and_drag());
 
         // Draw background
         ui.painter()
@@ -88,6 +961,14 @@ impl TextEditor {
 
         // Handle keyboard input
         self.handle_keyboard_input(&mut response, ui, text);
+        self.handle_dropped_files(&mut response, ui, text);
+        self.handle_sketch_drawing(&mut response, ui, text);
+        self.advance_animations(ui);
+
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        for image in self.images.values_mut() {
+            image.update_resolution(pixels_per_point);
+        }
 
         let font_id = &self.font_metrics.font_id.clone();
         let text_rect = rect.shrink(self.margin);
@@ -104,7 +985,7 @@ impl TextEditor {
             }
         };
         let digits = line_count.to_string().len().max(2);
-        let line_number_width = ui.fonts(|f| f.glyph_width(&font_id, '0')) * (digits + 1) as f32;
+        let line_number_width = ui.fonts(|f| f.glyph_width(font_id, '0')) * (digits + 1) as f32;
 
         let line_numbers_rect = Rect::from_min_size(
             text_rect.min,
@@ -115,26 +996,323 @@ impl TextEditor {
             Vec2::new(text_rect.width() - line_number_width, text_rect.height()),
         );
 
+        self.page_height = content_rect.intersect(ui.clip_rect()).height();
+
         // Handle focus & cursor clicking into text
         if response.clicked() {
             response.request_focus();
-            self.handle_click_positioning(&response, ui, text, &font_id, content_rect);
+            self.handle_click_positioning(&response, ui, text, font_id, content_rect);
         }
+        self.handle_text_drag(&response, ui, text, font_id, content_rect);
 
         // Render lines
+        let carets = self.carets();
+        let cursors: Vec<(usize, usize)> = carets.iter().map(|caret| caret.position).collect();
+        let selections: Vec<_> = carets.iter().filter_map(Caret::selection).collect();
+        let caret = CaretPaint {
+            shape: self.caret_shape,
+            width: self.caret_width,
+            visible: self.caret_visible(ui, &response),
+            highlight_line: self.highlight_current_line,
+        };
         self.renderer.render(
             ui,
             text,
-            &font_id,
+            font_id,
             line_numbers_rect,
             content_rect,
             &self.images,
-            self.cursor_line,
-            self.cursor_column,
+            &self.sketches,
+            self.cursor(),
+            &cursors,
+            &self.ime_preedit,
+            &selections,
+            self.tab_width,
+            self.show_whitespace,
+            self.line_break,
+            &caret,
         );
         // self.render_lines(ui, text, &font_id, line_numbers_rect, content_rect);
 
-        response
+        if response.has_focus() {
+            // Tells the platform where to put the input method's candidate window
+            let cursor_rect = self.renderer.cursor_rect().unwrap_or_else(|| {
+                Rect::from_min_size(
+                    content_rect.min,
+                    Vec2::new(1.0, ui.fonts(|f| f.row_height(font_id))),
+                )
+            });
+            let to_global = ui
+                .ctx()
+                .layer_transform_to_global(ui.layer_id())
+                .unwrap_or_default();
+            ui.ctx().output_mut(|o| {
+                o.ime = Some(egui::output::IMEOutput {
+                    rect: to_global * rect,
+                    cursor_rect: to_global * cursor_rect,
+                });
+            });
+        } else {
+            self.ime_preedit.clear();
+        }
+
+        self.show_context_menu(&mut response, ui, text);
+        self.show_image_editor(&mut response, ui, text);
+        self.show_annotation_editor(&mut response, ui, text);
+        if response.has_focus()
+            && let Some(sketch_rect) = self.renderer.sketch_rect(self.cursor_line)
+        {
+            self.sketch_pen.show_toolbar(ui.ctx(), sketch_rect);
+        }
+        self.update_image_textures(ui, content_rect.height());
+
+        response
+    }
+
+    /// Whether the caret is in the shown half of its blink. Input restarts
+    /// the blink, and it stops once the editor has been idle for a while.
+    fn caret_visible(&mut self, ui: &Ui, response: &Response) -> bool {
+        CaretBlink::read_system(ui.ctx());
+        let Some(blink) = self.blink() else {
+            return true;
+        };
+        if !response.has_focus() {
+            return true;
+        }
+
+        let now = ui.input(|i| i.time);
+        let active = response.gained_focus()
+            || ui.input(|i| {
+                i.events.iter().any(|event| {
+                    matches!(
+                        event,
+                        egui::Event::Key { pressed: true, .. }
+                            | egui::Event::Text(_)
+                            | egui::Event::Paste(_)
+                            | egui::Event::Ime(_)
+                            | egui::Event::PointerButton { pressed: true, .. }
+                    )
+                })
+            });
+        if active {
+            self.blink_start = now;
+        }
+
+        let (visible, change_in) = blink.phase(now - self.blink_start);
+        if let Some(seconds) = change_in {
+            ui.ctx()
+                .request_repaint_after(std::time::Duration::from_secs_f64(seconds));
+        }
+        visible
+    }
+
+    /// Uploads finished background decodes, starts decoding images near the
+    /// viewport and evicts far-offscreen textures when over the memory budget.
+    fn update_image_textures(&mut self, ui: &Ui, preload_distance: f32) {
+        for (id, result) in self.image_loader.finished() {
+            let Some(image) = self.images.get_mut(&id) else {
+                continue;
+            };
+            if image.state() != LoadState::Loading {
+                continue;
+            }
+
+            match result {
+                Ok(decoded) => image.upload(ui.ctx(), decoded),
+                Err(err) => {
+                    log::warn!("Failed to decode image {id}: {err}");
+                    image.set_state(LoadState::Failed);
+                }
+            }
+        }
+
+        let distances = self.renderer.image_distances();
+        for (id, image) in self.images.iter_mut() {
+            let near_viewport = distances
+                .get(id)
+                .is_some_and(|distance| *distance <= preload_distance);
+            if near_viewport && image.state() == LoadState::Unloaded {
+                image.set_state(LoadState::Loading);
+                self.image_loader
+                    .request(ui.ctx(), *id, image.source().clone());
+            }
+        }
+
+        // Duplicated images share a texture, which only takes memory once
+        let mut counted = HashSet::new();
+        let mut texture_bytes: usize = self
+            .images
+            .values()
+            .filter(|image| image.texture_id().is_none_or(|id| counted.insert(id)))
+            .map(EditorImage::texture_bytes)
+            .sum();
+        if texture_bytes <= self.texture_budget {
+            return;
+        }
+
+        // Images no longer in the text are treated as infinitely far away
+        let mut candidates: Vec<(usize, f32)> = self
+            .images
+            .iter()
+            .filter(|(_, image)| image.state() == LoadState::Loaded)
+            .map(|(id, _)| (*id, distances.get(id).copied().unwrap_or(f32::INFINITY)))
+            .filter(|(_, distance)| *distance > preload_distance)
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (id, _) in candidates {
+            if texture_bytes <= self.texture_budget {
+                break;
+            }
+            let texture = self.images.get(&id).and_then(EditorImage::texture_id);
+            let shared = texture.is_some()
+                && self
+                    .images
+                    .iter()
+                    .any(|(other_id, other)| *other_id != id && other.texture_id() == texture);
+            if let Some(image) = self.images.get_mut(&id) {
+                // A shared texture is only freed with the last image using it
+                if !shared {
+                    texture_bytes = texture_bytes.saturating_sub(image.texture_bytes());
+                }
+                image.evict();
+            }
+        }
+    }
+
+    /// Draws or erases strokes while the pointer is dragged across a sketch.
+    ///
+    /// Each drag becomes a single undo step.
+    fn handle_sketch_drawing(&mut self, response: &mut Response, ui: &Ui, text: &mut String) {
+        if response.drag_started()
+            && let Some(origin) = ui.input(|i| i.pointer.press_origin())
+            && let Some((line, rect)) = self.renderer.sketch_at(origin)
+        {
+            response.request_focus();
+            self.history.break_group();
+            self.cursor_line = line;
+            self.cursor_column = 0;
+            self.sketch_drag = Some(SketchDrag {
+                line,
+                rect,
+                sketch_id: None,
+            });
+
+            if self.sketch_pen.tool == SketchTool::Pen
+                && let Some(id) = self.begin_sketch_edit(text, line)
+                && let Some(sketch) = self.sketches.get_mut(&id)
+            {
+                let point = origin - rect.min;
+                sketch
+                    .strokes
+                    .push(self.sketch_pen.stroke([point.x, point.y]));
+                self.sketch_drag
+                    .as_mut()
+                    .expect("drag was just started")
+                    .sketch_id = Some(id);
+                response.mark_changed();
+            }
+        }
+
+        let Some(drag) = &self.sketch_drag else {
+            return;
+        };
+        let (line, rect, sketch_id) = (drag.line, drag.rect, drag.sketch_id);
+
+        if response.dragged()
+            && let Some(pos) = response.interact_pointer_pos()
+        {
+            let point = (pos - rect.min).clamp(Vec2::ZERO, rect.size());
+            let point = [point.x, point.y];
+
+            match self.sketch_pen.tool {
+                SketchTool::Pen => {
+                    if let Some(stroke) = sketch_id
+                        .and_then(|id| self.sketches.get_mut(&id))
+                        .and_then(|sketch| sketch.strokes.last_mut())
+                        && stroke.points.last() != Some(&point)
+                    {
+                        stroke.points.push(point);
+                    }
+                }
+                SketchTool::Eraser => {
+                    let current =
+                        sketch_id.or_else(|| text.lines().nth(line).and_then(extract_sketch_id));
+                    let hit = current
+                        .and_then(|id| self.sketches.get(&id))
+                        .is_some_and(|sketch| touches_stroke(sketch, point));
+
+                    if hit {
+                        let id = match sketch_id {
+                            Some(id) => Some(id),
+                            None => self.begin_sketch_edit(text, line),
+                        };
+                        if let Some(id) = id
+                            && let Some(sketch) = self.sketches.get_mut(&id)
+                        {
+                            erase_at(sketch, point);
+                            if let Some(drag) = &mut self.sketch_drag {
+                                drag.sketch_id = Some(id);
+                            }
+                            response.mark_changed();
+                        }
+                    }
+                }
+            }
+        }
+
+        if response.drag_stopped() {
+            self.sketch_drag = None;
+        }
+    }
+
+    fn handle_dropped_files(&mut self, response: &mut Response, ui: &Ui, text: &mut String) {
+        let dropped_files = ui.input(|i| i.raw.dropped_files.clone());
+        for file in dropped_files {
+            let bytes: Arc<[u8]> = if let Some(bytes) = file.bytes {
+                bytes
+            } else if let Some(path) = &file.path {
+                match std::fs::read(path) {
+                    Ok(bytes) => bytes.into(),
+                    Err(err) => {
+                        log::warn!("Failed to read dropped file {}: {err}", path.display());
+                        continue;
+                    }
+                }
+            } else {
+                continue;
+            };
+
+            match self.insert_image(ui.ctx(), text, bytes) {
+                Ok(()) => response.mark_changed(),
+                Err(err) => log::warn!("Dropped file {} is not an image: {err}", file.name),
+            }
+        }
+    }
+
+    fn advance_animations(&mut self, ui: &Ui) {
+        if !self.animate_images {
+            return;
+        }
+
+        let dt = ui.input(|i| i.stable_dt);
+        let hovered_image = self.renderer.hovered_image();
+        let mut next_frame: Option<f32> = None;
+
+        for (id, image) in self.images.iter_mut() {
+            // Hovering an animated image pauses it
+            if Some(*id) == hovered_image {
+                continue;
+            }
+            if let Some(delay) = image.advance(dt) {
+                next_frame = Some(next_frame.map_or(delay, |next| next.min(delay)));
+            }
+        }
+
+        if let Some(delay) = next_frame {
+            ui.ctx()
+                .request_repaint_after(std::time::Duration::from_secs_f32(delay));
+        }
     }
 
     fn handle_click_positioning(
@@ -146,63 +1324,156 @@ impl TextEditor {
         content_rect: Rect,
     ) {
         if let Some(click_pos) = response.interact_pointer_pos() {
-            let relative_pos = click_pos - content_rect.min;
-            let base_line_height = ui.fonts(|f| f.row_height(font_id));
+            let position = self.position_at(ui, text, font_id, content_rect, click_pos);
+            let modifiers = ui.input(|i| i.modifiers);
 
-            let lines: Vec<&str> = text.lines().collect();
+            // Alt+click adds a cursor, which becomes the primary one
+            if modifiers.alt {
+                let mut carets = self.carets();
+                let primary = Caret {
+                    position,
+                    anchor: None,
+                };
+                carets.push(primary);
+                self.set_carets(primary, carets);
+                return;
+            }
 
-            let max_line = if text.ends_with('\n') {
-                lines.len()
+            // Shift+click extends the selection from the current cursor
+            if modifiers.shift {
+                let cursor = self.cursor();
+                self.selection_anchor.get_or_insert(cursor);
             } else {
-                lines.len().saturating_sub(1)
-            };
+                self.selection_anchor = None;
+            }
+            self.extra_carets.clear();
+            (self.cursor_line, self.cursor_column) = position;
+        }
+    }
 
-            let mut current_y = 0.0;
-            let mut clicked_line = None;
+    /// Selects text while the pointer is dragged anywhere but on a sketch.
+    /// Dragging with Alt held selects a box, with a cursor on every line.
+    fn handle_text_drag(
+        &mut self,
+        response: &Response,
+        ui: &Ui,
+        text: &str,
+        font_id: &FontId,
+        content_rect: Rect,
+    ) {
+        if self.sketch_drag.is_some() {
+            return;
+        }
 
-            // Find which line was clicked by walking through Y positions
-            for (line_idx, line) in lines.iter().enumerate() {
-                let line_height = calculate_line_height(line, base_line_height, &self.images);
+        if response.drag_started()
+            && let Some(origin) = ui.input(|i| i.pointer.press_origin())
+        {
+            response.request_focus();
+            self.history.break_group();
+            let position = self.position_at(ui, text, font_id, content_rect, origin);
+            (self.cursor_line, self.cursor_column) = position;
+            self.extra_carets.clear();
 
-                if relative_pos.y >= current_y && relative_pos.y < current_y + line_height {
-                    clicked_line = Some(line_idx);
-                    break;
-                }
-                current_y += line_height;
+            if ui.input(|i| i.modifiers.alt) {
+                self.box_origin = Some((position.0, origin.x - content_rect.left()));
+                self.selection_anchor = None;
+            } else {
+                self.box_origin = None;
+                self.selection_anchor = Some(position);
             }
+        } else if response.dragged()
+            && let Some(pos) = response.interact_pointer_pos()
+        {
+            let position = self.position_at(ui, text, font_id, content_rect, pos);
+            if let Some(origin) = self.box_origin {
+                self.select_box(
+                    ui,
+                    text,
+                    font_id,
+                    origin,
+                    (position.0, pos.x - content_rect.left()),
+                );
+            } else if self.selection_anchor.is_some() {
+                (self.cursor_line, self.cursor_column) = position;
+            }
+        }
+
+        if response.drag_stopped() {
+            self.box_origin = None;
+        }
+    }
 
-            let clicked_line = clicked_line.unwrap_or(max_line);
+    /// Puts a cursor on every line between two corners of a box, given as a
+    /// line and a horizontal offset, each selecting the text between the
+    /// corners' offsets. The cursor on the line of `corner` is the primary one.
+    fn select_box(
+        &mut self,
+        ui: &Ui,
+        text: &str,
+        font_id: &FontId,
+        origin: (usize, f32),
+        corner: (usize, f32),
+    ) {
+        let lines: Vec<&str> = text.lines().collect();
+        let carets: Vec<Caret> = (origin.0.min(corner.0)..=origin.0.max(corner.0))
+            .map(|line_idx| {
+                let line = lines.get(line_idx).copied().unwrap_or_default();
+                Caret {
+                    position: (
+                        line_idx,
+                        column_at_x(ui, font_id, line, corner.1, self.tab_width),
+                    ),
+                    anchor: Some((
+                        line_idx,
+                        column_at_x(ui, font_id, line, origin.1, self.tab_width),
+                    )),
+                }
+            })
+            .collect();
 
-            // Rest of character positioning logic remains the same...
-            if clicked_line < lines.len() {
-                if let Some(line) = lines.get(clicked_line) {
-                    // Find closest character position in the line
-                    let mut best_column = 0;
-                    let mut best_distance = f32::INFINITY;
+        if let Some(primary) = carets.iter().find(|caret| caret.position.0 == corner.0) {
+            self.set_carets(*primary, carets.clone());
+        }
+    }
 
-                    for col in 0..=line.chars().count() {
-                        let text_before = line.chars().take(col).collect::<String>();
-                        let x_pos = ui.fonts(|f| {
-                            f.layout_no_wrap(text_before, font_id.clone(), Color32::WHITE)
-                                .size()
-                                .x
-                        });
+    /// The text position closest to the screen position `pos`.
+    fn position_at(
+        &self,
+        ui: &Ui,
+        text: &str,
+        font_id: &FontId,
+        content_rect: Rect,
+        pos: Pos2,
+    ) -> (usize, usize) {
+        let relative_pos = pos - content_rect.min;
+        let base_line_height = ui.fonts(|f| f.row_height(font_id));
 
-                        let distance = (x_pos - relative_pos.x).abs();
-                        if distance < best_distance {
-                            best_distance = distance;
-                            best_column = col;
-                        }
-                    }
+        let lines: Vec<&str> = text.lines().collect();
 
-                    self.cursor_line = clicked_line;
-                    self.cursor_column = best_column;
-                }
-            } else {
-                // We're on the trailing newline (empty line after text ending with '\n')
-                self.cursor_line = clicked_line;
-                self.cursor_column = 0;
+        let mut current_y = 0.0;
+        let mut clicked_line = None;
+
+        // Find which line was clicked by walking through Y positions
+        for (line_idx, line) in lines.iter().enumerate() {
+            let line_height =
+                calculate_line_height(line, base_line_height, &self.images, &self.sketches);
+
+            if relative_pos.y >= current_y && relative_pos.y < current_y + line_height {
+                clicked_line = Some(line_idx);
+                break;
             }
+            current_y += line_height;
+        }
+
+        let clicked_line = clicked_line.unwrap_or(last_line(text));
+
+        match lines.get(clicked_line) {
+            Some(line) => (
+                clicked_line,
+                column_at_x(ui, font_id, line, relative_pos.x, self.tab_width),
+            ),
+            // We're on the trailing newline (empty line after text ending with '\n')
+            None => (clicked_line, 0),
         }
     }
 
@@ -225,26 +1496,209 @@ impl TextEditor {
 
         let events = ui.input(|i| i.events.clone());
         for event in events {
+            // Committed input method text is typed like any other text
+            let event = match event {
+                egui::Event::Ime(ime) => match ime {
+                    egui::ImeEvent::Preedit(preedit) => {
+                        self.ime_preedit = preedit;
+                        continue;
+                    }
+                    egui::ImeEvent::Commit(committed) => {
+                        self.ime_preedit.clear();
+                        if committed.is_empty() {
+                            continue;
+                        }
+                        egui::Event::Text(committed)
+                    }
+                    egui::ImeEvent::Enabled | egui::ImeEvent::Disabled => {
+                        self.ime_preedit.clear();
+                        continue;
+                    }
+                },
+                // Keys belong to the input method while it is composing
+                egui::Event::Key { .. } if !self.ime_preedit.is_empty() => continue,
+                event => event,
+            };
+
+            if !self.extra_carets.is_empty() {
+                if self.handle_multi_caret_event(&event, text) {
+                    response.mark_changed();
+                    continue;
+                }
+                if self.move_carets(&event, text) {
+                    self.history.break_group();
+                    continue;
+                }
+                // Other edits and moves only work with a single cursor
+                if let egui::Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
+                } = &event
+                    && ((is_movement_key(*key) && !modifiers.alt)
+                        || matches!(key, Key::Tab | Key::Escape)
+                        || (modifiers.command
+                            && matches!(key, Key::A | Key::Backspace | Key::Delete)))
+                {
+                    self.extra_carets.clear();
+                }
+            }
+
+            // Shift extends the selection while moving, any other move drops it
+            if let egui::Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } = &event
+                && is_movement_key(*key)
+                && !modifiers.alt
+            {
+                if modifiers.shift {
+                    let cursor = self.cursor();
+                    self.selection_anchor.get_or_insert(cursor);
+                } else {
+                    self.selection_anchor = None;
+                }
+            }
+
             match event {
                 egui::Event::Text(new_text) => {
+                    self.record_replacing_selection(text, EditKind::Typing);
                     self.insert_text_at_cursor(text, &new_text);
                     self.cursor_column += new_text.chars().count();
                     response.mark_changed();
                 }
+                egui::Event::Paste(pasted) => {
+                    let pasted = pasted.replace("\r\n", "\n");
+                    self.record_replacing_selection(text, EditKind::Other);
+                    let start = byte_position(text, self.cursor());
+                    text.insert_str(start, &pasted);
+                    (self.cursor_line, self.cursor_column) =
+                        position_of(text, start + pasted.len());
+                    response.mark_changed();
+                }
                 egui::Event::Key {
-                    key, pressed: true, ..
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
                 } => match key {
-                    Key::Backspace => {
-                        self.delete_char_before_cursor(text);
+                    Key::Z if modifiers.command && modifiers.shift => {
+                        self.redo(text);
+                        response.mark_changed();
+                    }
+                    Key::Z if modifiers.command => {
+                        self.undo(text);
+                        response.mark_changed();
+                    }
+                    Key::Y if modifiers.command => {
+                        self.redo(text);
+                        response.mark_changed();
+                    }
+                    Key::A if modifiers.command => {
+                        self.history.break_group();
+                        self.selection_anchor = Some((0, 0));
+                        self.cursor_line = last_line(text);
+                        self.cursor_column = line_length(text, self.cursor_line);
+                    }
+                    Key::Backspace | Key::Delete
+                        if self.selection().is_some() || !modifiers.command =>
+                    {
+                        self.delete_at_carets(text, key == Key::Delete);
+                        response.mark_changed();
+                    }
+                    Key::Backspace if modifiers.command => {
+                        let start = self.word_left(text);
+                        self.delete_range(text, start, self.cursor());
+                        response.mark_changed();
+                    }
+                    Key::Delete if modifiers.command => {
+                        let end = self.word_right(text);
+                        self.delete_range(text, self.cursor(), end);
                         response.mark_changed();
                     }
                     Key::Enter => {
-                        self.insert_text_at_cursor(text, "\n");
+                        self.record_replacing_selection(text, EditKind::Other);
+                        // The new line starts at the indentation of the current one
+                        let indent: String = text
+                            .lines()
+                            .nth(self.cursor_line)
+                            .unwrap_or_default()
+                            .chars()
+                            .take(self.cursor_column)
+                            .take_while(|c| matches!(c, ' ' | '\t'))
+                            .collect();
+                        self.insert_text_at_cursor(text, &format!("\n{indent}"));
                         self.cursor_line += 1;
-                        self.cursor_column = 0;
+                        self.cursor_column = indent.chars().count();
                         response.mark_changed();
                     }
+                    Key::Tab => {
+                        if modifiers.shift {
+                            let lines = self
+                                .selected_lines()
+                                .unwrap_or(self.cursor_line..=self.cursor_line);
+                            self.outdent_lines(text, lines);
+                        } else if let Some(lines) = self.selected_lines() {
+                            self.indent_lines(text, lines);
+                        } else {
+                            self.record_replacing_selection(text, EditKind::Other);
+                            let indent = self.indent_at_cursor(text);
+                            self.insert_text_at_cursor(text, &indent);
+                            self.cursor_column += indent.chars().count();
+                        }
+                        response.mark_changed();
+                    }
+                    Key::Home if modifiers.command => {
+                        self.history.break_group();
+                        (self.cursor_line, self.cursor_column) = (0, 0);
+                    }
+                    Key::End if modifiers.command => {
+                        self.history.break_group();
+                        self.cursor_line = last_line(text);
+                        self.cursor_column = line_length(text, self.cursor_line);
+                    }
+                    Key::Home => {
+                        self.history.break_group();
+                        // Toggles between the indentation and the start of the line
+                        let indent = text.lines().nth(self.cursor_line).map_or(0, |line| {
+                            line.chars().take_while(|c| c.is_whitespace()).count()
+                        });
+                        self.cursor_column = if self.cursor_column == indent {
+                            0
+                        } else {
+                            indent
+                        };
+                    }
+                    Key::End => {
+                        self.history.break_group();
+                        self.cursor_column = line_length(text, self.cursor_line);
+                    }
+                    Key::PageUp | Key::PageDown => {
+                        self.history.break_group();
+                        let base_line_height =
+                            ui.fonts(|f| f.row_height(&self.font_metrics.font_id));
+                        let target = self.page_target(text, base_line_height, key == Key::PageDown);
+                        self.move_vertically(ui, text, target);
+                    }
+                    Key::ArrowLeft if modifiers.command => {
+                        self.history.break_group();
+                        (self.cursor_line, self.cursor_column) = self.word_left(text);
+                    }
+                    Key::ArrowRight if modifiers.command => {
+                        self.history.break_group();
+                        (self.cursor_line, self.cursor_column) = self.word_right(text);
+                    }
+                    Key::ArrowLeft | Key::ArrowRight
+                        if self.visual_cursor_movement && self.cursor_bidi(text).is_some() =>
+                    {
+                        self.history.break_group();
+                        self.move_visually(text, key == Key::ArrowRight);
+                    }
                     Key::ArrowLeft => {
+                        self.history.break_group();
                         if self.cursor_column > 0 {
                             self.cursor_column -= 1;
                         } else if self.cursor_line > 0 {
@@ -257,6 +1711,7 @@ impl TextEditor {
                         }
                     }
                     Key::ArrowRight => {
+                        self.history.break_group();
                         let lines: Vec<&str> = text.lines().collect();
                         if let Some(current_line) = lines.get(self.cursor_line) {
                             if self.cursor_column < current_line.chars().count() {
@@ -275,35 +1730,41 @@ impl TextEditor {
                             }
                         }
                     }
-                    Key::ArrowUp => {
-                        if self.cursor_line > 0 {
-                            self.cursor_line -= 1;
-                            let lines: Vec<&str> = text.lines().collect();
-                            if let Some(new_line) = lines.get(self.cursor_line) {
-                                self.cursor_column =
-                                    self.cursor_column.min(new_line.chars().count());
-                            }
-                        }
+                    Key::ArrowUp | Key::ArrowDown if modifiers.command && modifiers.alt => {
+                        self.history.break_group();
+                        self.add_caret_vertically(ui, text, key == Key::ArrowDown);
                     }
-                    Key::ArrowDown => {
-                        let lines: Vec<&str> = text.lines().collect();
-
-                        let max_line = if text.ends_with('\n') {
-                            lines.len()
-                        } else {
-                            lines.len().saturating_sub(1)
-                        };
-
-                        if self.cursor_line < max_line {
-                            self.cursor_line += 1;
-                            if let Some(new_line) = lines.get(self.cursor_line) {
-                                self.cursor_column =
-                                    self.cursor_column.min(new_line.chars().count());
-                            } else {
-                                // We're on the trailing newline, set column to 0
-                                self.cursor_column = 0;
-                            }
-                        }
+                    Key::ArrowUp if modifiers.alt => {
+                        self.line_command(text, LineCommand::MoveUp);
+                        response.mark_changed();
+                    }
+                    Key::ArrowDown if modifiers.alt => {
+                        self.line_command(text, LineCommand::MoveDown);
+                        response.mark_changed();
+                    }
+                    Key::D if modifiers.command && modifiers.shift => {
+                        self.line_command(text, LineCommand::Duplicate);
+                        response.mark_changed();
+                    }
+                    Key::K if modifiers.command && modifiers.shift => {
+                        self.line_command(text, LineCommand::Delete);
+                        response.mark_changed();
+                    }
+                    Key::F5 => {
+                        self.insert_date_time(text);
+                        response.mark_changed();
+                    }
+                    Key::J if modifiers.command => {
+                        self.line_command(text, LineCommand::Join);
+                        response.mark_changed();
+                    }
+                    Key::ArrowUp if self.cursor_line > 0 => {
+                        self.history.break_group();
+                        self.move_vertically(ui, text, self.cursor_line - 1);
+                    }
+                    Key::ArrowDown if self.cursor_line < last_line(text) => {
+                        self.history.break_group();
+                        self.move_vertically(ui, text, self.cursor_line + 1);
                     }
                     _ => {}
                 },
@@ -312,39 +1773,533 @@ impl TextEditor {
         }
     }
 
-    fn insert_text_at_cursor(&self, text: &mut String, new_text: &str) {
-        let cursor_byte_pos = self.get_cursor_byte_position(text);
-        text.insert_str(cursor_byte_pos, new_text);
+    /// Applies typing, deletion, Enter and paste at every cursor as one undo
+    /// step. Returns whether `event` was such an edit.
+    fn handle_multi_caret_event(&mut self, event: &egui::Event, text: &mut String) -> bool {
+        match event {
+            egui::Event::Text(new_text) => {
+                self.edit_carets(text, EditKind::Typing, false, |text, _, caret| {
+                    Some((selection_range(text, caret), new_text.clone()))
+                });
+            }
+            egui::Event::Paste(pasted) => {
+                let pasted = pasted.replace("\r\n", "\n");
+                let lines: Vec<&str> = pasted.lines().collect();
+                // Each cursor gets its own line when there is one line per cursor
+                let per_caret = lines.len() == self.extra_carets.len() + 1;
+                self.edit_carets(text, EditKind::Other, false, |text, idx, caret| {
+                    let insert = if per_caret { lines[idx] } else { &pasted };
+                    Some((selection_range(text, caret), insert.to_string()))
+                });
+            }
+            egui::Event::Key {
+                key: key @ (Key::Backspace | Key::Delete),
+                pressed: true,
+                modifiers,
+                ..
+            } if !modifiers.command => self.delete_at_carets(text, *key == Key::Delete),
+            egui::Event::Key {
+                key: Key::Enter,
+                pressed: true,
+                ..
+            } => {
+                self.edit_carets(text, EditKind::Other, false, |text, _, caret| {
+                    let (line, column) =
+                        caret.selection().map_or(caret.position, |(start, _)| start);
+                    let indent: String = text
+                        .lines()
+                        .nth(line)
+                        .unwrap_or_default()
+                        .chars()
+                        .take(column)
+                        .take_while(|c| matches!(c, ' ' | '\t'))
+                        .collect();
+                    Some((selection_range(text, caret), format!("\n{indent}")))
+                });
+            }
+            _ => return false,
+        }
+        true
     }
 
-    fn delete_char_before_cursor(&mut self, text: &mut String) {
-        if self.cursor_column > 0 {
-            let cursor_byte_pos = self.get_cursor_byte_position(text);
-            if let Some((char_start, _)) =
-                text.char_indices().nth(cursor_byte_pos.saturating_sub(1))
-            {
-                text.remove(char_start);
+    /// Moves every cursor with the arrow keys, extending each selection while
+    /// Shift is held. Returns whether `event` was such a move.
+    fn move_carets(&mut self, event: &egui::Event, text: &str) -> bool {
+        let egui::Event::Key {
+            key,
+            pressed: true,
+            modifiers,
+            ..
+        } = event
+        else {
+            return false;
+        };
+        if modifiers.command || modifiers.alt {
+            return false;
+        }
+
+        let last = last_line(text);
+        let step = |(line, column): (usize, usize)| match key {
+            Key::ArrowLeft if column > 0 => Some((line, column - 1)),
+            Key::ArrowLeft if line > 0 => Some((line - 1, line_length(text, line - 1))),
+            Key::ArrowRight if column < line_length(text, line) => Some((line, column + 1)),
+            Key::ArrowRight if line < last => Some((line + 1, 0)),
+            Key::ArrowUp if line > 0 => Some((line - 1, column.min(line_length(text, line - 1)))),
+            Key::ArrowDown if line < last => {
+                Some((line + 1, column.min(line_length(text, line + 1))))
+            }
+            Key::ArrowLeft | Key::ArrowRight | Key::ArrowUp | Key::ArrowDown => {
+                Some((line, column))
+            }
+            _ => None,
+        };
+
+        let primary = self.caret();
+        let mut new_primary = primary;
+        let mut moved = Vec::new();
+        for caret in self.carets() {
+            let Some(position) = step(caret.position) else {
+                return false;
+            };
+            let anchor = modifiers
+                .shift
+                .then(|| caret.anchor.unwrap_or(caret.position));
+            let moved_caret = Caret { position, anchor };
+            if caret == primary {
+                new_primary = moved_caret;
             }
-            self.cursor_column -= 1;
+            moved.push(moved_caret);
         }
-        // ... handle line joining logic
+        self.set_carets(new_primary, moved);
+        true
     }
 
-    fn get_cursor_byte_position(&self, text: &str) -> usize {
+    /// Deletes the selection at every cursor, or the character before it, or
+    /// after it when `forward` is set, joining lines at their ends.
+    fn delete_at_carets(&mut self, text: &mut String, forward: bool) {
+        self.edit_carets(text, EditKind::Other, false, |text, _, caret| {
+            if caret.selection().is_some() {
+                return Some((selection_range(text, caret), String::new()));
+            }
+            let (line, column) = caret.position;
+            // At either end of a line the deletion joins it with its neighbour
+            let other = if forward {
+                if column < line_length(text, line) {
+                    (line, column + 1)
+                } else if line < last_line(text) {
+                    (line + 1, 0)
+                } else {
+                    return None;
+                }
+            } else if column > 0 {
+                (line, column - 1)
+            } else if line > 0 {
+                (line - 1, line_length(text, line - 1))
+            } else {
+                return None;
+            };
+            let (start, end) = if forward {
+                (caret.position, other)
+            } else {
+                (other, caret.position)
+            };
+            Some((
+                byte_position(text, start)..byte_position(text, end),
+                String::new(),
+            ))
+        });
+    }
+
+    /// Replaces text at every cursor as one undo step.
+    ///
+    /// `edit` gets the text, the cursor's index in text order and the cursor,
+    /// and returns the byte range to replace and its replacement, or `None` to
+    /// leave the text at that cursor alone. Each cursor ends up behind its
+    /// replacement, which is selected when `select` is set.
+    fn edit_carets(
+        &mut self,
+        text: &mut String,
+        kind: EditKind,
+        select: bool,
+        mut edit: impl FnMut(&str, usize, Caret) -> Option<(Range<usize>, String)>,
+    ) {
+        let primary = self.caret();
+        let carets = self.carets();
+        let edits: Vec<_> = carets
+            .iter()
+            .enumerate()
+            .map(|(idx, caret)| edit(text, idx, *caret))
+            .collect();
+        if edits.iter().all(Option::is_none) {
+            return;
+        }
+        self.history.record(text, self.cursor(), kind);
+
+        let offsets: Vec<usize> = carets
+            .iter()
+            .map(|caret| byte_position(text, caret.position))
+            .collect();
+        let mut moved = Vec::with_capacity(carets.len());
+        let mut new_primary = primary;
+        // Growth of the text so far, and where the last edit ended in the original text
+        let mut delta: isize = 0;
+        let mut edited_until = 0;
+
+        for ((caret, edit), offset) in carets.iter().zip(edits).zip(offsets) {
+            let mut anchor = None;
+            let offset = match edit {
+                Some((range, insert)) => {
+                    // Overlapping edits, like Backspace at neighbouring cursors, apply once
+                    let start = range.start.max(edited_until);
+                    let end = range.end.max(start);
+                    let shifted = start.saturating_add_signed(delta);
+                    text.replace_range(shifted..end.saturating_add_signed(delta), &insert);
+                    delta += insert.len() as isize - (end - start) as isize;
+                    edited_until = end;
+                    if select {
+                        anchor = Some(position_of(text, shifted));
+                    }
+                    shifted + insert.len()
+                }
+                None => offset.max(edited_until).saturating_add_signed(delta),
+            };
+
+            let moved_caret = Caret {
+                position: position_of(text, offset),
+                anchor,
+            };
+            if *caret == primary {
+                new_primary = moved_caret;
+            }
+            moved.push(moved_caret);
+        }
+        self.set_carets(new_primary, moved);
+    }
+
+    /// Adds a cursor on the line above or below the primary one, at the same
+    /// horizontal position, and makes it the primary cursor.
+    fn add_caret_vertically(&mut self, ui: &Ui, text: &str, down: bool) {
+        let line_idx = match down {
+            true if self.cursor_line < last_line(text) => self.cursor_line + 1,
+            false if self.cursor_line > 0 => self.cursor_line - 1,
+            _ => return,
+        };
+
+        let mut carets = self.carets();
+        self.selection_anchor = None;
+        self.move_vertically(ui, text, line_idx);
+        carets.push(self.caret());
+        self.set_carets(self.caret(), carets);
+    }
+
+    /// Records the state before an edit and removes the selection it
+    /// replaces. Replacing a selection is never merged with earlier typing.
+    fn record_replacing_selection(&mut self, text: &mut String, kind: EditKind) {
+        let Some((start, end)) = self.selection() else {
+            self.history.record(text, self.cursor(), kind);
+            self.selection_anchor = None;
+            return;
+        };
+
+        self.history.record(text, self.cursor(), EditKind::Other);
+        text.replace_range(byte_position(text, start)..byte_position(text, end), "");
+        (self.cursor_line, self.cursor_column) = start;
+        self.selection_anchor = None;
+    }
+
+    /// Lines touched by a selection spanning more than one line. A selection
+    /// ending at the start of a line leaves that line out.
+    fn selected_lines(&self) -> Option<RangeInclusive<usize>> {
+        let (start, end) = self.selection()?;
+        if start.0 == end.0 {
+            return None;
+        }
+        let last = if end.1 == 0 { end.0 - 1 } else { end.0 };
+        Some(start.0..=last)
+    }
+
+    /// What Tab inserts at the cursor: a tab, or spaces up to the next tab stop.
+    fn indent_at_cursor(&self, text: &str) -> String {
+        if !self.indent_with_spaces {
+            return "\t".to_string();
+        }
+        let line = text.lines().nth(self.cursor_line).unwrap_or_default();
+        let before: String = line.chars().take(self.cursor_column).collect();
+        let column = expand_tabs(&before, self.tab_width).chars().count();
+        " ".repeat(self.tab_width - column % self.tab_width)
+    }
+
+    /// Adds one level of indentation to each non-empty line in `lines`.
+    fn indent_lines(&mut self, text: &mut String, lines: RangeInclusive<usize>) {
+        self.history.record(text, self.cursor(), EditKind::Other);
+        let unit = if self.indent_with_spaces {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_string()
+        };
+
+        for line_idx in lines.rev() {
+            let Some(range) = line_byte_range(text, line_idx) else {
+                continue;
+            };
+            if range.is_empty() {
+                continue;
+            }
+            text.insert_str(range.start, &unit);
+            self.shift_columns(line_idx, unit.len(), 0);
+        }
+    }
+
+    /// Removes one level of indentation, a tab or up to a tab width of
+    /// spaces, from each line in `lines`.
+    fn outdent_lines(&mut self, text: &mut String, lines: RangeInclusive<usize>) {
+        let removals: Vec<(usize, usize, usize)> = lines
+            .filter_map(|line_idx| {
+                let range = line_byte_range(text, line_idx)?;
+                let line = &text[range.clone()];
+                let count = if line.starts_with('\t') {
+                    1
+                } else {
+                    line.chars()
+                        .take(self.tab_width)
+                        .take_while(|&c| c == ' ')
+                        .count()
+                };
+                (count > 0).then_some((line_idx, range.start, count))
+            })
+            .collect();
+        if removals.is_empty() {
+            return;
+        }
+
+        self.history.record(text, self.cursor(), EditKind::Other);
+        for (line_idx, start, count) in removals.into_iter().rev() {
+            text.replace_range(start..start + count, "");
+            self.shift_columns(line_idx, 0, count);
+        }
+    }
+
+    /// Keeps the cursor and selection anchor on `line_idx` next to the same
+    /// text after characters were added or removed at the start of the line.
+    fn shift_columns(&mut self, line_idx: usize, added: usize, removed: usize) {
+        let shift = |(line, column): (usize, usize)| {
+            if line != line_idx {
+                (line, column)
+            } else if added > 0 && column == 0 {
+                // Whole selected lines stay selected
+                (line, 0)
+            } else {
+                (line, (column + added).saturating_sub(removed))
+            }
+        };
+        (self.cursor_line, self.cursor_column) = shift(self.cursor());
+        self.selection_anchor = self.selection_anchor.map(shift);
+    }
+
+    /// Moves the cursor to the column of `line_idx` closest to the preferred
+    /// horizontal position, which is taken from the cursor when this is not a
+    /// continuation of earlier vertical moves.
+    fn move_vertically(&mut self, ui: &Ui, text: &str, line_idx: usize) {
+        let font_id = &self.font_metrics.font_id;
+        let x = match self.preferred_x {
+            Some((x, position)) if position == self.cursor() => x,
+            _ => {
+                let line = text.lines().nth(self.cursor_line).unwrap_or_default();
+                column_x(ui, font_id, line, self.cursor_column, self.tab_width)
+            }
+        };
+
+        let line = text.lines().nth(line_idx).unwrap_or_default();
+        self.cursor_line = line_idx;
+        self.cursor_column = column_at_x(ui, font_id, line, x, self.tab_width);
+        self.preferred_x = Some((x, self.cursor()));
+    }
+
+    /// Reordering of the cursor line, if it contains right-to-left text.
+    fn cursor_bidi(&self, text: &str) -> Option<BidiLine> {
+        BidiLine::new(text.lines().nth(self.cursor_line)?)
+    }
+
+    /// Moves the cursor one character left or right as drawn, to the end of
+    /// the previous line or the start of the next one at the edges.
+    fn move_visually(&mut self, text: &str, right: bool) {
+        let position = (self.cursor_line, self.cursor_column);
+        let slot = self
+            .visual_slot
+            .and_then(|(slot, at)| (at == position).then_some(slot));
+        let step = self
+            .cursor_bidi(text)
+            .and_then(|bidi| bidi.step(self.cursor_column, slot, right));
+        if let Some((column, slot)) = step {
+            self.cursor_column = column;
+            self.visual_slot = Some((slot, (self.cursor_line, column)));
+        } else if right && self.cursor_line < last_line(text) {
+            self.cursor_line += 1;
+            self.cursor_column = 0;
+        } else if !right && self.cursor_line > 0 {
+            self.cursor_line -= 1;
+            self.cursor_column = line_length(text, self.cursor_line);
+        }
+    }
+
+    /// Start of the word before the cursor, or the end of the previous line
+    /// at the start of a line.
+    fn word_left(&self, text: &str) -> (usize, usize) {
+        let column = self.cursor_column.min(line_length(text, self.cursor_line));
+        if column == 0 && self.cursor_line > 0 {
+            let line = self.cursor_line - 1;
+            return (line, line_length(text, line));
+        }
+        let line = text.lines().nth(self.cursor_line).unwrap_or_default();
+        (self.cursor_line, previous_word_boundary(line, column))
+    }
+
+    /// End of the word after the cursor, or the start of the next line at the
+    /// end of a line.
+    fn word_right(&self, text: &str) -> (usize, usize) {
+        let length = line_length(text, self.cursor_line);
+        if self.cursor_column >= length && self.cursor_line < last_line(text) {
+            return (self.cursor_line + 1, 0);
+        }
+        let line = text.lines().nth(self.cursor_line).unwrap_or_default();
+        (
+            self.cursor_line,
+            next_word_boundary(line, self.cursor_column),
+        )
+    }
+
+    /// Line reached by moving one viewport height up or down, measured with
+    /// the real height of image and sketch lines. Moves at least one line.
+    fn page_target(&self, text: &str, base_line_height: f32, down: bool) -> usize {
         let lines: Vec<&str> = text.lines().collect();
-        let mut byte_pos = 0;
-
-        for (i, line) in lines.iter().enumerate() {
-            if i == self.cursor_line {
-                byte_pos += line
-                    .chars()
-                    .take(self.cursor_column)
-                    .map(|c| c.len_utf8())
-                    .sum::<usize>();
+        let last = last_line(text);
+        let height = |line_idx: usize| {
+            lines.get(line_idx).map_or(base_line_height, |line| {
+                calculate_line_height(line, base_line_height, &self.images, &self.sketches)
+            })
+        };
+
+        let mut line_idx = self.cursor_line.min(last);
+        let mut travelled = 0.0;
+        loop {
+            let (next, passed) = match down {
+                true if line_idx < last => (line_idx + 1, line_idx),
+                false if line_idx > 0 => (line_idx - 1, line_idx - 1),
+                _ => break,
+            };
+            let passed_height = height(passed);
+            if line_idx != self.cursor_line && travelled + passed_height > self.page_height {
                 break;
             }
-            byte_pos += line.len() + 1; // +1 for newline
+            travelled += passed_height;
+            line_idx = next;
+        }
+        line_idx
+    }
+
+    /// Deletes the text between two cursor positions as one undo step and
+    /// moves the cursor to `start`.
+    fn delete_range(&mut self, text: &mut String, start: (usize, usize), end: (usize, usize)) {
+        let range = byte_position(text, start)..byte_position(text, end);
+        if range.is_empty() {
+            return;
+        }
+        self.history.record(text, self.cursor(), EditKind::Other);
+        text.replace_range(range, "");
+        (self.cursor_line, self.cursor_column) = start;
+        self.selection_anchor = None;
+    }
+
+    fn insert_text_at_cursor(&self, text: &mut String, new_text: &str) {
+        let cursor_byte_pos = self.get_cursor_byte_position(text);
+        text.insert_str(cursor_byte_pos, new_text);
+    }
+
+    fn get_cursor_byte_position(&self, text: &str) -> usize {
+        byte_position(text, self.cursor())
+    }
+}
+
+/// Byte offset of a `(line, column)` position in `text`.
+fn byte_position(text: &str, (line_idx, column): (usize, usize)) -> usize {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut byte_pos = 0;
+
+    for (i, line) in lines.iter().enumerate() {
+        if i == line_idx {
+            byte_pos += line
+                .chars()
+                .take(column)
+                .map(|c| c.len_utf8())
+                .sum::<usize>();
+            break;
         }
-        byte_pos
+        byte_pos += line.len() + 1; // +1 for newline
     }
+    byte_pos
+}
+
+fn is_movement_key(key: Key) -> bool {
+    matches!(
+        key,
+        Key::ArrowLeft
+            | Key::ArrowRight
+            | Key::ArrowUp
+            | Key::ArrowDown
+            | Key::Home
+            | Key::End
+            | Key::PageUp
+            | Key::PageDown
+    )
+}
+
+/// Column of `line` closest to the horizontal offset `x`.
+fn column_at_x(ui: &Ui, font_id: &FontId, line: &str, x: f32, tab_width: usize) -> usize {
+    let mut best_column = 0;
+    let mut best_distance = f32::INFINITY;
+
+    for col in 0..=line.chars().count() {
+        let distance = (column_x(ui, font_id, line, col, tab_width) - x).abs();
+        if distance < best_distance {
+            best_distance = distance;
+            best_column = col;
+        }
+    }
+    best_column
+}
+
+/// Line and column of the byte offset `byte` in `text`.
+fn position_of(text: &str, byte: usize) -> (usize, usize) {
+    let before = &text[..byte];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (
+        before.matches('\n').count(),
+        before[line_start..].chars().count(),
+    )
+}
+
+/// Byte range of the caret's selection, or the empty range at the caret.
+fn selection_range(text: &str, caret: Caret) -> Range<usize> {
+    let (start, end) = caret
+        .selection()
+        .unwrap_or((caret.position, caret.position));
+    byte_position(text, start)..byte_position(text, end)
+}
+
+/// Index of the last line the cursor can reach, including the empty line
+/// after a trailing newline.
+fn last_line(text: &str) -> usize {
+    let count = text.lines().count();
+    if text.ends_with('\n') {
+        count
+    } else {
+        count.saturating_sub(1)
+    }
+}
+
+/// Number of characters on line `line_idx`, zero past the end of the text.
+fn line_length(text: &str, line_idx: usize) -> usize {
+    text.lines()
+        .nth(line_idx)
+        .map_or(0, |line| line.chars().count())
 }
@@ -0,0 +1,103 @@
+﻿use std::collections::VecDeque;
+
+const MAX_UNDO_STEPS: usize = 1000;
+/// Total size of the undo snapshots kept, so long notes keep fewer steps.
+const MAX_UNDO_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKind {
+    /// Consecutive typing is merged into a single undo step.
+    Typing,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    text: String,
+    cursor: (usize, usize),
+}
+
+/// Undo/redo stacks of whole-text snapshots.
+///
+/// Images are never removed from the editor during a session, so restoring the
+/// text is enough to bring back an image line together with its image.
+#[derive(Default)]
+pub struct UndoHistory {
+    /// Oldest snapshot first, dropped once over the step or size limit.
+    undo: VecDeque<Snapshot>,
+    redo: Vec<Snapshot>,
+    /// Combined text size of the snapshots in `undo`.
+    undo_bytes: usize,
+    last_kind: Option<EditKind>,
+}
+
+impl UndoHistory {
+    /// Records the state before an edit of the given kind is applied.
+    pub fn record(&mut self, text: &str, cursor: (usize, usize), kind: EditKind) {
+        self.redo.clear();
+        if kind == EditKind::Typing && self.last_kind == Some(EditKind::Typing) {
+            return;
+        }
+
+        self.push_undo(Snapshot {
+            text: text.to_string(),
+            cursor,
+        });
+        self.last_kind = Some(kind);
+    }
+
+    fn push_undo(&mut self, snapshot: Snapshot) {
+        self.undo_bytes += snapshot.text.len();
+        self.undo.push_back(snapshot);
+        // The newest step is always kept, however large
+        while self.undo.len() > 1
+            && (self.undo.len() > MAX_UNDO_STEPS || self.undo_bytes > MAX_UNDO_BYTES)
+        {
+            if let Some(oldest) = self.undo.pop_front() {
+                self.undo_bytes -= oldest.text.len();
+            }
+        }
+    }
+
+    /// Ends the current typing group, e.g. when the cursor is moved.
+    pub fn break_group(&mut self) {
+        self.last_kind = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.undo_bytes = 0;
+        self.last_kind = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Restores the previous snapshot into `text`, returning its cursor position.
+    pub fn undo(&mut self, text: &mut String, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        let snapshot = self.undo.pop_back()?;
+        self.undo_bytes -= snapshot.text.len();
+        self.redo.push(Snapshot {
+            text: std::mem::replace(text, snapshot.text),
+            cursor,
+        });
+        self.last_kind = None;
+        Some(snapshot.cursor)
+    }
+
+    pub fn redo(&mut self, text: &mut String, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        let snapshot = self.redo.pop()?;
+        self.push_undo(Snapshot {
+            text: std::mem::replace(text, snapshot.text),
+            cursor,
+        });
+        self.last_kind = None;
+        Some(snapshot.cursor)
+    }
+}
@@ -0,0 +1,372 @@
+﻿use crate::document::{Annotation, MarkdownImage, content_hash, is_svg};
+use egui::load::SizeHint;
+use egui::{ColorImage, Context, TextureHandle, TextureId, TextureOptions, Vec2};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, Frame, ImageError, ImageFormat, ImageResult, RgbaImage};
+use std::io::Cursor;
+use std::sync::Arc;
+
+// Browsers play GIF frame delays of 10ms or less at 100ms, so do the same
+const MAX_CLAMPED_DELAY: f32 = 0.01;
+const DEFAULT_FRAME_DELAY: f32 = 0.1;
+
+/// Pixels decoded from an image file, ready to be uploaded as textures.
+///
+/// Decoding is pure CPU work and can happen off the UI thread.
+pub struct DecodedImage {
+    frames: Vec<(ColorImage, f32)>,
+    size: Vec2,
+    svg_scale: Option<f32>,
+}
+
+impl DecodedImage {
+    /// Decodes encoded image bytes, keeping every frame of animated GIFs and APNGs.
+    ///
+    /// SVGs are rasterized at `pixels_per_point` and sized by their intrinsic size in points.
+    pub fn decode(bytes: &[u8], pixels_per_point: f32) -> ImageResult<Self> {
+        if is_svg(bytes) {
+            let intrinsic = rasterize_svg(bytes, 1.0)?;
+            let image = rasterize_svg(bytes, pixels_per_point)?;
+
+            return Ok(Self {
+                size: Vec2::new(intrinsic.size[0] as f32, intrinsic.size[1] as f32),
+                frames: vec![(image, DEFAULT_FRAME_DELAY)],
+                svg_scale: Some(pixels_per_point),
+            });
+        }
+
+        let frames = match image::guess_format(bytes)? {
+            ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?
+                .into_frames()
+                .collect_frames()?,
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(bytes))?;
+                if decoder.is_apng()? {
+                    decoder.apng()?.into_frames().collect_frames()?
+                } else {
+                    Vec::new()
+                }
+            }
+            _ => Vec::new(),
+        };
+        let frames = if frames.is_empty() {
+            vec![Frame::new(image::load_from_memory(bytes)?.to_rgba8())]
+        } else {
+            frames
+        };
+
+        let size = frames
+            .first()
+            .map(|frame| {
+                let buffer = frame.buffer();
+                Vec2::new(buffer.width() as f32, buffer.height() as f32)
+            })
+            .unwrap_or(Vec2::ZERO);
+
+        let frames = frames
+            .into_iter()
+            .map(|frame| {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let delay = numer as f32 / denom.max(1) as f32 / 1000.0;
+                let delay = if delay <= MAX_CLAMPED_DELAY {
+                    DEFAULT_FRAME_DELAY
+                } else {
+                    delay
+                };
+
+                let buffer = frame.into_buffer();
+                let image = ColorImage::from_rgba_unmultiplied(
+                    [buffer.width() as usize, buffer.height() as usize],
+                    buffer.as_raw(),
+                );
+                (image, delay)
+            })
+            .collect();
+
+        Ok(Self {
+            frames,
+            size,
+            svg_scale: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadState {
+    Unloaded,
+    Loading,
+    Loaded,
+    Failed,
+}
+
+/// A decoded frame of an animated image, uploaded to the image's texture when
+/// it is shown.
+#[derive(Clone)]
+pub struct ImageFrame {
+    image: Arc<ColorImage>,
+    delay: f32,
+}
+
+/// An image referenced by the document.
+///
+/// The encoded source bytes are always kept; textures only exist while the image
+/// is near the viewport and can be evicted again to stay within the memory budget.
+pub struct EditorImage {
+    name: String,
+    texture: Option<TextureHandle>,
+    /// Every frame of an animated image, empty for still images.
+    frames: Vec<ImageFrame>,
+    /// Index of the frame currently in `texture`.
+    shown_frame: usize,
+    size: Vec2,
+    source: Arc<[u8]>,
+    hash: String,
+    annotations: Vec<Annotation>,
+    markdown: Option<MarkdownImage>,
+    state: LoadState,
+    elapsed: f32,
+    /// Pixels per point the SVG texture was last rasterized at, `None` for raster images.
+    svg_scale: Option<f32>,
+}
+
+impl EditorImage {
+    /// An image whose textures are created later, shown as a `size` placeholder until then.
+    pub fn unloaded(name: String, source: Arc<[u8]>, size: Vec2) -> Self {
+        Self {
+            name,
+            texture: None,
+            frames: Vec::new(),
+            shown_frame: 0,
+            size,
+            hash: content_hash(&source),
+            annotations: Vec::new(),
+            markdown: None,
+            source,
+            state: LoadState::Unloaded,
+            elapsed: 0.0,
+            svg_scale: None,
+        }
+    }
+
+    /// A copy sharing this image's source bytes and texture under a new name.
+    ///
+    /// Animated images change their texture as they play, so a copy of one is
+    /// decoded again into a texture of its own.
+    pub fn duplicate(&self, name: String) -> Self {
+        let mut copy = Self::unloaded(name, self.source.clone(), self.size);
+        copy.annotations = self.annotations.clone();
+        copy.markdown = self.markdown.clone();
+        if !self.is_animated() {
+            copy.texture = self.texture.clone();
+            copy.state = self.state;
+            copy.svg_scale = self.svg_scale;
+        }
+        copy
+    }
+
+    /// Decodes and uploads an image immediately.
+    pub fn decode(ctx: &Context, name: String, bytes: Arc<[u8]>) -> ImageResult<Self> {
+        let decoded = DecodedImage::decode(&bytes, ctx.pixels_per_point())?;
+        let mut image = Self::unloaded(name, bytes, decoded.size);
+        image.upload(ctx, decoded);
+        Ok(image)
+    }
+
+    /// Creates the texture, showing the first frame. The other frames of an
+    /// animation are uploaded to it one at a time as they play.
+    pub fn upload(&mut self, ctx: &Context, decoded: DecodedImage) {
+        let options = if decoded.svg_scale.is_some() {
+            TextureOptions::LINEAR
+        } else {
+            TextureOptions::default()
+        };
+
+        let mut frames: Vec<ImageFrame> = decoded
+            .frames
+            .into_iter()
+            .map(|(image, delay)| ImageFrame {
+                image: Arc::new(image),
+                delay,
+            })
+            .collect();
+        let Some(first) = frames.first() else {
+            self.state = LoadState::Failed;
+            return;
+        };
+        self.texture = Some(ctx.load_texture(&self.name, first.image.clone(), options));
+        if frames.len() == 1 {
+            frames.clear();
+        }
+        self.frames = frames;
+        self.shown_frame = 0;
+        self.elapsed = 0.0;
+        self.size = decoded.size;
+        self.svg_scale = decoded.svg_scale;
+        self.state = LoadState::Loaded;
+    }
+
+    /// Drops the texture and frames, keeping the source so the image can be
+    /// decoded again.
+    pub fn evict(&mut self) {
+        self.texture = None;
+        self.frames.clear();
+        self.svg_scale = None;
+        self.state = LoadState::Unloaded;
+    }
+
+    pub fn state(&self) -> LoadState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: LoadState) {
+        self.state = state;
+    }
+
+    /// The uploaded texture, which duplicated images share.
+    pub fn texture_id(&self) -> Option<TextureId> {
+        self.texture.as_ref().map(TextureHandle::id)
+    }
+
+    /// Approximate memory held by this image's texture and decoded frames.
+    pub fn texture_bytes(&self) -> usize {
+        let frames: usize = self
+            .frames
+            .iter()
+            .map(|frame| frame.image.pixels.len() * 4)
+            .sum();
+        frames
+            + self
+                .texture
+                .as_ref()
+                .map_or(0, |texture| texture.byte_size())
+    }
+
+    /// Re-rasterizes SVG images whenever the display scale (DPI or zoom) changes.
+    pub fn update_resolution(&mut self, pixels_per_point: f32) {
+        let Some(scale) = self.svg_scale else {
+            return;
+        };
+        if (scale - pixels_per_point).abs() < f32::EPSILON {
+            return;
+        }
+
+        let Some(texture) = &mut self.texture else {
+            return;
+        };
+        match rasterize_svg(&self.source, pixels_per_point) {
+            Ok(image) => texture.set(image, TextureOptions::LINEAR),
+            Err(err) => log::warn!("Failed to rasterize SVG: {err}"),
+        }
+        self.svg_scale = Some(pixels_per_point);
+    }
+
+    pub fn size(&self) -> Vec2 {
+        self.size
+    }
+
+    /// The original encoded bytes this image was decoded from.
+    pub fn source(&self) -> &Arc<[u8]> {
+        &self.source
+    }
+
+    pub fn annotations(&self) -> &[Annotation] {
+        &self.annotations
+    }
+
+    pub fn set_annotations(&mut self, annotations: Vec<Annotation>) {
+        self.annotations = annotations;
+    }
+
+    /// The Markdown syntax this image was opened from, if any.
+    pub fn markdown(&self) -> Option<&MarkdownImage> {
+        self.markdown.as_ref()
+    }
+
+    pub fn set_markdown(&mut self, markdown: Option<MarkdownImage>) {
+        self.markdown = markdown;
+    }
+
+    /// Content hash of the source bytes, shared by images showing identical files.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    /// Texture showing the current frame, if the image is loaded.
+    pub fn texture(&self) -> Option<&TextureHandle> {
+        self.texture.as_ref()
+    }
+
+    /// Advances the animation clock, returning the time until the next frame change.
+    pub fn advance(&mut self, dt: f32) -> Option<f32> {
+        if !self.is_animated() {
+            return None;
+        }
+
+        let total: f32 = self.frames.iter().map(|frame| frame.delay).sum();
+        self.elapsed = (self.elapsed + dt) % total;
+
+        let current = self.current_frame();
+        if current != self.shown_frame
+            && let Some(texture) = &mut self.texture
+        {
+            texture.set(
+                self.frames[current].image.clone(),
+                TextureOptions::default(),
+            );
+            self.shown_frame = current;
+        }
+
+        let mut frame_end = 0.0;
+        for frame in &self.frames {
+            frame_end += frame.delay;
+            if self.elapsed < frame_end {
+                return Some(frame_end - self.elapsed);
+            }
+        }
+        Some(DEFAULT_FRAME_DELAY)
+    }
+
+    fn current_frame(&self) -> usize {
+        let mut frame_end = 0.0;
+        for (index, frame) in self.frames.iter().enumerate() {
+            frame_end += frame.delay;
+            if self.elapsed < frame_end {
+                return index;
+            }
+        }
+        0
+    }
+}
+
+/// Decodes the first frame of an image into editable pixels.
+pub fn decode_still(bytes: &[u8]) -> ImageResult<RgbaImage> {
+    if !is_svg(bytes) {
+        return Ok(image::load_from_memory(bytes)?.to_rgba8());
+    }
+
+    let image = rasterize_svg(bytes, 1.0)?;
+    let pixels = image
+        .pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_srgba_unmultiplied())
+        .collect();
+    RgbaImage::from_raw(image.size[0] as u32, image.size[1] as u32, pixels)
+        .ok_or_else(|| svg_error("Rasterized SVG has an invalid size".to_string()))
+}
+
+fn rasterize_svg(bytes: &[u8], scale: f32) -> ImageResult<ColorImage> {
+    egui_extras::image::load_svg_bytes_with_size(bytes, Some(SizeHint::Scale(scale.into())))
+        .map_err(svg_error)
+}
+
+fn svg_error(message: String) -> ImageError {
+    ImageError::Decoding(image::error::DecodingError::new(
+        image::error::ImageFormatHint::Name("SVG".to_string()),
+        message,
+    ))
+}
@@ -0,0 +1,286 @@
+﻿use super::image::decode_still;
+use egui::{Color32, ColorImage, Context, Pos2, Rect, Sense, Stroke, TextureHandle, Vec2};
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage, imageops};
+use std::io::Cursor;
+
+const PREVIEW_MAX_SIZE: Vec2 = Vec2::new(560.0, 420.0);
+
+pub enum ImageEditorResult {
+    /// PNG bytes of the edited image, and where its old pixels ended up.
+    Apply {
+        bytes: Vec<u8>,
+        map: PixelMap,
+    },
+    Cancel,
+}
+
+/// Where a point of the image as opened ends up in the edited image, an
+/// affine map of pixel coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelMap {
+    x: [f32; 3],
+    y: [f32; 3],
+}
+
+impl PixelMap {
+    const IDENTITY: Self = Self {
+        x: [1.0, 0.0, 0.0],
+        y: [0.0, 1.0, 0.0],
+    };
+
+    pub fn apply(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+        [
+            self.x[0] * x + self.x[1] * y + self.x[2],
+            self.y[0] * x + self.y[1] * y + self.y[2],
+        ]
+    }
+
+    /// This map followed by `next`.
+    fn then(self, next: Self) -> Self {
+        let row = |r: [f32; 3]| {
+            [
+                r[0] * self.x[0] + r[1] * self.y[0],
+                r[0] * self.x[1] + r[1] * self.y[1],
+                r[0] * self.x[2] + r[1] * self.y[2] + r[2],
+            ]
+        };
+        Self {
+            x: row(next.x),
+            y: row(next.y),
+        }
+    }
+}
+
+/// Overlay window for cropping, rotating and flipping one image of the document.
+///
+/// Edits are made on a still copy of the image, so animated images are
+/// flattened to their first frame and SVGs to their intrinsic size.
+pub struct ImageEditor {
+    image_id: usize,
+    image: RgbaImage,
+    /// The crops, turns and flips made so far.
+    map: PixelMap,
+    preview: TextureHandle,
+    /// Area to keep, in normalized `0..1` image coordinates.
+    crop: Option<Rect>,
+    drag_origin: Option<Pos2>,
+}
+
+impl ImageEditor {
+    pub fn open(ctx: &Context, image_id: usize, source: &[u8]) -> ImageResult<Self> {
+        let image = decode_still(source)?;
+        let preview = ctx.load_texture(
+            "image_editor_preview",
+            to_color_image(&image),
+            Default::default(),
+        );
+
+        Ok(Self {
+            image_id,
+            image,
+            map: PixelMap::IDENTITY,
+            preview,
+            crop: None,
+            drag_origin: None,
+        })
+    }
+
+    /// Id of the image being edited.
+    pub fn image_id(&self) -> usize {
+        self.image_id
+    }
+
+    pub fn show(&mut self, ctx: &Context) -> Option<ImageEditorResult> {
+        let mut result = None;
+        let mut open = true;
+
+        egui::Window::new("Edit Image")
+            .collapsible(false)
+            .resizable(false)
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Rotate Left").clicked() {
+                        self.transform(imageops::rotate270, |width, _| PixelMap {
+                            x: [0.0, 1.0, 0.0],
+                            y: [-1.0, 0.0, width],
+                        });
+                    }
+                    if ui.button("Rotate Right").clicked() {
+                        self.transform(imageops::rotate90, |_, height| PixelMap {
+                            x: [0.0, -1.0, height],
+                            y: [1.0, 0.0, 0.0],
+                        });
+                    }
+                    if ui.button("Flip Horizontal").clicked() {
+                        self.transform(imageops::flip_horizontal, |width, _| PixelMap {
+                            x: [-1.0, 0.0, width],
+                            y: [0.0, 1.0, 0.0],
+                        });
+                    }
+                    if ui.button("Flip Vertical").clicked() {
+                        self.transform(imageops::flip_vertical, |_, height| PixelMap {
+                            x: [1.0, 0.0, 0.0],
+                            y: [0.0, -1.0, height],
+                        });
+                    }
+                    if ui
+                        .add_enabled(self.crop.is_some(), egui::Button::new("Clear Crop"))
+                        .clicked()
+                    {
+                        self.crop = None;
+                    }
+                });
+
+                ui.label("Drag on the image to select the area to keep.");
+                self.show_preview(ui);
+
+                ui.horizontal(|ui| {
+                    if ui.button("Apply").clicked() {
+                        result = Some(match self.encode() {
+                            Ok(bytes) => ImageEditorResult::Apply {
+                                bytes,
+                                map: self.map,
+                            },
+                            Err(err) => {
+                                log::error!("Failed to encode edited image: {err}");
+                                ImageEditorResult::Cancel
+                            }
+                        });
+                    }
+                    if ui.button("Cancel").clicked() {
+                        result = Some(ImageEditorResult::Cancel);
+                    }
+                });
+            });
+
+        if !open {
+            result = Some(ImageEditorResult::Cancel);
+        }
+        result
+    }
+
+    fn show_preview(&mut self, ui: &mut egui::Ui) {
+        let image_size = Vec2::new(self.image.width() as f32, self.image.height() as f32);
+        let scale = (PREVIEW_MAX_SIZE.x / image_size.x)
+            .min(PREVIEW_MAX_SIZE.y / image_size.y)
+            .min(1.0);
+
+        let (rect, response) = ui.allocate_exact_size(image_size * scale, Sense::drag());
+        ui.painter().image(
+            self.preview.id(),
+            rect,
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+            Color32::WHITE,
+        );
+
+        let to_normalized = |pos: Pos2| {
+            let normalized = (pos - rect.min) / rect.size();
+            Pos2::new(normalized.x.clamp(0.0, 1.0), normalized.y.clamp(0.0, 1.0))
+        };
+
+        if response.drag_started() {
+            self.drag_origin = response.interact_pointer_pos().map(to_normalized);
+        }
+        if response.dragged()
+            && let (Some(origin), Some(pos)) = (self.drag_origin, response.interact_pointer_pos())
+        {
+            self.crop = Some(Rect::from_two_pos(origin, to_normalized(pos)));
+        }
+        if response.drag_stopped() {
+            self.drag_origin = None;
+        }
+
+        if let Some(crop) = self.crop {
+            let crop_rect = Rect::from_min_max(
+                rect.min + crop.min.to_vec2() * rect.size(),
+                rect.min + crop.max.to_vec2() * rect.size(),
+            );
+
+            // Dim everything outside the crop area
+            let shade = Color32::from_black_alpha(140);
+            let painter = ui.painter();
+            painter.rect_filled(
+                Rect::from_min_max(rect.min, Pos2::new(rect.max.x, crop_rect.min.y)),
+                0.0,
+                shade,
+            );
+            painter.rect_filled(
+                Rect::from_min_max(Pos2::new(rect.min.x, crop_rect.max.y), rect.max),
+                0.0,
+                shade,
+            );
+            painter.rect_filled(
+                Rect::from_min_max(
+                    Pos2::new(rect.min.x, crop_rect.min.y),
+                    Pos2::new(crop_rect.min.x, crop_rect.max.y),
+                ),
+                0.0,
+                shade,
+            );
+            painter.rect_filled(
+                Rect::from_min_max(
+                    Pos2::new(crop_rect.max.x, crop_rect.min.y),
+                    Pos2::new(rect.max.x, crop_rect.max.y),
+                ),
+                0.0,
+                shade,
+            );
+            painter.rect_stroke(
+                crop_rect,
+                0.0,
+                Stroke::new(1.0, Color32::WHITE),
+                egui::StrokeKind::Outside,
+            );
+        }
+    }
+
+    /// Applies `operation` to the working image, `map` giving where it moves
+    /// the pixels of an image of the given width and height.
+    fn transform(&mut self, operation: fn(&RgbaImage) -> RgbaImage, map: fn(f32, f32) -> PixelMap) {
+        self.apply_crop();
+        let step = map(self.image.width() as f32, self.image.height() as f32);
+        self.map = self.map.then(step);
+        self.image = operation(&self.image);
+        self.preview
+            .set(to_color_image(&self.image), Default::default());
+    }
+
+    /// Crops the working image to the selected area, if any.
+    fn apply_crop(&mut self) {
+        let Some(crop) = self.crop.take() else {
+            return;
+        };
+
+        let width = self.image.width() as f32;
+        let height = self.image.height() as f32;
+        let x = (crop.min.x * width).round() as u32;
+        let y = (crop.min.y * height).round() as u32;
+        let crop_width = ((crop.max.x * width).round() as u32).saturating_sub(x);
+        let crop_height = ((crop.max.y * height).round() as u32).saturating_sub(y);
+
+        if crop_width > 0 && crop_height > 0 {
+            self.image = imageops::crop_imm(&self.image, x, y, crop_width, crop_height).to_image();
+            self.map = self.map.then(PixelMap {
+                x: [1.0, 0.0, -(x as f32)],
+                y: [0.0, 1.0, -(y as f32)],
+            });
+        }
+    }
+
+    fn encode(&mut self) -> ImageResult<Vec<u8>> {
+        self.apply_crop();
+
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgba8(self.image.clone())
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+        Ok(bytes)
+    }
+}
+
+fn to_color_image(image: &RgbaImage) -> ColorImage {
+    ColorImage::from_rgba_unmultiplied(
+        [image.width() as usize, image.height() as usize],
+        image.as_raw(),
+    )
+}
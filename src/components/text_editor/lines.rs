@@ -0,0 +1,123 @@
+﻿use std::cmp::Ordering;
+
+/// Commands working on whole lines: the cursor line, or every line the
+/// selection touches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineCommand {
+    Duplicate,
+    MoveUp,
+    MoveDown,
+    Delete,
+    /// Joins the lines with single spaces, or the cursor line with the next.
+    Join,
+    Sort(SortOrder),
+    RemoveDuplicates,
+    Reverse,
+}
+
+impl LineCommand {
+    /// Whether the command only does something on a selection of several lines.
+    pub fn needs_selection(self) -> bool {
+        matches!(self, Self::Sort(_) | Self::RemoveDuplicates | Self::Reverse)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+    /// Runs of digits compare by their value, so "item 9" sorts before "item 10".
+    Natural,
+    CaseInsensitive,
+}
+
+impl SortOrder {
+    pub const ALL: [SortOrder; 4] = [
+        SortOrder::Ascending,
+        SortOrder::Descending,
+        SortOrder::Natural,
+        SortOrder::CaseInsensitive,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortOrder::Ascending => "Ascending",
+            SortOrder::Descending => "Descending",
+            SortOrder::Natural => "Natural",
+            SortOrder::CaseInsensitive => "Case-Insensitive",
+        }
+    }
+}
+
+/// Sorts `lines` in place. Equal lines keep their order.
+pub fn sort_lines(lines: &mut [String], order: SortOrder) {
+    match order {
+        SortOrder::Ascending => lines.sort(),
+        SortOrder::Descending => lines.sort_by(|a, b| b.cmp(a)),
+        SortOrder::Natural => lines.sort_by(|a, b| natural_cmp(a, b)),
+        SortOrder::CaseInsensitive => lines.sort_by_cached_key(|line| line.to_lowercase()),
+    }
+}
+
+/// Compares strings character by character, except that runs of ASCII digits
+/// compare by their numeric value.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Without leading zeros, a longer number is a larger one
+                let ordering = x
+                    .trim_start_matches('0')
+                    .len()
+                    .cmp(&y.trim_start_matches('0').len())
+                    .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0')))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+/// Joins `lines` with single spaces, dropping the indentation of every line
+/// but the first and skipping blank lines.
+pub fn join_lines(lines: &[String]) -> String {
+    let mut joined = lines
+        .first()
+        .map_or(String::new(), |line| line.trim_end().to_string());
+    for line in &lines[1.min(lines.len())..] {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !joined.is_empty() && !joined.ends_with(char::is_whitespace) {
+            joined.push(' ');
+        }
+        joined.push_str(line);
+    }
+    joined
+}
@@ -0,0 +1,98 @@
+﻿use super::image::DecodedImage;
+use egui::Context;
+use image::ImageResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+type LoadResult = (usize, ImageResult<DecodedImage>);
+
+/// Most decode threads a loader runs, so opening a note with dozens of
+/// images does not start dozens of threads.
+const MAX_WORKERS: usize = 4;
+
+struct Job {
+    id: usize,
+    source: Arc<[u8]>,
+    pixels_per_point: f32,
+    ctx: Context,
+}
+
+/// Decodes images on a few background threads and hands the pixels back to
+/// the UI thread.
+///
+/// Dropping the loader discards the results of any decodes still in flight
+/// and the requests not started yet.
+pub struct ImageLoader {
+    jobs: Sender<Job>,
+    receiver: Receiver<LoadResult>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ImageLoader {
+    pub fn new() -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let workers = std::thread::available_parallelism()
+            .map_or(1, |count| count.get())
+            .min(MAX_WORKERS);
+        for _ in 0..workers {
+            let job_receiver = job_receiver.clone();
+            let sender = sender.clone();
+            let cancelled = cancelled.clone();
+            std::thread::spawn(move || {
+                loop {
+                    // The lock is only held while waiting, decodes run in parallel
+                    let job = match job_receiver.lock() {
+                        Ok(jobs) => jobs.recv(),
+                        Err(_) => break,
+                    };
+                    let Ok(job) = job else {
+                        break;
+                    };
+                    if cancelled.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    let result = DecodedImage::decode(&job.source, job.pixels_per_point);
+                    if sender.send((job.id, result)).is_err() {
+                        break;
+                    }
+                    job.ctx.request_repaint();
+                }
+            });
+        }
+
+        Self {
+            jobs,
+            receiver,
+            cancelled,
+        }
+    }
+
+    pub fn request(&self, ctx: &Context, id: usize, source: Arc<[u8]>) {
+        let job = Job {
+            id,
+            source,
+            pixels_per_point: ctx.pixels_per_point(),
+            ctx: ctx.clone(),
+        };
+        if self.jobs.send(job).is_err() {
+            log::warn!("No decode threads are running, image {id} stays unloaded");
+        }
+    }
+
+    /// Decodes that have completed since the last call.
+    pub fn finished(&self) -> impl Iterator<Item = LoadResult> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+impl Drop for ImageLoader {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
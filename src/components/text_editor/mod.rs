@@ -1,5 +1,24 @@
-﻿mod editor;
+﻿mod annotation;
+mod annotation_editor;
+mod bidi;
+mod caret;
+mod convert;
+mod editor;
+mod history;
+mod image;
+mod image_editor;
+mod lines;
+mod loader;
 mod renderer;
+mod sketch;
 mod util;
+mod whitespace;
 
-pub use editor::TextEditor;
\ No newline at end of file
+pub use annotation::flatten;
+pub use caret::CaretShape;
+pub use convert::Conversion;
+pub use editor::TextEditor;
+pub use image::decode_still;
+pub use lines::{LineCommand, SortOrder};
+pub use sketch::paint_sketch;
+pub use util::extract_placeholder_id;
\ No newline at end of file
@@ -1,14 +1,81 @@
-﻿use crate::components::text_editor::editor::{FontMetrics, TextEditorImageMap};
-use crate::components::text_editor::util::{calculate_line_height, extract_image_id};
-use egui::{Color32, FontId, Pos2, Rect, Stroke, Ui, Vec2};
+﻿use crate::components::text_editor::annotation::paint_annotations;
+use crate::components::text_editor::bidi::BidiLine;
+use crate::components::text_editor::caret::{CaretPaint, paint_caret};
+use crate::components::text_editor::editor::{
+    TextEditorImageMap, TextEditorSketchMap, TextSelection,
+};
+use crate::components::text_editor::sketch::paint_sketch;
+use crate::components::text_editor::util::{
+    calculate_line_height, expand_tabs, extract_image_id, extract_sketch_id, slot_x,
+};
+use crate::components::text_editor::whitespace::{
+    LineEnding, WhitespaceLine, line_endings, paint_whitespace,
+};
+use crate::document::LineBreak;
+use egui::{Color32, FontId, Pos2, Rangef, Rect, Stroke, StrokeKind, Ui, Vec2};
+use std::borrow::Cow;
+use std::collections::HashMap;
 
-pub struct TextEditorRenderer;
+pub struct TextEditorRenderer {
+    hovered_image: Option<usize>,
+    image_distances: HashMap<usize, f32>,
+    image_rects: Vec<(usize, Rect)>,
+    sketch_rects: Vec<(usize, Rect)>,
+    cursor_rect: Option<Rect>,
+}
 
 impl TextEditorRenderer {
     pub fn new() -> Self {
-        Self
+        Self {
+            hovered_image: None,
+            image_distances: HashMap::new(),
+            image_rects: Vec::new(),
+            sketch_rects: Vec::new(),
+            cursor_rect: None,
+        }
+    }
+
+    /// The line whose image contains `pos` during the last rendered frame.
+    pub fn image_line_at(&self, pos: Pos2) -> Option<usize> {
+        self.image_rects
+            .iter()
+            .find(|(_, rect)| rect.contains(pos))
+            .map(|(line_idx, _)| *line_idx)
+    }
+
+    /// The sketch line and rect containing `pos` during the last rendered frame.
+    pub fn sketch_at(&self, pos: Pos2) -> Option<(usize, Rect)> {
+        self.sketch_rects
+            .iter()
+            .find(|(_, rect)| rect.contains(pos))
+            .copied()
+    }
+
+    /// Where the sketch on `line_idx` was drawn during the last rendered frame.
+    pub fn sketch_rect(&self, line_idx: usize) -> Option<Rect> {
+        self.sketch_rects
+            .iter()
+            .find(|(line, _)| *line == line_idx)
+            .map(|(_, rect)| *rect)
+    }
+
+    /// Where the primary cursor was drawn during the last rendered frame.
+    pub fn cursor_rect(&self) -> Option<Rect> {
+        self.cursor_rect
+    }
+
+    /// The image under the pointer during the last rendered frame.
+    pub fn hovered_image(&self) -> Option<usize> {
+        self.hovered_image
     }
 
+    /// Vertical distance of each image line from the viewport during the last
+    /// rendered frame, `0.0` for visible images.
+    pub fn image_distances(&self) -> &HashMap<usize, f32> {
+        &self.image_distances
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn render(
         &mut self,
         ui: &mut Ui,
@@ -17,10 +84,23 @@ impl TextEditorRenderer {
         line_numbers_rect: Rect,
         content_rect: Rect,
         images: &TextEditorImageMap,
-        cursor_line: usize,
-        cursor_column: usize,
+        sketches: &TextEditorSketchMap,
+        cursor: (usize, usize),
+        cursors: &[(usize, usize)],
+        preedit: &str,
+        selections: &[TextSelection],
+        tab_width: usize,
+        show_whitespace: bool,
+        line_break: LineBreak,
+        caret: &CaretPaint,
     ) {
-        if text.is_empty() {
+        self.hovered_image = None;
+        self.image_distances.clear();
+        self.image_rects.clear();
+        self.sketch_rects.clear();
+        self.cursor_rect = None;
+
+        if text.is_empty() && preedit.is_empty() {
             self.render_empty_editor(ui, font_id, line_numbers_rect, content_rect);
         } else {
             self.render_text_content(
@@ -30,8 +110,15 @@ impl TextEditorRenderer {
                 line_numbers_rect,
                 content_rect,
                 images,
-                cursor_line,
-                cursor_column,
+                sketches,
+                cursor,
+                cursors,
+                preedit,
+                selections,
+                tab_width,
+                show_whitespace,
+                line_break,
+                caret,
             );
         }
     }
@@ -62,6 +149,7 @@ impl TextEditorRenderer {
         );
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn render_text_content(
         &mut self,
         ui: &mut Ui,
@@ -70,19 +158,47 @@ impl TextEditorRenderer {
         line_numbers_rect: Rect,
         content_rect: Rect,
         images: &TextEditorImageMap,
-        cursor_line: usize,
-        cursor_column: usize,
+        sketches: &TextEditorSketchMap,
+        cursor: (usize, usize),
+        cursors: &[(usize, usize)],
+        preedit: &str,
+        selections: &[TextSelection],
+        tab_width: usize,
+        show_whitespace: bool,
+        line_break: LineBreak,
+        caret: &CaretPaint,
     ) {
         let base_line_height = ui.fonts(|f| f.row_height(font_id));
+        let space_width = ui.fonts(|f| f.glyph_width(font_id, ' '));
+        let highlight_band = |y: f32| {
+            ui.painter().rect_filled(
+                Rect::from_x_y_ranges(
+                    line_numbers_rect.left()..=content_rect.right(),
+                    y..=(y + base_line_height),
+                ),
+                0.0,
+                ui.visuals().faint_bg_color,
+            );
+        };
         let mut current_y = line_numbers_rect.top();
+        let endings = if show_whitespace {
+            line_endings(text, line_break)
+        } else {
+            Vec::new()
+        };
 
         for (line_idx, line) in text.lines().enumerate() {
-            let mut line_height = calculate_line_height(line, base_line_height, images);
+            let mut line_height = calculate_line_height(line, base_line_height, images, sketches);
+            let current_line = line_idx == cursor.0 && caret.highlight_line;
+            if current_line {
+                highlight_band(current_y);
+            }
 
             // If there's an image, draw it and change the line_height
-            if let Some((texture, image_size)) =
-                extract_image_id(line).and_then(|id| images.get(&id))
+            if let Some((image_id, image)) =
+                extract_image_id(line).and_then(|id| images.get(&id).map(|image| (id, image)))
             {
+                let image_size = image.size();
                 line_height = base_line_height
                     + crate::components::text_editor::editor::IMAGE_PADDING
                     + image_size.y
@@ -97,14 +213,66 @@ impl TextEditorRenderer {
                         content_rect.left() + crate::components::text_editor::editor::IMAGE_PADDING,
                         image_y,
                     ),
-                    *image_size,
+                    image_size,
                 );
-                ui.painter().image(
-                    texture.id(),
-                    image_rect,
-                    Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
-                    Color32::WHITE,
+                let viewport = content_rect.intersect(ui.clip_rect());
+                let distance = (viewport.top() - image_rect.bottom())
+                    .max(image_rect.top() - viewport.bottom())
+                    .max(0.0);
+                self.image_distances.insert(image_id, distance);
+                self.image_rects.push((line_idx, image_rect));
+
+                if image.is_animated() && ui.rect_contains_pointer(image_rect) {
+                    self.hovered_image = Some(image_id);
+                }
+
+                if let Some(texture) = image.texture() {
+                    ui.painter().image(
+                        texture.id(),
+                        image_rect,
+                        Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+                        Color32::WHITE,
+                    );
+                } else {
+                    // Placeholder at the final size until the image has been decoded
+                    ui.painter()
+                        .rect_filled(image_rect, 2.0, ui.visuals().faint_bg_color);
+                    ui.painter().rect_stroke(
+                        image_rect,
+                        2.0,
+                        ui.visuals().widgets.noninteractive.bg_stroke,
+                        StrokeKind::Inside,
+                    );
+                }
+
+                if !image.annotations().is_empty() && image_size.x > 0.0 {
+                    paint_annotations(
+                        ui.painter(),
+                        image_rect,
+                        image_rect.width() / image_size.x,
+                        image.annotations(),
+                    );
+                }
+            }
+
+            if let Some(sketch) = extract_sketch_id(line).and_then(|id| sketches.get(&id)) {
+                let sketch_rect = Rect::from_min_size(
+                    Pos2::new(
+                        content_rect.left() + crate::components::text_editor::editor::IMAGE_PADDING,
+                        current_y
+                            + base_line_height
+                            + crate::components::text_editor::editor::IMAGE_PADDING,
+                    ),
+                    Vec2::new(sketch.width, sketch.height),
                 );
+                self.sketch_rects.push((line_idx, sketch_rect));
+
+                let border = if line_idx == cursor.0 {
+                    ui.visuals().selection.stroke
+                } else {
+                    ui.visuals().widgets.noninteractive.bg_stroke
+                };
+                paint_sketch(ui.painter(), sketch_rect, sketch, border);
             }
 
             // Draw line number (move this before the text drawing when no image)
@@ -117,48 +285,204 @@ impl TextEditorRenderer {
                 egui::Align2::RIGHT_TOP,
                 &line_number,
                 font_id.clone(),
-                ui.visuals().weak_text_color(),
+                if current_line {
+                    ui.visuals().text_color()
+                } else {
+                    ui.visuals().weak_text_color()
+                },
             );
 
+            // IME composition is shown inline at the primary cursor until committed
+            let preedit_columns = preedit.chars().count();
+            let composing = line_idx == cursor.0 && preedit_columns > 0;
+            let line: Cow<str> = if composing {
+                let byte = line
+                    .char_indices()
+                    .nth(cursor.1)
+                    .map_or(line.len(), |(idx, _)| idx);
+                Cow::Owned(format!("{}{preedit}{}", &line[..byte], &line[byte..]))
+            } else {
+                Cow::Borrowed(line)
+            };
+            let shown_column = |column: usize| {
+                if composing && column >= cursor.1 {
+                    column + preedit_columns
+                } else {
+                    column
+                }
+            };
+
+            // Right-to-left runs are drawn reordered, so a range of columns
+            // can cover several separate spans of the line
+            let bidi = BidiLine::new(&line);
+            let visual: Cow<str> = match &bidi {
+                Some(bidi) => Cow::Owned(bidi.visual_text(&line)),
+                None => Cow::Borrowed(&line),
+            };
+            let caret_x = |column: usize| {
+                let slot = bidi.as_ref().map_or(column, |bidi| bidi.slot(column));
+                slot_x(ui, font_id, &visual, slot, tab_width)
+            };
+            let spans = |columns: std::ops::Range<usize>| {
+                let runs = match &bidi {
+                    Some(bidi) => bidi.visual_runs(columns),
+                    None => vec![columns],
+                };
+                runs.into_iter()
+                    .map(|run| {
+                        (
+                            slot_x(ui, font_id, &visual, run.start, tab_width),
+                            slot_x(ui, font_id, &visual, run.end, tab_width),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            for &(start, end) in selections
+                .iter()
+                .filter(|(start, end)| (start.0..=end.0).contains(&line_idx))
+            {
+                let from = if line_idx == start.0 {
+                    shown_column(start.1)
+                } else {
+                    0
+                };
+                let to = if line_idx == end.0 {
+                    shown_column(end.1)
+                } else {
+                    line.chars().count()
+                };
+                let mut spans = spans(from..to);
+                // A selected line break shows as a space after the line
+                if line_idx != end.0 {
+                    let line_end = slot_x(ui, font_id, &visual, visual.chars().count(), tab_width);
+                    spans.push((
+                        line_end,
+                        line_end + ui.fonts(|f| f.glyph_width(font_id, ' ')),
+                    ));
+                }
+                for (left, right) in spans {
+                    ui.painter().rect_filled(
+                        Rect::from_min_max(
+                            Pos2::new(content_rect.left() + left, current_y),
+                            Pos2::new(content_rect.left() + right, current_y + base_line_height),
+                        ),
+                        0.0,
+                        ui.visuals().selection.bg_fill,
+                    );
+                }
+            }
+
             // Draw line text
             ui.painter().text(
                 Pos2::new(content_rect.left(), current_y),
                 egui::Align2::LEFT_TOP,
-                line,
+                expand_tabs(&visual, tab_width),
                 font_id.clone(),
                 ui.visuals().text_color(),
             );
 
-            // Draw cursor if this is the cursor line
-            if line_idx == cursor_line {
-                let chars_to_cursor = cursor_column.min(line.chars().count());
-                let text_before_cursor = line.chars().take(chars_to_cursor).collect::<String>();
+            if show_whitespace {
+                let logical = |position: usize| {
+                    bidi.as_ref()
+                        .map_or(position, |bidi| bidi.logical(position))
+                };
+                paint_whitespace(
+                    ui,
+                    font_id,
+                    Pos2::new(content_rect.left(), current_y),
+                    base_line_height,
+                    &WhitespaceLine {
+                        visual: &visual,
+                        logical: &logical,
+                        trailing_from: line.trim_end().chars().count(),
+                        first: line_idx == 0,
+                        ending: endings.get(line_idx).copied().unwrap_or(LineEnding::None),
+                    },
+                    tab_width,
+                );
+            }
 
-                let x_offset = ui.fonts(|f| {
-                    f.layout_no_wrap(text_before_cursor, font_id.clone(), Color32::WHITE)
-                        .size()
-                        .x
-                });
+            if composing {
+                for (left, right) in spans(cursor.1..cursor.1 + preedit_columns) {
+                    ui.painter().hline(
+                        (content_rect.left() + left)..=(content_rect.left() + right),
+                        current_y + base_line_height - 1.0,
+                        Stroke::new(1.0, ui.visuals().text_color()),
+                    );
+                }
+            }
 
-                let cursor_pos = Pos2::new(content_rect.left() + x_offset, current_y);
+            // Draw the cursors on this line
+            for &(_, cursor_column) in cursors.iter().filter(|(line, _)| *line == line_idx) {
+                let column = shown_column(cursor_column);
+                let x = caret_x(column);
+                // The character after the caret, which may be drawn to its left
+                let mut cell = if column < line.chars().count() {
+                    let next = caret_x(column + 1);
+                    Rangef::new(x.min(next), x.max(next))
+                } else {
+                    Rangef::new(x, x + space_width)
+                };
+                if cell.span() < 1.0 {
+                    cell = Rangef::new(x, x + space_width);
+                }
 
-                ui.painter().vline(
-                    cursor_pos.x,
-                    cursor_pos.y..=(cursor_pos.y + base_line_height),
-                    Stroke::new(1.0, ui.visuals().text_color()),
+                let caret_rect = paint_caret(
+                    ui.painter(),
+                    caret,
+                    content_rect.left() + x,
+                    Rangef::new(
+                        content_rect.left() + cell.min,
+                        content_rect.left() + cell.max,
+                    ),
+                    Rangef::new(current_y, current_y + base_line_height),
+                    ui.visuals().text_color(),
                 );
+                if (line_idx, cursor_column) == cursor {
+                    self.cursor_rect = Some(caret_rect);
+                }
             }
 
             current_y += line_height;
         }
 
         // Draw cursor if we're on a line that doesn't exist yet (trailing newline)
-        if cursor_line >= text.lines().count() {
-            ui.painter().vline(
-                content_rect.left(),
-                current_y..=(current_y + base_line_height),
-                Stroke::new(1.0, ui.visuals().text_color()),
+        if cursors
+            .iter()
+            .any(|(line, _)| *line >= text.lines().count())
+        {
+            if cursor.0 >= text.lines().count() && caret.highlight_line {
+                highlight_band(current_y);
+            }
+            let mut cursor_x = content_rect.left();
+            if cursor.0 >= text.lines().count() && !preedit.is_empty() {
+                let preedit_rect = ui.painter().text(
+                    Pos2::new(content_rect.left(), current_y),
+                    egui::Align2::LEFT_TOP,
+                    expand_tabs(preedit, tab_width),
+                    font_id.clone(),
+                    ui.visuals().text_color(),
+                );
+                ui.painter().hline(
+                    preedit_rect.x_range(),
+                    current_y + base_line_height - 1.0,
+                    Stroke::new(1.0, ui.visuals().text_color()),
+                );
+                cursor_x = preedit_rect.right();
+            }
+
+            let caret_rect = paint_caret(
+                ui.painter(),
+                caret,
+                cursor_x,
+                Rangef::new(cursor_x, cursor_x + space_width),
+                Rangef::new(current_y, current_y + base_line_height),
+                ui.visuals().text_color(),
             );
+            if cursor.0 >= text.lines().count() {
+                self.cursor_rect = Some(caret_rect);
+            }
         }
 
         // Handle trailing newline at end of text
@@ -170,9 +494,13 @@ impl TextEditorRenderer {
                     current_y,
                 ),
                 egui::Align2::RIGHT_TOP,
-                &format!("{}", extra_line + 1),
+                format!("{}", extra_line + 1),
                 font_id.clone(),
-                ui.visuals().weak_text_color(),
+                if cursor.0 == extra_line && caret.highlight_line {
+                    ui.visuals().text_color()
+                } else {
+                    ui.visuals().weak_text_color()
+                },
             );
         }
     }
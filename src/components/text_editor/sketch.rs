@@ -0,0 +1,132 @@
+﻿use crate::document::{Sketch, SketchStroke};
+use egui::{Color32, Context, Painter, Pos2, Rect, Shape, Stroke, StrokeKind, Vec2};
+
+pub const SKETCH_SIZE: Vec2 = Vec2::new(480.0, 240.0);
+const ERASER_RADIUS: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SketchTool {
+    Pen,
+    Eraser,
+}
+
+/// Drawing settings shared by every sketch in the document.
+pub struct SketchPen {
+    pub tool: SketchTool,
+    pub color: Color32,
+    pub width: f32,
+}
+
+impl Default for SketchPen {
+    fn default() -> Self {
+        Self {
+            tool: SketchTool::Pen,
+            color: Color32::BLACK,
+            width: 2.0,
+        }
+    }
+}
+
+impl SketchPen {
+    /// A new stroke starting at `point` with the current color and width.
+    pub fn stroke(&self, point: [f32; 2]) -> SketchStroke {
+        SketchStroke {
+            points: vec![point],
+            color: self.color.to_array(),
+            width: self.width,
+        }
+    }
+
+    /// Tool palette floating above the top-right corner of the sketch in `rect`.
+    pub fn show_toolbar(&mut self, ctx: &Context, rect: Rect) {
+        egui::Area::new(egui::Id::new("sketch_toolbar"))
+            .order(egui::Order::Foreground)
+            .pivot(egui::Align2::RIGHT_BOTTOM)
+            .fixed_pos(rect.right_top())
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.selectable_value(&mut self.tool, SketchTool::Pen, "Pen");
+                        ui.selectable_value(&mut self.tool, SketchTool::Eraser, "Eraser");
+                        ui.separator();
+                        egui::color_picker::color_edit_button_srgba(
+                            ui,
+                            &mut self.color,
+                            egui::color_picker::Alpha::Opaque,
+                        );
+                        ui.add(egui::Slider::new(&mut self.width, 1.0..=16.0).text("Width"));
+                    });
+                });
+            });
+    }
+}
+
+/// Paints the sketch paper and its strokes into `rect`, scaled to its width.
+pub fn paint_sketch(painter: &Painter, rect: Rect, sketch: &Sketch, border: Stroke) {
+    painter.rect_filled(rect, 2.0, Color32::WHITE);
+
+    let scale = sketch.scale_to_width(rect.width());
+    let painter = painter.with_clip_rect(painter.clip_rect().intersect(rect));
+    for stroke in &sketch.strokes {
+        let color = Color32::from_rgba_unmultiplied(
+            stroke.color[0],
+            stroke.color[1],
+            stroke.color[2],
+            stroke.color[3],
+        );
+        let points: Vec<Pos2> = stroke
+            .placed_points(rect.min.into(), scale)
+            .map(Pos2::from)
+            .collect();
+        let width = stroke.width * scale;
+
+        // A click without movement leaves a dot
+        if let [point] = points.as_slice() {
+            painter.circle_filled(*point, width / 2.0, color);
+        } else {
+            painter.add(Shape::line(points, Stroke::new(width, color)));
+        }
+    }
+
+    painter.rect_stroke(rect, 2.0, border, StrokeKind::Outside);
+}
+
+/// Whether the eraser at `point` touches any stroke of `sketch`.
+pub fn touches_stroke(sketch: &Sketch, point: [f32; 2]) -> bool {
+    sketch
+        .strokes
+        .iter()
+        .any(|stroke| stroke_hit(stroke, point))
+}
+
+/// Removes every stroke the eraser at `point` touches.
+pub fn erase_at(sketch: &mut Sketch, point: [f32; 2]) {
+    sketch.strokes.retain(|stroke| !stroke_hit(stroke, point));
+}
+
+fn stroke_hit(stroke: &SketchStroke, point: [f32; 2]) -> bool {
+    let point = Pos2::new(point[0], point[1]);
+    let reach = ERASER_RADIUS + stroke.width / 2.0;
+    let points: Vec<Pos2> = stroke
+        .points
+        .iter()
+        .map(|point| Pos2::new(point[0], point[1]))
+        .collect();
+
+    if let [single] = points.as_slice() {
+        return single.distance(point) <= reach;
+    }
+    points
+        .windows(2)
+        .any(|segment| segment_distance(segment[0], segment[1], point) <= reach)
+}
+
+fn segment_distance(a: Pos2, b: Pos2, point: Pos2) -> f32 {
+    let ab = b - a;
+    let length_sq = ab.length_sq();
+    if length_sq == 0.0 {
+        return a.distance(point);
+    }
+    let t = ((point - a).dot(ab) / length_sq).clamp(0.0, 1.0);
+    (a + ab * t).distance(point)
+}
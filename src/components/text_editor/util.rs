@@ -1,27 +1,194 @@
-﻿use crate::components::text_editor::editor::{IMAGE_PADDING, TextEditorImageMap};
+﻿use crate::components::text_editor::bidi::BidiLine;
+use crate::components::text_editor::editor::{
+    IMAGE_PADDING, TextEditorImageMap, TextEditorSketchMap,
+};
+use egui::{Color32, FontId, Ui};
+use std::borrow::Cow;
 
 pub fn extract_image_id(line: &str) -> Option<usize> {
-    if let Some(start) = line.find("[image(") {
-        if let Some(end) = line[start + 7..].find(")]") {
-            let id_str = &line[start + 7..start + 7 + end];
-            return id_str.parse::<usize>().ok();
+    extract_placeholder_id(line, "image")
+}
+
+pub fn extract_sketch_id(line: &str) -> Option<usize> {
+    extract_placeholder_id(line, "sketch")
+}
+
+/// Id of the first `[tag(id)]` placeholder in `line`.
+pub fn extract_placeholder_id(line: &str, tag: &str) -> Option<usize> {
+    let prefix = format!("[{tag}(");
+    if let Some(start) = line.find(&prefix)
+        && let Some(end) = line[start + prefix.len()..].find(")]")
+    {
+        let id_str = &line[start + prefix.len()..start + prefix.len() + end];
+        return id_str.parse::<usize>().ok();
+    }
+    None
+}
+
+/// Byte ranges of the `[image(..)]` and `[sketch(..)]` placeholders in `text`,
+/// in text order.
+pub fn placeholder_ranges(text: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut line_start = 0;
+    for line in text.split('\n') {
+        let mut from = 0;
+        while let Some(start) = ["[image(", "[sketch("]
+            .iter()
+            .filter_map(|prefix| line[from..].find(prefix))
+            .min()
+        {
+            let start = from + start;
+            let Some(end) = line[start..].find(")]") else {
+                break;
+            };
+            let end = start + end + 2;
+            ranges.push(line_start + start..line_start + end);
+            from = end;
         }
+        line_start += line.len() + 1;
+    }
+    ranges
+}
+
+/// Byte range of line `line_idx` in `text`, excluding its line break.
+pub fn line_byte_range(text: &str, line_idx: usize) -> Option<std::ops::Range<usize>> {
+    let mut start = 0;
+    for (idx, line) in text.split('\n').enumerate() {
+        if idx == line_idx {
+            return Some(start..start + line.len());
+        }
+        start += line.len() + 1;
     }
     None
 }
 
+/// Id of the image placeholder on line `line_idx`, if it has one.
+pub fn line_image_id(text: &str, line_idx: usize) -> Option<usize> {
+    text.lines().nth(line_idx).and_then(extract_image_id)
+}
+
+/// Line holding the `[image(id)]` placeholder, if it is still in `text`.
+pub fn image_line(text: &str, id: usize) -> Option<usize> {
+    text.lines()
+        .position(|line| extract_image_id(line) == Some(id))
+}
+
 pub fn calculate_line_height(
     line: &str,
     base_line_height: f32,
     images: &TextEditorImageMap,
+    sketches: &TextEditorSketchMap,
 ) -> f32 {
-    if let Some(image_id) = extract_image_id(line) {
-        if let Some((_, image_size)) = images.get(&image_id) {
-            base_line_height + IMAGE_PADDING + image_size.y + IMAGE_PADDING
+    // Sketches are laid out exactly like images, below the placeholder text
+    let block_height = extract_image_id(line)
+        .and_then(|id| images.get(&id))
+        .map(|image| image.size().y)
+        .or_else(|| {
+            extract_sketch_id(line)
+                .and_then(|id| sketches.get(&id))
+                .map(|sketch| sketch.height)
+        });
+
+    match block_height {
+        Some(height) => base_line_height + IMAGE_PADDING + height + IMAGE_PADDING,
+        None => base_line_height,
+    }
+}
+
+/// Replaces tabs with spaces up to the next multiple of `tab_width` columns.
+pub fn expand_tabs(line: &str, tab_width: usize) -> Cow<'_, str> {
+    if !line.contains('\t') {
+        return Cow::Borrowed(line);
+    }
+
+    let tab_width = tab_width.max(1);
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = tab_width - column % tab_width;
+            expanded.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
         } else {
-            base_line_height
+            expanded.push(c);
+            column += 1;
         }
+    }
+    Cow::Owned(expanded)
+}
+
+/// Horizontal offset of the caret at `column` from the start of `line`, with
+/// tabs drawn `tab_width` columns wide and right-to-left runs reordered.
+pub fn column_x(ui: &Ui, font_id: &FontId, line: &str, column: usize, tab_width: usize) -> f32 {
+    match BidiLine::new(line) {
+        Some(bidi) => slot_x(
+            ui,
+            font_id,
+            &bidi.visual_text(line),
+            bidi.slot(column),
+            tab_width,
+        ),
+        None => slot_x(ui, font_id, line, column, tab_width),
+    }
+}
+
+/// Horizontal offset of the first `slot` characters of `visual`, a line as
+/// drawn.
+pub fn slot_x(ui: &Ui, font_id: &FontId, visual: &str, slot: usize, tab_width: usize) -> f32 {
+    let text_before = visual.chars().take(slot).collect::<String>();
+    let text_before = expand_tabs(&text_before, tab_width).into_owned();
+    ui.fonts(|f| {
+        f.layout_no_wrap(text_before, font_id.clone(), Color32::WHITE)
+            .size()
+            .x
+    })
+}
+
+#[derive(PartialEq, Eq)]
+enum CharClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Whitespace
+    } else if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
     } else {
-        base_line_height
+        CharClass::Punctuation
+    }
+}
+
+/// Column of the start of the word before `column`, skipping whitespace first.
+pub fn previous_word_boundary(line: &str, column: usize) -> usize {
+    let chars: Vec<char> = line.chars().collect();
+    let mut column = column.min(chars.len());
+    while column > 0 && char_class(chars[column - 1]) == CharClass::Whitespace {
+        column -= 1;
+    }
+    if column > 0 {
+        let class = char_class(chars[column - 1]);
+        while column > 0 && char_class(chars[column - 1]) == class {
+            column -= 1;
+        }
+    }
+    column
+}
+
+/// Column of the end of the word after `column`, skipping whitespace first.
+pub fn next_word_boundary(line: &str, column: usize) -> usize {
+    let chars: Vec<char> = line.chars().collect();
+    let mut column = column.min(chars.len());
+    while column < chars.len() && char_class(chars[column]) == CharClass::Whitespace {
+        column += 1;
+    }
+    if column < chars.len() {
+        let class = char_class(chars[column]);
+        while column < chars.len() && char_class(chars[column]) == class {
+            column += 1;
+        }
     }
+    column
 }
@@ -0,0 +1,231 @@
+﻿use super::util::expand_tabs;
+use crate::document::LineBreak;
+use egui::{Color32, FontId, Pos2, Rangef, Rect, Stroke, StrokeKind, Ui, Vec2};
+
+/// How a line ends in the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    /// The last line, with nothing after it.
+    None,
+}
+
+/// Line endings of `text`, one per line of `text.lines()`, as the file
+/// using `line_break` has them.
+pub fn line_endings(text: &str, line_break: LineBreak) -> Vec<LineEnding> {
+    let ending = match line_break {
+        LineBreak::Lf => LineEnding::Lf,
+        LineBreak::CrLf => LineEnding::CrLf,
+    };
+    text.split_inclusive('\n')
+        .map(|line| {
+            if line.ends_with('\n') {
+                ending
+            } else {
+                LineEnding::None
+            }
+        })
+        .collect()
+}
+
+/// Invisible characters that View → Show Whitespace marks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Invisible {
+    Space,
+    Tab,
+    NoBreakSpace,
+    /// En spaces, ideographic spaces and the like.
+    OtherSpace,
+    ZeroWidth,
+    Control,
+    /// A byte order mark anywhere but at the very start of the text, usually
+    /// left over from concatenating files.
+    MisplacedBom,
+}
+
+fn classify(c: char, at_text_start: bool) -> Option<Invisible> {
+    match c {
+        ' ' => Some(Invisible::Space),
+        '\t' => Some(Invisible::Tab),
+        '\u{A0}' | '\u{2007}' | '\u{202F}' => Some(Invisible::NoBreakSpace),
+        '\u{FEFF}' if !at_text_start => Some(Invisible::MisplacedBom),
+        '\u{200B}'..='\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{206F}'
+        | '\u{FEFF}' => Some(Invisible::ZeroWidth),
+        _ if c.is_control() => Some(Invisible::Control),
+        _ if c.is_whitespace() => Some(Invisible::OtherSpace),
+        _ => None,
+    }
+}
+
+/// Name shown when hovering a character that has no visible glyph.
+fn describe(c: char, kind: Invisible) -> String {
+    let name = match c {
+        '\u{A0}' => "NO-BREAK SPACE",
+        '\u{2007}' => "FIGURE SPACE",
+        '\u{202F}' => "NARROW NO-BREAK SPACE",
+        '\u{200B}' => "ZERO WIDTH SPACE",
+        '\u{200C}' => "ZERO WIDTH NON-JOINER",
+        '\u{200D}' => "ZERO WIDTH JOINER",
+        '\u{200E}' => "LEFT-TO-RIGHT MARK",
+        '\u{200F}' => "RIGHT-TO-LEFT MARK",
+        '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => "DIRECTIONAL FORMATTING",
+        '\u{2060}' => "WORD JOINER",
+        '\u{FEFF}' if kind == Invisible::MisplacedBom => {
+            "BYTE ORDER MARK in the middle of the text"
+        }
+        '\u{FEFF}' => "BYTE ORDER MARK",
+        '\r' => "CARRIAGE RETURN",
+        '\0' => "NULL",
+        '\u{1B}' => "ESCAPE",
+        '\u{7F}' => "DELETE",
+        _ if kind == Invisible::ZeroWidth => "FORMAT CHARACTER",
+        _ => "CONTROL CHARACTER",
+    };
+    format!("U+{:04X} {name}", c as u32)
+}
+
+/// What [`paint_whitespace`] needs to know about one drawn line.
+pub struct WhitespaceLine<'a> {
+    /// The line as drawn, after bidi reordering.
+    pub visual: &'a str,
+    /// Logical column of each character of `visual`.
+    pub logical: &'a dyn Fn(usize) -> usize,
+    /// Logical column where the trailing whitespace starts.
+    pub trailing_from: usize,
+    /// Whether the line is the first of the text, where a BOM belongs.
+    pub first: bool,
+    pub ending: LineEnding,
+}
+
+/// Draws faint markers over the spaces, tabs, invisible characters and line
+/// ending of a line drawn at `origin`, and highlights trailing whitespace and
+/// misplaced byte order marks.
+pub fn paint_whitespace(
+    ui: &Ui,
+    font_id: &FontId,
+    origin: Pos2,
+    row_height: f32,
+    line: &WhitespaceLine,
+    tab_width: usize,
+) {
+    let faint = ui.visuals().weak_text_color().gamma_multiply(0.6);
+    let warn = ui.visuals().warn_fg_color;
+    let error = ui.visuals().error_fg_color;
+    let cells = char_cells(ui, font_id, line.visual, tab_width);
+    let mid_y = origin.y + row_height / 2.0;
+    let painter = ui.painter();
+
+    for (idx, (c, cell)) in line.visual.chars().zip(&cells).enumerate() {
+        let logical = (line.logical)(idx);
+        let Some(kind) = classify(c, line.first && logical == 0) else {
+            continue;
+        };
+        let rect = Rect::from_x_y_ranges(
+            (origin.x + cell.min)..=(origin.x + cell.max),
+            origin.y..=(origin.y + row_height),
+        );
+
+        if logical >= line.trailing_from && c.is_whitespace() {
+            painter.rect_filled(rect, 0.0, error.gamma_multiply(0.2));
+        }
+
+        match kind {
+            Invisible::Space | Invisible::OtherSpace => {
+                painter.circle_filled(Pos2::new(rect.center().x, mid_y), 1.0, faint);
+            }
+            Invisible::NoBreakSpace => {
+                painter.circle_stroke(
+                    Pos2::new(rect.center().x, mid_y),
+                    1.5,
+                    Stroke::new(1.0, warn),
+                );
+            }
+            Invisible::Tab => {
+                let length = (rect.width() - 4.0).max(2.0);
+                painter.arrow(
+                    Pos2::new(rect.left() + 2.0, mid_y),
+                    Vec2::new(length, 0.0),
+                    Stroke::new(1.0, faint),
+                );
+            }
+            Invisible::ZeroWidth | Invisible::Control | Invisible::MisplacedBom => {
+                let color = if kind == Invisible::MisplacedBom {
+                    error
+                } else {
+                    warn
+                };
+                // Most of these are drawn zero width, give them a thin bar
+                let marker = if rect.width() < 2.0 {
+                    Rect::from_center_size(rect.center(), Vec2::new(2.0, rect.height()))
+                } else {
+                    rect
+                };
+                if kind == Invisible::MisplacedBom {
+                    painter.rect_filled(marker, 0.0, color.gamma_multiply(0.4));
+                }
+                painter.rect_stroke(marker, 1.0, Stroke::new(1.0, color), StrokeKind::Inside);
+                if ui.rect_contains_pointer(marker.expand(2.0)) {
+                    egui::show_tooltip_text(
+                        ui.ctx(),
+                        ui.layer_id(),
+                        ui.id().with(("invisible", origin.y.to_bits(), idx)),
+                        describe(c, kind),
+                    );
+                }
+            }
+        }
+    }
+
+    let label = match line.ending {
+        LineEnding::Lf => "LF",
+        LineEnding::CrLf => "CRLF",
+        LineEnding::None => return,
+    };
+    let end = cells.last().map_or(0.0, |cell| cell.max);
+    let small = FontId::new(font_id.size * 0.6, font_id.family.clone());
+    let text_rect = painter.text(
+        Pos2::new(origin.x + end + 4.0, mid_y),
+        egui::Align2::LEFT_CENTER,
+        label,
+        small,
+        faint,
+    );
+    painter.rect_stroke(
+        text_rect.expand2(Vec2::new(2.0, 0.0)),
+        2.0,
+        Stroke::new(1.0, faint),
+        StrokeKind::Outside,
+    );
+}
+
+/// Horizontal extent of each character of `visual` as drawn, with tabs
+/// covering the columns up to the next tab stop.
+fn char_cells(ui: &Ui, font_id: &FontId, visual: &str, tab_width: usize) -> Vec<Rangef> {
+    let tab_width = tab_width.max(1);
+    let expanded = expand_tabs(visual, tab_width).into_owned();
+    let galley = ui.fonts(|f| f.layout_no_wrap(expanded, font_id.clone(), Color32::WHITE));
+    let glyphs = galley.rows.first().map_or(&[][..], |row| &row.glyphs[..]);
+    let x_at = |column: usize| {
+        glyphs
+            .get(column)
+            .map_or(galley.size().x, |glyph| glyph.pos.x)
+    };
+
+    let mut column = 0;
+    visual
+        .chars()
+        .map(|c| {
+            let width = if c == '\t' {
+                tab_width - column % tab_width
+            } else {
+                1
+            };
+            let cell = Rangef::new(x_at(column), x_at(column + width));
+            column += width;
+            cell
+        })
+        .collect()
+}
@@ -1,5 +1,4 @@
-﻿use crate::icons::Icons;
-use eframe::egui::{self, ViewportCommand};
+﻿use eframe::egui::{self, ViewportCommand};
 use eframe::emath::Vec2;
 use eframe::epaint::Color32;
 use egui::{Button, Response};
@@ -173,7 +172,7 @@ struct TitleBar;
 
 impl TitleBar {
     fn show(ui: &mut egui::Ui, title_bar_rect: egui::Rect, title: &str, assets: &AssetManager) {
-        use egui::{Align2, FontId, Id, PointerButton, Sense, UiBuilder, vec2};
+        use egui::{Align2, FontId, Id, Sense, vec2};
 
         let painter = ui.painter();
 
@@ -0,0 +1,1186 @@
+﻿// src/document.rs
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Extension of single-file documents bundling text, manifest and image files in a zip.
+pub const BUNDLE_EXTENSION: &str = "notula";
+const BUNDLE_VERSION: u32 = 1;
+const BUNDLE_TEXT: &str = "document.txt";
+const BUNDLE_MANIFEST: &str = "manifest.json";
+/// File name prefix of images Notula writes to an assets folder, followed by the image id.
+const ASSET_PREFIX: &str = "image-";
+
+/// How the images of a plain-text document are stored on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageStorage {
+    /// Base64 blobs inside the `.meta` sidecar.
+    #[default]
+    Sidecar,
+    /// Real image files in a `<file name>.assets/` folder next to the document,
+    /// with placeholders referencing them by file name.
+    AssetsFolder,
+}
+
+/// The line break a document's file uses. The editor only works with `\n`,
+/// so CRLF files are converted when read and converted back when saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineBreak {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineBreak {
+    /// The line break of `text`'s first line.
+    fn detect(text: &str) -> Self {
+        match text.find('\n') {
+            Some(end) if text[..end].ends_with('\r') => Self::CrLf,
+            _ => Self::Lf,
+        }
+    }
+
+    /// `text` with its `\n` line breaks replaced by this one.
+    fn apply<'a>(self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            Self::Lf => Cow::Borrowed(text),
+            Self::CrLf => Cow::Owned(text.replace('\n', "\r\n")),
+        }
+    }
+}
+
+/// A document read from disk, with every image placeholder resolved to an id
+/// and `\n` line breaks only.
+pub struct LoadedDocument {
+    pub text: String,
+    pub metadata: DocumentMetadata,
+    pub storage: ImageStorage,
+    pub line_break: LineBreak,
+}
+
+/// Sidecar metadata stored next to a document as `<file>.meta`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    #[serde(default)]
+    pub images: BTreeMap<usize, ImageMetadata>,
+    /// Encoded image files keyed by the SHA-256 of their bytes, so images with
+    /// identical content share a single blob.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blobs: BTreeMap<String, ImageBlob>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sketches: BTreeMap<usize, Sketch>,
+}
+
+impl DocumentMetadata {
+    pub fn add_image(
+        &mut self,
+        id: usize,
+        hash: &str,
+        bytes: &[u8],
+        width: u32,
+        height: u32,
+    ) -> &mut ImageMetadata {
+        self.blobs
+            .entry(hash.to_string())
+            .or_insert_with(|| ImageBlob::new(bytes));
+        self.images.insert(
+            id,
+            ImageMetadata {
+                id,
+                hash: hash.to_string(),
+                width,
+                height,
+                annotations: Vec::new(),
+                markdown: None,
+                markdown_link: None,
+                shared_asset: None,
+                data: String::new(),
+                svg: None,
+            },
+        );
+        self.images.get_mut(&id).expect("image was just inserted")
+    }
+
+    /// The encoded bytes of image `id`.
+    pub fn image_bytes(&self, id: usize) -> Option<Result<Vec<u8>, base64::DecodeError>> {
+        let image = self.images.get(&id)?;
+        match self.blobs.get(&image.hash) {
+            Some(blob) => Some(blob.bytes()),
+            // Files written before blobs existed store the image data inline
+            None if !image.data.is_empty() || image.svg.is_some() => Some(
+                ImageBlob {
+                    data: image.data.clone(),
+                    svg: image.svg.clone(),
+                }
+                .bytes(),
+            ),
+            None => None,
+        }
+    }
+}
+
+/// A single image referenced by an `[image(id)]` placeholder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub id: usize,
+    #[serde(default)]
+    pub hash: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+    /// Set for images opened from standard Markdown syntax, which is written
+    /// back instead of the placeholder and keeps the image out of the sidecar.
+    #[serde(skip)]
+    pub markdown: Option<MarkdownImage>,
+    /// Set for Markdown images whose annotations or edited pixels are kept in
+    /// the sidecar, since Markdown has no syntax for them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub markdown_link: Option<MarkdownLink>,
+    /// Set in [`ImageStorage::AssetsFolder`] mode when the image's placeholder
+    /// names an asset file that is not `image-<id>`, because identical images
+    /// share one file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_asset: Option<SharedAsset>,
+    #[serde(default, skip_serializing)]
+    data: String,
+    #[serde(default, skip_serializing)]
+    svg: Option<String>,
+}
+
+/// An image written as `![alt](target)` in a Markdown document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarkdownImage {
+    pub alt: String,
+    /// The optional `"title"` after the link target.
+    pub title: String,
+    /// The original Markdown text, written back unchanged on save.
+    pub source: String,
+    /// Content hash of the file at the link target, which edited copies no longer match.
+    pub hash: String,
+}
+
+/// The Markdown image in the text a sidecar entry belongs to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarkdownLink {
+    /// The image syntax as written in the text.
+    pub source: String,
+    /// Which of several images written the same way this is, counted from 0.
+    #[serde(default)]
+    pub occurrence: usize,
+    /// Content hash of the link target when the entry was saved.
+    pub target_hash: String,
+}
+
+/// The asset file placeholder a sidecar entry belongs to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedAsset {
+    pub name: String,
+    /// Which of the placeholders naming the file this is, counted from 0.
+    #[serde(default)]
+    pub occurrence: usize,
+}
+
+/// Vector markup drawn over an image, in image pixel coordinates.
+///
+/// Annotations never modify the image blob; they are only burned into the
+/// pixels when the image is exported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Annotation {
+    Arrow {
+        from: [f32; 2],
+        to: [f32; 2],
+        color: [u8; 4],
+        width: f32,
+    },
+    Rectangle {
+        min: [f32; 2],
+        max: [f32; 2],
+        color: [u8; 4],
+        width: f32,
+    },
+    Highlight {
+        points: Vec<[f32; 2]>,
+        color: [u8; 4],
+        width: f32,
+    },
+    Callout {
+        center: [f32; 2],
+        number: u32,
+        color: [u8; 4],
+    },
+    Label {
+        position: [f32; 2],
+        text: String,
+        color: [u8; 4],
+        size: f32,
+    },
+}
+
+/// A freehand drawing referenced by a `[sketch(id)]` placeholder.
+///
+/// Sizes and stroke points are in points, relative to the top-left corner of
+/// the sketch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sketch {
+    pub width: f32,
+    pub height: f32,
+    #[serde(default)]
+    pub strokes: Vec<SketchStroke>,
+}
+
+impl Sketch {
+    /// The scale at which the sketch is `width` wide.
+    pub fn scale_to_width(&self, width: f32) -> f32 {
+        width / self.width.max(1.0)
+    }
+
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            width,
+            height,
+            strokes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SketchStroke {
+    pub points: Vec<[f32; 2]>,
+    pub color: [u8; 4],
+    pub width: f32,
+}
+
+impl SketchStroke {
+    /// The stroke's points in a sketch drawn from `origin` at `scale` times its size.
+    pub fn placed_points(
+        &self,
+        origin: [f32; 2],
+        scale: f32,
+    ) -> impl Iterator<Item = [f32; 2]> + '_ {
+        self.points
+            .iter()
+            .map(move |point| [origin[0] + point[0] * scale, origin[1] + point[1] * scale])
+    }
+}
+
+/// The original encoded bytes of an image file.
+///
+/// `data` holds the file base64-encoded (PNG, JPEG, GIF, ...) so that animated
+/// images keep all of their frames when saved and reopened. SVG images are kept
+/// readable as plain markup in `svg` instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageBlob {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub svg: Option<String>,
+}
+
+impl ImageBlob {
+    pub fn new(bytes: &[u8]) -> Self {
+        let svg = is_svg(bytes)
+            .then(|| std::str::from_utf8(bytes).ok())
+            .flatten()
+            .map(str::to_string);
+
+        Self {
+            data: if svg.is_some() {
+                String::new()
+            } else {
+                BASE64.encode(bytes)
+            },
+            svg,
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match &self.svg {
+            Some(svg) => Ok(svg.as_bytes().to_vec()),
+            None => BASE64.decode(&self.data),
+        }
+    }
+}
+
+/// Contents of `manifest.json` inside a `.notula` bundle.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BundleManifest {
+    version: u32,
+    #[serde(default)]
+    images: BTreeMap<usize, ImageMetadata>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sketches: BTreeMap<usize, Sketch>,
+    /// Markdown syntax of the images opened from a Markdown document, keyed by
+    /// image id, so converting back to Markdown writes it again.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    markdown: BTreeMap<usize, MarkdownImage>,
+    /// Zip entry holding each image file, keyed by content hash.
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+}
+
+/// Hex-encoded SHA-256 of `bytes`, used as the key of an image blob.
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Sniffs whether `bytes` look like an SVG document rather than a raster image.
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    // Raster formats never start with markup, so any XML prolog, doctype or
+    // comment followed by an <svg> element is treated as SVG
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// File extension matching the format of the encoded image `bytes`.
+pub fn image_extension(bytes: &[u8]) -> &'static str {
+    if is_svg(bytes) {
+        return "svg";
+    }
+    image::guess_format(bytes)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("png")
+}
+
+/// Whether `path` has a Markdown extension, enabling `![alt](path)` images.
+pub fn is_markdown(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("md") || extension.eq_ignore_ascii_case("markdown")
+    })
+}
+
+/// Whether `path` is a single-file `.notula` bundle rather than text plus sidecar.
+pub fn is_bundle(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(BUNDLE_EXTENSION))
+}
+
+pub fn metadata_path(path: &Path) -> PathBuf {
+    let mut meta_path = path.as_os_str().to_owned();
+    meta_path.push(".meta");
+    PathBuf::from(meta_path)
+}
+
+/// Folder holding the image files of `path` in [`ImageStorage::AssetsFolder`] mode,
+/// named after the whole file name so `notes.txt` and `notes.md` keep theirs apart.
+pub fn assets_dir(path: &Path) -> PathBuf {
+    let mut assets = path.as_os_str().to_owned();
+    assets.push(".assets");
+    PathBuf::from(assets)
+}
+
+/// The assets folder to read `path`'s images from, falling back to the
+/// `notes.assets` name used before folders included the extension.
+fn existing_assets_dir(path: &Path) -> PathBuf {
+    let assets = assets_dir(path);
+    let legacy = path.with_extension("assets");
+    if !assets.exists() && legacy.is_dir() {
+        legacy
+    } else {
+        assets
+    }
+}
+
+/// Names of the asset files the version of `path` on disk references, empty
+/// unless it was last saved in [`ImageStorage::AssetsFolder`] mode.
+fn referenced_assets(path: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    if let Ok(text) = fs::read_to_string(path) {
+        rewrite_placeholders(&text, "image", |inner| {
+            if inner.parse::<usize>().is_err() {
+                names.insert(inner.to_string());
+            }
+            None
+        });
+    }
+    names
+}
+
+pub fn load(path: &Path) -> io::Result<LoadedDocument> {
+    if is_bundle(path) {
+        let (text, metadata) = load_bundle(path)?;
+        return Ok(LoadedDocument {
+            line_break: LineBreak::detect(&text),
+            text: text.replace("\r\n", "\n"),
+            metadata,
+            storage: ImageStorage::Sidecar,
+        });
+    }
+
+    let text = fs::read_to_string(path)?;
+    let line_break = LineBreak::detect(&text);
+    let text = text.replace("\r\n", "\n");
+
+    let meta_path = metadata_path(path);
+    let mut metadata = if meta_path.exists() {
+        let json = fs::read_to_string(meta_path)?;
+        serde_json::from_str(&json).map_err(io::Error::other)?
+    } else {
+        DocumentMetadata::default()
+    };
+
+    let (text, storage) = resolve_assets(&existing_assets_dir(path), &text, &mut metadata);
+    let text = if is_markdown(path) {
+        let base = path.parent().unwrap_or(Path::new(""));
+        resolve_markdown_images(base, &text, &mut metadata)
+    } else {
+        text
+    };
+
+    Ok(LoadedDocument {
+        text,
+        metadata,
+        storage,
+        line_break,
+    })
+}
+
+/// Saves `text`, which has `\n` line breaks, with `line_break` ones.
+pub fn save(
+    path: &Path,
+    text: &str,
+    metadata: &DocumentMetadata,
+    storage: ImageStorage,
+    line_break: LineBreak,
+) -> io::Result<()> {
+    if is_bundle(path) {
+        return save_bundle(path, &line_break.apply(text), metadata);
+    }
+
+    // Other formats have no Markdown syntax, so those images are saved as placeholders
+    let (text, metadata) = if is_markdown(path) {
+        restore_markdown_images(text, metadata)
+    } else {
+        (text.to_string(), metadata.clone())
+    };
+
+    // Only files the previous save wrote are cleaned up, the folder may hold others
+    let assets = assets_dir(path);
+    let previous = referenced_assets(path);
+    let (text, metadata) = match storage {
+        ImageStorage::Sidecar => {
+            remove_stale_assets(&assets, &previous, &HashSet::new())?;
+            (text, metadata)
+        }
+        ImageStorage::AssetsFolder => write_assets(&assets, &previous, &text, &metadata)?,
+    };
+
+    fs::write(path, line_break.apply(&text).as_bytes())?;
+
+    let meta_path = metadata_path(path);
+    if metadata.images.is_empty() && metadata.sketches.is_empty() {
+        if meta_path.exists() {
+            fs::remove_file(meta_path)?;
+        }
+    } else {
+        let json = serde_json::to_string_pretty(&metadata).map_err(io::Error::other)?;
+        fs::write(meta_path, json)?;
+    }
+
+    Ok(())
+}
+
+/// Replaces every `[tag(...)]` placeholder for which `rewrite`, given the text
+/// between the parentheses, returns a replacement.
+pub fn rewrite_placeholders(
+    text: &str,
+    tag: &str,
+    mut rewrite: impl FnMut(&str) -> Option<String>,
+) -> String {
+    const CLOSE: &str = ")]";
+    let open = format!("[{tag}(");
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(&open) {
+        let inner_start = start + open.len();
+        let Some(length) = rest[inner_start..].find(CLOSE) else {
+            break;
+        };
+        let inner = &rest[inner_start..inner_start + length];
+
+        result.push_str(&rest[..start]);
+        match rewrite(inner).filter(|_| !inner.contains('\n')) {
+            Some(replacement) => result.push_str(&replacement),
+            None => result.push_str(&rest[start..inner_start + length + CLOSE.len()]),
+        }
+        rest = &rest[inner_start + length + CLOSE.len()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The id encoded in a file name Notula wrote to an assets folder.
+fn asset_id(name: &str) -> Option<usize> {
+    Path::new(name)
+        .file_stem()?
+        .to_str()?
+        .strip_prefix(ASSET_PREFIX)?
+        .parse()
+        .ok()
+}
+
+/// Width and height read from the image header, without decoding the pixels.
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Loads images referenced by file name from `assets` and points their
+/// placeholders at image ids instead.
+fn resolve_assets(
+    assets: &Path,
+    text: &str,
+    metadata: &mut DocumentMetadata,
+) -> (String, ImageStorage) {
+    let mut storage = ImageStorage::Sidecar;
+    let mut used: HashSet<usize> = HashSet::new();
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut next_id = metadata.images.keys().max().map_or(1, |id| id + 1);
+
+    let text = rewrite_placeholders(text, "image", |name| {
+        if name.parse::<usize>().is_ok() {
+            return None;
+        }
+        storage = ImageStorage::AssetsFolder;
+        if !files.contains_key(name) {
+            match fs::read(assets.join(name)) {
+                Ok(bytes) => files.insert(name.to_string(), bytes),
+                Err(err) => {
+                    log::warn!("Failed to read image asset {name}: {err}");
+                    return None;
+                }
+            };
+        }
+        let bytes = &files[name];
+
+        let occurrence = occurrences.entry(name.to_string()).or_default();
+        let placeholder = SharedAsset {
+            name: name.to_string(),
+            occurrence: *occurrence,
+        };
+        *occurrence += 1;
+
+        // The sidecar entry saved for this placeholder, or for its file
+        let entry_id = metadata
+            .images
+            .iter()
+            .find(|(_, image)| image.shared_asset.as_ref() == Some(&placeholder))
+            .map(|(id, _)| *id)
+            .or_else(|| {
+                asset_id(name).filter(|id| {
+                    metadata
+                        .images
+                        .get(id)
+                        .is_none_or(|image| image.shared_asset.is_none())
+                })
+            });
+        let id = entry_id.filter(|id| !used.contains(id)).unwrap_or(next_id);
+        next_id = next_id.max(id + 1);
+        used.insert(id);
+
+        // Sizes and annotations from the sidecar only apply if the file is unchanged
+        let hash = content_hash(bytes);
+        let previous = entry_id
+            .and_then(|id| metadata.images.get(&id))
+            .filter(|image| image.hash == hash)
+            .cloned();
+        let (width, height) = previous
+            .as_ref()
+            .map(|image| (image.width, image.height))
+            .or_else(|| image_dimensions(bytes))
+            .unwrap_or((0, 0));
+
+        metadata
+            .add_image(id, &hash, bytes, width, height)
+            .annotations = previous.map(|image| image.annotations).unwrap_or_default();
+        Some(format!("[image({id})]"))
+    });
+
+    if storage == ImageStorage::AssetsFolder {
+        // Sidecar entries of deleted asset files have nothing to load
+        let missing: Vec<usize> = metadata
+            .images
+            .iter()
+            .filter(|(id, image)| {
+                image.markdown_link.is_none() && metadata.image_bytes(**id).is_none()
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in missing {
+            metadata.images.remove(&id);
+        }
+    }
+
+    (text, storage)
+}
+
+/// Replaces Markdown `![alt](target)` images whose target is a data URI or a
+/// file next to the document with placeholders, keeping the original syntax
+/// for saving.
+///
+/// Image syntax inside code spans and fenced code blocks is left alone.
+fn resolve_markdown_images(base: &Path, text: &str, metadata: &mut DocumentMetadata) -> String {
+    // Annotations and edits of Markdown images saved in the sidecar, matched up below
+    let mut linked: Vec<ImageMetadata> = Vec::new();
+    metadata.images.retain(|_, image| {
+        if image.markdown_link.is_some() {
+            linked.push(image.clone());
+        }
+        image.markdown_link.is_none()
+    });
+    let mut next_id = metadata.images.keys().max().map_or(1, |id| id + 1);
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+
+    let code = markdown_code_ranges(text);
+    let mut result = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut search = 0;
+    while let Some(found) = text[search..].find("![") {
+        let start = search + found;
+        if let Some(range) = code.iter().find(|range| range.contains(&start)) {
+            search = range.end;
+            continue;
+        }
+        let Some(image) = parse_markdown_image(&text[start..]) else {
+            search = start + 2;
+            continue;
+        };
+        let end = start + image.length;
+        let source = &text[start..end];
+        search = end;
+
+        let target_bytes = match markdown_image_bytes(base, image.target) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::warn!("Failed to load Markdown image {}: {err}", image.target);
+                continue;
+            }
+        };
+        let target_hash = content_hash(&target_bytes);
+
+        let occurrence = occurrences.entry(source).or_default();
+        let link = linked.iter().find(|image| {
+            image
+                .markdown_link
+                .as_ref()
+                .is_some_and(|link| link.source == source && link.occurrence == *occurrence)
+        });
+        *occurrence += 1;
+        let link = link.filter(|entry| {
+            let unchanged = entry
+                .markdown_link
+                .as_ref()
+                .is_some_and(|link| link.target_hash == target_hash);
+            if !unchanged {
+                log::warn!(
+                    "{} changed since it was annotated or edited, using the file as it is",
+                    image.target
+                );
+            }
+            unchanged
+        });
+        let edited = link
+            .filter(|image| image.hash != target_hash)
+            .and_then(|image| metadata.blobs.get(&image.hash))
+            .and_then(|blob| blob.bytes().ok());
+
+        let id = next_id;
+        next_id += 1;
+        let bytes = edited.unwrap_or(target_bytes);
+        let (width, height) = link
+            .map(|image| (image.width, image.height))
+            .or_else(|| image_dimensions(&bytes))
+            .unwrap_or((0, 0));
+        let entry = metadata.add_image(id, &content_hash(&bytes), &bytes, width, height);
+        entry.annotations = link
+            .map(|image| image.annotations.clone())
+            .unwrap_or_default();
+        entry.markdown = Some(MarkdownImage {
+            alt: image.alt.to_string(),
+            title: image.title.to_string(),
+            source: source.to_string(),
+            hash: target_hash,
+        });
+
+        result.push_str(&text[copied..start]);
+        result.push_str(&format!("[image({id})]"));
+        copied = end;
+    }
+    result.push_str(&text[copied..]);
+    result
+}
+
+/// Byte ranges of the fenced code blocks and inline code spans of Markdown
+/// `text`, where image syntax is just text.
+fn markdown_code_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    // Fence character, its length and where the block started
+    let mut fence: Option<(u8, usize, usize)> = None;
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let marker = &line[indent..];
+        let run = |c: u8| marker.bytes().take_while(|&b| b == c).count();
+
+        match fence {
+            Some((c, length, start)) => {
+                if indent <= 3 && run(c) >= length && marker[run(c)..].trim().is_empty() {
+                    ranges.push(start..line_end);
+                    fence = None;
+                }
+            }
+            None => {
+                let opening = [b'`', b'~']
+                    .into_iter()
+                    .map(|c| (c, run(c)))
+                    .find(|&(_, length)| length >= 3);
+                match opening {
+                    Some((c, length)) if indent <= 3 => fence = Some((c, length, line_start)),
+                    _ => ranges.extend(
+                        code_spans(line)
+                            .into_iter()
+                            .map(|span| line_start + span.start..line_start + span.end),
+                    ),
+                }
+            }
+        }
+        line_start = line_end;
+    }
+    // An unclosed fence runs to the end of the document
+    if let Some((_, _, start)) = fence {
+        ranges.push(start..text.len());
+    }
+    ranges
+}
+
+/// Byte ranges of the inline code spans in `line`: text between two runs of
+/// the same number of backticks.
+fn code_spans(line: &str) -> Vec<Range<usize>> {
+    let bytes = line.as_bytes();
+    let run_at = |index: usize| bytes[index..].iter().take_while(|&&b| b == b'`').count();
+
+    let mut spans = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'`' {
+            index += 1;
+            continue;
+        }
+        let length = run_at(index);
+
+        let mut close = None;
+        let mut search = index + length;
+        while search < bytes.len() {
+            if bytes[search] != b'`' {
+                search += 1;
+                continue;
+            }
+            let run = run_at(search);
+            if run == length {
+                close = Some(search + run);
+                break;
+            }
+            search += run;
+        }
+
+        match close {
+            Some(end) => {
+                spans.push(index..end);
+                index = end;
+            }
+            // Unmatched backticks are literal
+            None => index += length,
+        }
+    }
+    spans
+}
+
+/// Puts the original Markdown back for images opened from Markdown syntax and
+/// leaves them out of the metadata, since their data lives at the link target.
+///
+/// Annotated or edited ones keep a sidecar entry pointing at their Markdown,
+/// with the edited pixels but not the unchanged ones.
+fn restore_markdown_images(text: &str, metadata: &DocumentMetadata) -> (String, DocumentMetadata) {
+    let mut metadata = metadata.clone();
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    let text = rewrite_placeholders(text, "image", |inner| {
+        let id = inner.parse::<usize>().ok()?;
+        let image = metadata.images.get_mut(&id)?;
+        let markdown = image.markdown.as_ref()?;
+
+        let occurrence = occurrences.entry(markdown.source.clone()).or_default();
+        if image.hash != markdown.hash || !image.annotations.is_empty() {
+            image.markdown_link = Some(MarkdownLink {
+                source: markdown.source.clone(),
+                occurrence: *occurrence,
+                target_hash: markdown.hash.clone(),
+            });
+        }
+        *occurrence += 1;
+        Some(markdown.source.clone())
+    });
+
+    metadata
+        .images
+        .retain(|_, image| image.markdown.is_none() || image.markdown_link.is_some());
+    let used: HashSet<&String> = metadata
+        .images
+        .values()
+        .filter(|image| {
+            image
+                .markdown
+                .as_ref()
+                .is_none_or(|markdown| markdown.hash != image.hash)
+        })
+        .map(|image| &image.hash)
+        .collect();
+    let blobs = metadata
+        .blobs
+        .iter()
+        .filter(|(hash, _)| used.contains(hash))
+        .map(|(hash, blob)| (hash.clone(), blob.clone()))
+        .collect();
+    metadata.blobs = blobs;
+
+    (text, metadata)
+}
+
+/// The parts of a Markdown `![alt](target "title")` image.
+struct ParsedMarkdownImage<'a> {
+    alt: &'a str,
+    target: &'a str,
+    title: &'a str,
+    /// Byte length of the whole image syntax.
+    length: usize,
+}
+
+/// Parses a Markdown image at the start of `text`.
+fn parse_markdown_image(text: &str) -> Option<ParsedMarkdownImage<'_>> {
+    let after_bang = text.strip_prefix("![")?;
+    let alt_end = after_bang.find(']')?;
+    let alt = &after_bang[..alt_end];
+    if alt.contains('\n') {
+        return None;
+    }
+
+    let link_start = 2 + alt_end + 1;
+    let link = text[link_start..].strip_prefix('(')?;
+
+    // Link targets may contain balanced parentheses
+    let mut depth = 0;
+    let mut link_end = None;
+    for (index, c) in link.char_indices() {
+        match c {
+            '\n' => return None,
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                link_end = Some(index);
+                break;
+            }
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    let link_end = link_end?;
+    let inner = link[..link_end].trim();
+
+    let (target, title) = if let Some(bracketed) = inner.strip_prefix('<') {
+        let end = bracketed.find('>')?;
+        (&bracketed[..end], &bracketed[end + 1..])
+    } else {
+        let end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+        (&inner[..end], &inner[end..])
+    };
+
+    let title = title.trim();
+    let title = ["\"\"", "''", "()"]
+        .iter()
+        .find_map(|quotes| {
+            let mut quotes = quotes.chars();
+            title
+                .strip_prefix(quotes.next()?)?
+                .strip_suffix(quotes.next()?)
+        })
+        .unwrap_or("");
+
+    Some(ParsedMarkdownImage {
+        alt,
+        target,
+        title,
+        length: link_start + 1 + link_end + 1,
+    })
+}
+
+/// Reads the image a Markdown link points at, either a `data:` URI or a path
+/// relative to the document's folder.
+fn markdown_image_bytes(base: &Path, target: &str) -> io::Result<Vec<u8>> {
+    if let Some(data_uri) = target.strip_prefix("data:") {
+        let (header, data) = data_uri
+            .split_once(',')
+            .ok_or_else(|| io::Error::other("data URI without a comma"))?;
+        return if header.ends_with(";base64") {
+            let data: String = data.split_whitespace().collect();
+            BASE64.decode(data).map_err(io::Error::other)
+        } else {
+            Ok(percent_decode(data).into_bytes())
+        };
+    }
+
+    let target = target.strip_prefix("file://").unwrap_or(target);
+    if target.contains("://") {
+        return Err(io::Error::other("remote images are not downloaded"));
+    }
+
+    let path = percent_decode(target);
+    fs::read(base.join(path))
+}
+
+/// Decodes `%XX` escapes, as used for spaces in Markdown link targets.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && let Some(byte) = text
+                .get(index + 1..index + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Writes every image to `assets` as a file and references it by file name,
+/// returning the text and the sidecar metadata without embedded image data.
+///
+/// Files in `previous`, written by the last save, are removed once no image uses them.
+fn write_assets(
+    assets: &Path,
+    previous: &HashSet<String>,
+    text: &str,
+    metadata: &DocumentMetadata,
+) -> io::Result<(String, DocumentMetadata)> {
+    if !metadata.images.is_empty() {
+        fs::create_dir_all(assets)?;
+    }
+
+    let mut names: HashMap<usize, String> = HashMap::new();
+    // Identical images share one file, keyed by content hash; their
+    // annotations are kept apart in the sidecar
+    let mut files: HashMap<&str, String> = HashMap::new();
+    for (id, image) in &metadata.images {
+        // Edits of Markdown images stay in the sidecar, their text has no placeholder
+        if image.markdown_link.is_some() {
+            continue;
+        }
+        if let Some(name) = files.get(image.hash.as_str()) {
+            names.insert(*id, name.clone());
+            continue;
+        }
+
+        let Some(bytes) = metadata.image_bytes(*id) else {
+            continue;
+        };
+        let bytes = bytes.map_err(io::Error::other)?;
+        let name = format!("{ASSET_PREFIX}{id}.{}", image_extension(&bytes));
+
+        // Leave unchanged files alone so their modification time stays put
+        let file = assets.join(&name);
+        if fs::read(&file).ok().as_deref() != Some(bytes.as_slice()) {
+            fs::write(&file, &bytes)?;
+        }
+
+        files.insert(&image.hash, name.clone());
+        names.insert(*id, name);
+    }
+
+    remove_stale_assets(assets, previous, &names.values().cloned().collect())?;
+
+    // Placeholders naming a file that is not their own record which one they are
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+    let mut shared: Vec<(usize, SharedAsset)> = Vec::new();
+    let mut seen = HashSet::new();
+    let text = rewrite_placeholders(text, "image", |inner| {
+        let id = inner.parse::<usize>().ok()?;
+        let name = names.get(&id)?;
+        let occurrence = occurrences.entry(name).or_default();
+        // A repeated placeholder of the same image reuses its entry when loaded
+        if seen.insert(id) && (*occurrence > 0 || asset_id(name) != Some(id)) {
+            shared.push((
+                id,
+                SharedAsset {
+                    name: name.clone(),
+                    occurrence: *occurrence,
+                },
+            ));
+        }
+        *occurrence += 1;
+        Some(format!("[image({name})]"))
+    });
+
+    let mut metadata = metadata.clone();
+    for image in metadata.images.values_mut() {
+        image.shared_asset = None;
+    }
+    for (id, placeholder) in shared {
+        if let Some(image) = metadata.images.get_mut(&id) {
+            image.shared_asset = Some(placeholder);
+        }
+    }
+    let linked: HashSet<String> = metadata
+        .images
+        .values()
+        .filter(|image| image.markdown_link.is_some())
+        .map(|image| image.hash.clone())
+        .collect();
+    metadata.blobs.retain(|hash, _| linked.contains(hash));
+    Ok((text, metadata))
+}
+
+/// Deletes the image files in `previous` that Notula wrote to `assets` and are
+/// not in `keep`, and the folder itself once it is empty. Files added by the
+/// user or referenced by other documents are never touched.
+fn remove_stale_assets(
+    assets: &Path,
+    previous: &HashSet<String>,
+    keep: &HashSet<String>,
+) -> io::Result<()> {
+    if previous.is_empty() {
+        return Ok(());
+    }
+    for name in previous.difference(keep) {
+        let is_plain_name = Path::new(name).file_name() == Some(name.as_ref());
+        if !is_plain_name || asset_id(name).is_none() {
+            continue;
+        }
+        match fs::remove_file(assets.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+
+    if fs::read_dir(assets).is_ok_and(|mut entries| entries.next().is_none()) {
+        fs::remove_dir(assets)?;
+    }
+    Ok(())
+}
+
+fn load_bundle(path: &Path) -> io::Result<(String, DocumentMetadata)> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+
+    let manifest: BundleManifest =
+        serde_json::from_reader(archive.by_name(BUNDLE_MANIFEST)?).map_err(io::Error::other)?;
+    if manifest.version > BUNDLE_VERSION {
+        log::warn!(
+            "{} was written by a newer version of Notula (bundle version {})",
+            path.display(),
+            manifest.version
+        );
+    }
+
+    let mut text = String::new();
+    archive.by_name(BUNDLE_TEXT)?.read_to_string(&mut text)?;
+
+    let mut images = manifest.images;
+    for (id, markdown) in manifest.markdown {
+        if let Some(image) = images.get_mut(&id) {
+            image.markdown = Some(markdown);
+        }
+    }
+    let mut metadata = DocumentMetadata {
+        images,
+        sketches: manifest.sketches,
+        ..Default::default()
+    };
+    for (hash, name) in manifest.files {
+        let mut bytes = Vec::new();
+        archive.by_name(&name)?.read_to_end(&mut bytes)?;
+        metadata.blobs.insert(hash, ImageBlob::new(&bytes));
+    }
+
+    Ok((text, metadata))
+}
+
+fn save_bundle(path: &Path, text: &str, metadata: &DocumentMetadata) -> io::Result<()> {
+    // Write next to the target first so a failed save never destroys the previous bundle
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|file| write_bundle(file, text, metadata))
+        .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write_bundle(file: fs::File, text: &str, metadata: &DocumentMetadata) -> io::Result<()> {
+    let mut manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        images: metadata.images.clone(),
+        sketches: metadata.sketches.clone(),
+        markdown: metadata
+            .images
+            .iter()
+            .filter_map(|(id, image)| Some((*id, image.markdown.clone()?)))
+            .collect(),
+        files: BTreeMap::new(),
+    };
+
+    let mut zip = ZipWriter::new(file);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file(BUNDLE_TEXT, deflated)?;
+    zip.write_all(text.as_bytes())?;
+
+    for (hash, blob) in &metadata.blobs {
+        let bytes = blob.bytes().map_err(io::Error::other)?;
+        let name = format!("images/{hash}.{}", image_extension(&bytes));
+
+        // Raster formats are already compressed
+        let options = if is_svg(&bytes) {
+            deflated
+        } else {
+            SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+        };
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(&bytes)?;
+        manifest.files.insert(hash.clone(), name);
+    }
+
+    zip.start_file(BUNDLE_MANIFEST, deflated)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(io::Error::other)?;
+    zip.finish()?;
+
+    Ok(())
+}
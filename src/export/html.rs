@@ -0,0 +1,194 @@
+﻿use super::{ExportedImage, escape_xml, line_caption, sketch_svg};
+use crate::components::text_editor::extract_placeholder_id;
+use crate::document::{DocumentMetadata, rewrite_placeholders};
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+// Links the Markdown renderer resolves back to the document's images and sketches
+const IMAGE_SCHEME: &str = "notula-image:";
+const SKETCH_SCHEME: &str = "notula-sketch:";
+
+const STYLE: &str = "body { font-family: system-ui, sans-serif; line-height: 1.5; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; color: #222; }
+.text { white-space: pre-wrap; font-family: ui-monospace, monospace; }
+figure { margin: 1rem 0; }
+img, svg { max-width: 100%; height: auto; }
+figcaption { font-size: 0.9em; color: #555; }
+pre { background: #f4f4f4; padding: 0.75rem; overflow-x: auto; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 0.25rem 0.5rem; }";
+
+/// Converts a note into a single HTML page with every image embedded as a data URI.
+///
+/// Markdown documents are rendered as Markdown, plain text keeps its line breaks.
+pub fn to_html(title: &str, text: &str, metadata: &DocumentMetadata, markdown: bool) -> String {
+    let body = if markdown {
+        markdown_body(text, metadata)
+    } else {
+        plain_body(text, metadata)
+    };
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n{body}</body>\n</html>\n",
+        escape_xml(title)
+    )
+}
+
+fn plain_body(text: &str, metadata: &DocumentMetadata) -> String {
+    let mut body = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+
+    for line in text.lines() {
+        let block = if let Some(id) = extract_placeholder_id(line, "image") {
+            ExportedImage::load(metadata, id).map(|image| {
+                let caption = line_caption(line, "image");
+                let alt = if caption.is_empty() {
+                    format!("Image {id}")
+                } else {
+                    caption.clone()
+                };
+                figure(&image_tag(&image, &alt), &caption)
+            })
+        } else {
+            extract_placeholder_id(line, "sketch")
+                .and_then(|id| metadata.sketches.get(&id))
+                .map(|sketch| figure(&sketch_svg(sketch), &line_caption(line, "sketch")))
+        };
+
+        match block {
+            Some(html) => {
+                flush_paragraph(&mut body, &mut paragraph);
+                body.push_str(&html);
+            }
+            None => paragraph.push(line),
+        }
+    }
+    flush_paragraph(&mut body, &mut paragraph);
+
+    body
+}
+
+fn flush_paragraph(body: &mut String, paragraph: &mut Vec<&str>) {
+    if paragraph.is_empty() {
+        return;
+    }
+    body.push_str("<div class=\"text\">");
+    body.push_str(&escape_xml(&paragraph.join("\n")));
+    body.push_str("</div>\n");
+    paragraph.clear();
+}
+
+fn markdown_body(text: &str, metadata: &DocumentMetadata) -> String {
+    let source = rewrite_placeholders(text, "image", |inner| {
+        let id = inner.parse::<usize>().ok()?;
+        Some(format!("![]({IMAGE_SCHEME}{id})"))
+    });
+    let source = rewrite_placeholders(&source, "sketch", |inner| {
+        let id = inner.parse::<usize>().ok()?;
+        Some(format!("![]({SKETCH_SCHEME}{id})"))
+    });
+
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+
+    // Images are replaced by hand-written HTML, so collect each one's alt text first
+    let mut events = Vec::new();
+    let mut image: Option<(String, String, String)> = None;
+    for event in Parser::new_ext(&source, options) {
+        match (&mut image, event) {
+            (
+                None,
+                Event::Start(Tag::Image {
+                    dest_url, title, ..
+                }),
+            ) => image = Some((dest_url.to_string(), title.to_string(), String::new())),
+            (Some((_, _, alt)), Event::Text(text) | Event::Code(text)) => alt.push_str(&text),
+            (Some(_), Event::End(TagEnd::Image)) => {
+                let (dest, title, alt) = image.take().expect("inside an image");
+                events.push(Event::InlineHtml(
+                    markdown_image(&dest, &title, &alt, metadata).into(),
+                ));
+            }
+            (Some(_), _) => {}
+            (None, event) => events.push(event),
+        }
+    }
+
+    let mut body = String::new();
+    pulldown_cmark::html::push_html(&mut body, events.into_iter());
+    body
+}
+
+fn markdown_image(dest: &str, title: &str, alt: &str, metadata: &DocumentMetadata) -> String {
+    if let Some(sketch) = dest
+        .strip_prefix(SKETCH_SCHEME)
+        .and_then(|id| id.parse::<usize>().ok())
+        .and_then(|id| metadata.sketches.get(&id))
+    {
+        return sketch_svg(sketch);
+    }
+
+    let Some((id, image)) = dest
+        .strip_prefix(IMAGE_SCHEME)
+        .and_then(|id| id.parse::<usize>().ok())
+        .and_then(|id| ExportedImage::load(metadata, id).map(|image| (id, image)))
+    else {
+        // Remote or missing images keep pointing where the Markdown said
+        let mut tag = format!(
+            "<img src=\"{}\" alt=\"{}\"",
+            escape_xml(dest),
+            escape_xml(alt)
+        );
+        if !title.is_empty() {
+            tag.push_str(&format!(" title=\"{}\"", escape_xml(title)));
+        }
+        tag.push('>');
+        return tag;
+    };
+
+    let alt = [alt, image.alt.as_str()]
+        .into_iter()
+        .find(|alt| !alt.is_empty())
+        .map_or_else(|| format!("Image {id}"), str::to_string);
+    let caption = if title.is_empty() {
+        &image.title
+    } else {
+        title
+    };
+
+    if caption.is_empty() {
+        image_tag(&image, &alt)
+    } else {
+        figure(&image_tag(&image, &alt), caption)
+    }
+}
+
+fn image_tag(image: &ExportedImage, alt: &str) -> String {
+    let mut tag = format!(
+        "<img src=\"{}\" alt=\"{}\"",
+        image.data_uri(),
+        escape_xml(alt)
+    );
+    if image.width > 0 && image.height > 0 {
+        tag.push_str(&format!(
+            " width=\"{}\" height=\"{}\"",
+            image.width, image.height
+        ));
+    }
+    if !image.title.is_empty() {
+        tag.push_str(&format!(" title=\"{}\"", escape_xml(&image.title)));
+    }
+    tag.push('>');
+    tag
+}
+
+fn figure(content: &str, caption: &str) -> String {
+    if caption.is_empty() {
+        format!("<figure>{content}</figure>\n")
+    } else {
+        format!(
+            "<figure>{content}<figcaption>{}</figcaption></figure>\n",
+            escape_xml(caption)
+        )
+    }
+}
@@ -0,0 +1,164 @@
+﻿pub mod docx;
+pub mod html;
+pub mod odt;
+mod office;
+pub mod page;
+pub mod pdf;
+pub mod print;
+
+use crate::components::text_editor::{decode_still, flatten};
+use crate::document::{DocumentMetadata, Sketch, image_extension};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::fmt::Write;
+use std::io;
+
+/// Image pixels are shown at 96 DPI, like on screen.
+const PX_TO_PT: f32 = 0.75;
+
+/// An image as it should appear in an exported document, with its
+/// annotations burned in.
+pub struct ExportedImage {
+    pub bytes: Vec<u8>,
+    /// Size in pixels, zero when unknown.
+    pub width: u32,
+    pub height: u32,
+    pub alt: String,
+    pub title: String,
+}
+
+impl ExportedImage {
+    pub fn load(metadata: &DocumentMetadata, id: usize) -> Option<Self> {
+        let image = metadata.images.get(&id)?;
+        let bytes = match metadata.image_bytes(id)? {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::warn!("Skipping image {id} in export: invalid base64 data: {err}");
+                return None;
+            }
+        };
+        let bytes = match flatten(&bytes, &image.annotations) {
+            Ok(flattened) => flattened,
+            Err(err) => {
+                log::warn!("Exporting image {id} without its annotations: {err}");
+                bytes
+            }
+        };
+
+        let (alt, title) = image
+            .markdown
+            .as_ref()
+            .map(|markdown| (markdown.alt.clone(), markdown.title.clone()))
+            .unwrap_or_default();
+
+        Some(Self {
+            bytes,
+            width: image.width,
+            height: image.height,
+            alt,
+            title,
+        })
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match image_extension(&self.bytes) {
+            "svg" => "image/svg+xml",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "bmp" => "image/bmp",
+            _ => "image/png",
+        }
+    }
+
+    /// Display size in points. The stored size is zero when the image header
+    /// could not be read, then the image is decoded to measure it.
+    pub fn size_pt(&self) -> io::Result<(f32, f32)> {
+        let (width, height) = if self.width == 0 || self.height == 0 {
+            decode_still(&self.bytes)
+                .map_err(io::Error::other)?
+                .dimensions()
+        } else {
+            (self.width, self.height)
+        };
+        Ok((width as f32 * PX_TO_PT, height as f32 * PX_TO_PT))
+    }
+
+    pub fn data_uri(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.mime_type(),
+            BASE64.encode(&self.bytes)
+        )
+    }
+}
+
+/// The text of an image line around its placeholder, used as the caption of
+/// images in plain-text documents.
+pub fn line_caption(line: &str, tag: &str) -> String {
+    let Some(start) = line.find(&format!("[{tag}(")) else {
+        return line.trim().to_string();
+    };
+    let end = line[start..]
+        .find(")]")
+        .map_or(line.len(), |end| start + end + 2);
+    format!("{} {}", line[..start].trim(), line[end..].trim())
+        .trim()
+        .to_string()
+}
+
+/// Escapes text for use in HTML or XML content and attribute values.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Standalone SVG markup drawing the strokes of `sketch` on white paper.
+pub fn sketch_svg(sketch: &Sketch) -> String {
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}"><rect width="100%" height="100%" fill="white"/>"#,
+        sketch.width, sketch.height
+    );
+
+    for stroke in &sketch.strokes {
+        let [r, g, b, a] = stroke.color;
+        let color = format!("rgb({r},{g},{b})");
+        let opacity = a as f32 / 255.0;
+
+        if let [point] = stroke.points.as_slice() {
+            let _ = write!(
+                svg,
+                r#"<circle cx="{}" cy="{}" r="{}" fill="{color}" fill-opacity="{opacity}"/>"#,
+                point[0],
+                point[1],
+                stroke.width / 2.0
+            );
+            continue;
+        }
+
+        let points = stroke
+            .points
+            .iter()
+            .map(|point| format!("{},{}", point[0], point[1]))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = write!(
+            svg,
+            r#"<polyline points="{points}" fill="none" stroke="{color}" stroke-opacity="{opacity}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+            stroke.width
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
@@ -0,0 +1,166 @@
+﻿use super::office::{Block, Picture, blocks, escape_text};
+use crate::document::DocumentMetadata;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const MIME_TYPE: &str = "application/vnd.oasis.opendocument.text";
+
+const NAMESPACES: &str = r#"xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:style="urn:oasis:names:tc:opendocument:xmlns:style:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0" xmlns:draw="urn:oasis:names:tc:opendocument:xmlns:drawing:1.0" xmlns:fo="urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:svg="urn:oasis:names:tc:opendocument:xmlns:svg-compatible:1.0" office:version="1.3""#;
+
+/// Body text and heading styles, named the way LibreOffice names its own.
+const STYLES: &str = r#"<office:styles><style:default-style style:family="paragraph"><style:paragraph-properties fo:margin-bottom="0.1in"/><style:text-properties style:font-name="Liberation Sans" fo:font-family="'Liberation Sans'" fo:font-size="11pt"/></style:default-style><style:style style:name="Standard" style:family="paragraph" style:class="text"/><style:style style:name="Heading" style:family="paragraph" style:parent-style-name="Standard" style:class="text"><style:paragraph-properties fo:margin-top="0.17in" fo:keep-with-next="always"/><style:text-properties fo:font-weight="bold"/></style:style><style:style style:name="Heading_20_1" style:display-name="Heading 1" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="1"><style:text-properties fo:font-size="18pt"/></style:style><style:style style:name="Heading_20_2" style:display-name="Heading 2" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="2"><style:text-properties fo:font-size="15pt"/></style:style><style:style style:name="Heading_20_3" style:display-name="Heading 3" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="3"><style:text-properties fo:font-size="13pt"/></style:style><style:style style:name="Heading_20_4" style:display-name="Heading 4" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="4"><style:text-properties fo:font-size="12pt"/></style:style><style:style style:name="Heading_20_5" style:display-name="Heading 5" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="5"><style:text-properties fo:font-style="italic"/></style:style><style:style style:name="Heading_20_6" style:display-name="Heading 6" style:family="paragraph" style:parent-style-name="Heading" style:default-outline-level="6"><style:text-properties fo:font-style="italic" fo:font-weight="normal"/></style:style></office:styles>"#;
+
+/// L1 is a bullet list, L2 a numbered list.
+const LIST_STYLES: &str = r#"<office:automatic-styles><text:list-style style:name="L1"><text:list-level-style-bullet text:level="1" text:bullet-char="•"><style:list-level-properties text:list-level-position-and-space-mode="label-alignment"><style:list-level-label-alignment text:label-followed-by="listtab" text:list-tab-stop-position="0.5in" fo:text-indent="-0.25in" fo:margin-left="0.5in"/></style:list-level-properties></text:list-level-style-bullet></text:list-style><text:list-style style:name="L2"><text:list-level-style-number text:level="1" style:num-suffix="." style:num-format="1"><style:list-level-properties text:list-level-position-and-space-mode="label-alignment"><style:list-level-label-alignment text:label-followed-by="listtab" text:list-tab-stop-position="0.5in" fo:text-indent="-0.25in" fo:margin-left="0.5in"/></style:list-level-properties></text:list-level-style-number></text:list-style></office:automatic-styles>"#;
+
+/// Converts a note into an OpenDocument text file, one paragraph per line,
+/// with images and sketches as pictures anchored as characters at their
+/// display size.
+///
+/// Markdown headings and list items of `.md` documents become headings and
+/// lists.
+pub fn to_odt(text: &str, metadata: &DocumentMetadata, markdown: bool) -> io::Result<Vec<u8>> {
+    let mut body = String::new();
+    let mut pictures: Vec<(String, Picture)> = Vec::new();
+    // Consecutive list items share one list, `Some(ordered)` while one is open
+    let mut open_list: Option<bool> = None;
+
+    for block in blocks(text, metadata, markdown) {
+        let list = match &block {
+            Block::ListItem { ordered, .. } => Some(*ordered),
+            _ => None,
+        };
+        if open_list.is_some() && open_list != list {
+            body.push_str("</text:list>");
+            open_list = None;
+        }
+
+        match block {
+            Block::Paragraph(text) => {
+                let _ = write!(
+                    body,
+                    r#"<text:p text:style-name="Standard">{}</text:p>"#,
+                    paragraph_text(&text)
+                );
+            }
+            Block::Heading(level, text) => {
+                let _ = write!(
+                    body,
+                    r#"<text:h text:style-name="Heading_20_{level}" text:outline-level="{level}">{}</text:h>"#,
+                    paragraph_text(&text)
+                );
+            }
+            Block::ListItem { ordered, text } => {
+                if open_list.is_none() {
+                    let style = if ordered { "L2" } else { "L1" };
+                    let _ = write!(body, r#"<text:list text:style-name="{style}">"#);
+                    open_list = Some(ordered);
+                }
+                let _ = write!(
+                    body,
+                    r#"<text:list-item><text:p text:style-name="Standard">{}</text:p></text:list-item>"#,
+                    paragraph_text(&text)
+                );
+            }
+            Block::Picture(picture) => {
+                let index = pictures.len() + 1;
+                let path = format!("Pictures/image{index}.{}", picture.extension);
+                let description = escape_text(&picture.description);
+                let _ = write!(
+                    body,
+                    r#"<text:p text:style-name="Standard"><draw:frame draw:name="Image{index}" text:anchor-type="as-char" svg:width="{:.2}pt" svg:height="{:.2}pt" draw:z-index="0"><draw:image xlink:href="{path}" xlink:type="simple" xlink:show="embed" xlink:actuate="onLoad"/><svg:desc>{description}</svg:desc></draw:frame></text:p>"#,
+                    picture.width, picture.height
+                );
+                pictures.push((path, picture));
+            }
+        }
+    }
+    if open_list.is_some() {
+        body.push_str("</text:list>");
+    }
+
+    let content = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-content {NAMESPACES}>{LIST_STYLES}<office:body><office:text>{body}</office:text></office:body></office:document-content>"#
+    );
+    let styles = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-styles {NAMESPACES}>{STYLES}</office:document-styles>"#
+    );
+
+    let mut manifest = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.3"><manifest:file-entry manifest:full-path="/" manifest:version="1.3" manifest:media-type="application/vnd.oasis.opendocument.text"/><manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/><manifest:file-entry manifest:full-path="styles.xml" manifest:media-type="text/xml"/>"#,
+    );
+    for (path, picture) in &pictures {
+        let _ = write!(
+            manifest,
+            r#"<manifest:file-entry manifest:full-path="{path}" manifest:media-type="{}"/>"#,
+            picture.media_type()
+        );
+    }
+    manifest.push_str("</manifest:manifest>");
+
+    let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default();
+
+    // The mime type must come first and uncompressed, so it can be sniffed
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(MIME_TYPE.as_bytes())?;
+    for (name, part) in [
+        ("content.xml", &content),
+        ("styles.xml", &styles),
+        ("META-INF/manifest.xml", &manifest),
+    ] {
+        zip.start_file(name, deflated)?;
+        zip.write_all(part.as_bytes())?;
+    }
+    for (path, picture) in &pictures {
+        zip.start_file(path.as_str(), stored)?;
+        zip.write_all(&picture.bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Escapes paragraph text, spelling out tabs and runs of spaces that ODF
+/// would otherwise collapse.
+fn paragraph_text(text: &str) -> String {
+    let mut result = String::new();
+    let mut spaces = 0;
+    let mut at_start = true;
+
+    for c in text.chars() {
+        match c {
+            ' ' => spaces += 1,
+            '\t' => {
+                flush_spaces(&mut result, &mut spaces, at_start);
+                result.push_str("<text:tab/>");
+                at_start = false;
+            }
+            _ => {
+                flush_spaces(&mut result, &mut spaces, at_start);
+                result.push_str(&escape_text(c.encode_utf8(&mut [0; 4])));
+                at_start = false;
+            }
+        }
+    }
+    flush_spaces(&mut result, &mut spaces, at_start);
+    result
+}
+
+fn flush_spaces(result: &mut String, spaces: &mut usize, at_start: bool) {
+    match (*spaces, at_start) {
+        (0, _) => {}
+        (1, false) => result.push(' '),
+        (count, true) => {
+            let _ = write!(result, r#"<text:s text:c="{count}"/>"#);
+        }
+        (count, false) => {
+            let _ = write!(result, r#" <text:s text:c="{}"/>"#, count - 1);
+        }
+    }
+    *spaces = 0;
+}
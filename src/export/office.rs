@@ -0,0 +1,169 @@
+﻿use super::{ExportedImage, PX_TO_PT, escape_xml, line_caption, sketch_svg};
+use crate::components::text_editor::{decode_still, extract_placeholder_id};
+use crate::document::{DocumentMetadata, image_extension};
+use std::io;
+
+/// Widest picture placed in a word processor document, in points: the text
+/// width of a Letter page with one-inch margins.
+const MAX_PICTURE_WIDTH: f32 = 468.0;
+
+/// A line of a note as a word processor paragraph.
+pub enum Block {
+    Paragraph(String),
+    /// Markdown heading with its level from 1 to 6.
+    Heading(u8, String),
+    /// Markdown list item.
+    ListItem {
+        ordered: bool,
+        text: String,
+    },
+    Picture(Picture),
+}
+
+/// An image or sketch in a format word processors read.
+pub struct Picture {
+    pub bytes: Vec<u8>,
+    pub extension: &'static str,
+    /// Display size in points.
+    pub width: f32,
+    pub height: f32,
+    pub description: String,
+}
+
+impl Picture {
+    pub fn media_type(&self) -> &'static str {
+        match self.extension {
+            "jpg" => "image/jpeg",
+            "gif" => "image/gif",
+            _ => "image/png",
+        }
+    }
+}
+
+/// Splits a note into paragraphs and pictures. Text next to an image
+/// placeholder becomes a paragraph above the picture.
+pub fn blocks(text: &str, metadata: &DocumentMetadata, markdown: bool) -> Vec<Block> {
+    let mut blocks = Vec::new();
+
+    for line in text.lines() {
+        let picture = if let Some(id) = extract_placeholder_id(line, "image") {
+            ExportedImage::load(metadata, id).map(|image| (picture(image), "image"))
+        } else {
+            extract_placeholder_id(line, "sketch")
+                .and_then(|id| metadata.sketches.get(&id))
+                .map(|sketch| {
+                    let svg = sketch_svg(sketch).into_bytes();
+                    let image = ExportedImage {
+                        bytes: svg,
+                        width: sketch.width as u32,
+                        height: sketch.height as u32,
+                        alt: String::new(),
+                        title: String::new(),
+                    };
+                    (picture(image), "sketch")
+                })
+        };
+
+        let Some((picture, tag)) = picture else {
+            blocks.push(text_block(line, markdown));
+            continue;
+        };
+
+        let caption = line_caption(line, tag);
+        if !caption.is_empty() {
+            blocks.push(text_block(&caption, markdown));
+        }
+        match picture {
+            Ok(mut picture) => {
+                if picture.description.is_empty() {
+                    picture.description = caption;
+                }
+                blocks.push(Block::Picture(picture));
+            }
+            Err(err) => log::warn!("Leaving a {tag} out of the document: {err}"),
+        }
+    }
+
+    blocks
+}
+
+fn text_block(line: &str, markdown: bool) -> Block {
+    if !markdown {
+        return Block::Paragraph(line.to_string());
+    }
+
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    if (1..=6).contains(&hashes)
+        && let Some(heading) = line[hashes..].strip_prefix(' ')
+    {
+        return Block::Heading(hashes as u8, heading.trim().to_string());
+    }
+
+    let item = line.trim_start();
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(text) = item.strip_prefix(bullet) {
+            return Block::ListItem {
+                ordered: false,
+                text: text.to_string(),
+            };
+        }
+    }
+    let digits = item.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0
+        && let Some(text) = item[digits..]
+            .strip_prefix(". ")
+            .or_else(|| item[digits..].strip_prefix(") "))
+    {
+        return Block::ListItem {
+            ordered: true,
+            text: text.to_string(),
+        };
+    }
+
+    Block::Paragraph(line.to_string())
+}
+
+/// Keeps PNG, JPEG and GIF data as it is and converts anything else, such as
+/// SVG or WebP, to PNG. The picture is scaled down to fit the text width.
+fn picture(image: ExportedImage) -> io::Result<Picture> {
+    let description = if image.alt.is_empty() {
+        image.title.clone()
+    } else {
+        image.alt.clone()
+    };
+
+    let (bytes, extension, (width, height)) = match image_extension(&image.bytes) {
+        extension @ ("png" | "jpg" | "gif") => {
+            let size = image.size_pt()?;
+            (image.bytes, extension, size)
+        }
+        _ => {
+            let decoded = decode_still(&image.bytes).map_err(io::Error::other)?;
+            let mut png = Vec::new();
+            decoded
+                .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .map_err(io::Error::other)?;
+            let (width, height) = decoded.dimensions();
+            (
+                png,
+                "png",
+                (width as f32 * PX_TO_PT, height as f32 * PX_TO_PT),
+            )
+        }
+    };
+
+    let scale = (MAX_PICTURE_WIDTH / width).min(1.0);
+    Ok(Picture {
+        bytes,
+        extension,
+        width: width * scale,
+        height: height * scale,
+        description,
+    })
+}
+
+/// Escapes text for XML, dropping control characters XML cannot contain.
+pub fn escape_text(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    escape_xml(&text)
+}
@@ -0,0 +1,564 @@
+﻿use super::{ExportedImage, line_caption};
+use crate::components::text_editor::extract_placeholder_id;
+use crate::document::{DocumentMetadata, Sketch};
+use crate::fonts::{self, FamilyFont};
+use ab_glyph::{Font, FontArc};
+use std::io;
+
+/// Points per millimetre.
+pub const MM_TO_PT: f32 = 72.0 / 25.4;
+const LINE_SPACING: f32 = 1.25;
+/// Vertical space around images and sketches, in points.
+const BLOCK_SPACING: f32 = 6.0;
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    A4,
+    A5,
+    Letter,
+    Legal,
+}
+
+impl PageSize {
+    pub const ALL: [PageSize; 4] = [Self::A4, Self::A5, Self::Letter, Self::Legal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::A4 => "A4",
+            Self::A5 => "A5",
+            Self::Letter => "Letter",
+            Self::Legal => "Legal",
+        }
+    }
+
+    /// Portrait width and height in millimetres.
+    pub fn size_mm(self) -> (f32, f32) {
+        match self {
+            Self::A4 => (210.0, 297.0),
+            Self::A5 => (148.0, 210.0),
+            Self::Letter => (215.9, 279.4),
+            Self::Legal => (215.9, 355.6),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+/// egui's font families, so printed output matches the editor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFont {
+    Monospace,
+    Proportional,
+}
+
+impl PageFont {
+    pub const ALL: [PageFont; 2] = [Self::Monospace, Self::Proportional];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Monospace => "Monospace",
+            Self::Proportional => "Proportional",
+        }
+    }
+
+    /// The fonts egui uses for this family, in fallback order.
+    pub fn fonts(self) -> io::Result<Vec<FamilyFont>> {
+        let family = match self {
+            Self::Monospace => egui::FontFamily::Monospace,
+            Self::Proportional => egui::FontFamily::Proportional,
+        };
+
+        let fonts = fonts::family_fonts(&family);
+        if fonts.is_empty() {
+            return Err(io::Error::other(format!(
+                "no {} font available",
+                self.name()
+            )));
+        }
+        Ok(fonts)
+    }
+}
+
+/// Paper and text settings for printing and PDF export.
+#[derive(Debug, Clone)]
+pub struct PageSetup {
+    pub page_size: PageSize,
+    pub orientation: Orientation,
+    pub margin_mm: f32,
+    pub font: PageFont,
+    pub font_size: f32,
+    pub line_numbers: bool,
+    /// Printed at the top and bottom of every page, see [`expand_header`].
+    pub header: String,
+    pub footer: String,
+}
+
+impl Default for PageSetup {
+    fn default() -> Self {
+        Self {
+            page_size: PageSize::A4,
+            orientation: Orientation::Portrait,
+            margin_mm: 20.0,
+            font: PageFont::Monospace,
+            font_size: 10.0,
+            line_numbers: false,
+            header: "&f".to_string(),
+            footer: "Page &p".to_string(),
+        }
+    }
+}
+
+impl PageSetup {
+    /// Page width and height in points.
+    pub fn page_size_pt(&self) -> (f32, f32) {
+        let (width, height) = self.page_size.size_mm();
+        let (width, height) = match self.orientation {
+            Orientation::Portrait => (width, height),
+            Orientation::Landscape => (height, width),
+        };
+        (width * MM_TO_PT, height * MM_TO_PT)
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("page_setup")
+            .num_columns(2)
+            .spacing([12.0, 6.0])
+            .show(ui, |ui| {
+                ui.label("Page size");
+                egui::ComboBox::from_id_salt("page_size")
+                    .selected_text(self.page_size.name())
+                    .show_ui(ui, |ui| {
+                        for size in PageSize::ALL {
+                            ui.selectable_value(&mut self.page_size, size, size.name());
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Orientation");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.orientation, Orientation::Portrait, "Portrait");
+                    ui.radio_value(&mut self.orientation, Orientation::Landscape, "Landscape");
+                });
+                ui.end_row();
+
+                ui.label("Margins");
+                ui.add(egui::Slider::new(&mut self.margin_mm, 5.0..=50.0).suffix(" mm"));
+                ui.end_row();
+
+                ui.label("Font");
+                egui::ComboBox::from_id_salt("page_font")
+                    .selected_text(self.font.name())
+                    .show_ui(ui, |ui| {
+                        for font in PageFont::ALL {
+                            ui.selectable_value(&mut self.font, font, font.name());
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Font size");
+                ui.add(egui::Slider::new(&mut self.font_size, 6.0..=24.0).suffix(" pt"));
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(&mut self.line_numbers, "Line numbers");
+                ui.end_row();
+
+                let codes = "&f file name, &d date, &t time, &p page number, &n page count";
+                ui.label("Header");
+                ui.text_edit_singleline(&mut self.header)
+                    .on_hover_text(codes);
+                ui.end_row();
+
+                ui.label("Footer");
+                ui.text_edit_singleline(&mut self.footer)
+                    .on_hover_text(codes);
+                ui.end_row();
+            });
+    }
+}
+
+/// Something drawn on a page, positioned in points from the top-left corner.
+pub enum PageItem {
+    Text {
+        /// Left end of the baseline.
+        position: [f32; 2],
+        text: String,
+        /// Index into [`PageLayout::fonts`].
+        font: usize,
+        /// Drawn grey, for line numbers.
+        weak: bool,
+    },
+    Image {
+        /// Left, top, width and height.
+        rect: [f32; 4],
+        /// Index into [`PageLayout::images`].
+        image: usize,
+    },
+    Sketch {
+        rect: [f32; 4],
+        sketch: Sketch,
+    },
+}
+
+#[derive(Default)]
+pub struct Page {
+    pub items: Vec<PageItem>,
+}
+
+/// A document broken into pages.
+pub struct PageLayout {
+    pub width: f32,
+    pub height: f32,
+    pub font_size: f32,
+    /// Distance from the top of a text row to its baseline.
+    pub ascent: f32,
+    /// The font family's fonts in fallback order. Each text item uses the
+    /// first one with glyphs for its characters.
+    pub fonts: Vec<&'static [u8]>,
+    pub pages: Vec<Page>,
+    pub images: Vec<ExportedImage>,
+}
+
+/// Wraps the text to the page width and distributes it, together with the
+/// images and sketches, over pages.
+///
+/// Images are scaled down to fit the page and always start a new page rather
+/// than being split. `title` fills in the `&f` code of the header and footer.
+pub fn layout(
+    title: &str,
+    text: &str,
+    metadata: &DocumentMetadata,
+    setup: &PageSetup,
+) -> io::Result<PageLayout> {
+    let (font_data, fonts): (Vec<_>, Vec<_>) = setup
+        .font
+        .fonts()?
+        .into_iter()
+        .map(|font| (font.data, font.font))
+        .unzip();
+    let (width, height) = setup.page_size_pt();
+    let margin = setup.margin_mm * MM_TO_PT;
+    let line_height = setup.font_size * LINE_SPACING;
+
+    // The header and footer take a row each, plus a blank row towards the text
+    let top = margin
+        + if setup.header.is_empty() {
+            0.0
+        } else {
+            2.0 * line_height
+        };
+    let bottom = height
+        - margin
+        - if setup.footer.is_empty() {
+            0.0
+        } else {
+            2.0 * line_height
+        };
+
+    let mut layout = Layouter {
+        fonts,
+        font_size: setup.font_size,
+        line_height,
+        top,
+        bottom,
+        y: top,
+        pages: vec![Page::default()],
+        images: Vec::new(),
+        gutter: 0.0,
+        text_left: margin,
+        text_width: 0.0,
+    };
+
+    let line_count = text.lines().count().max(1);
+    if setup.line_numbers {
+        let digits = line_count.to_string().len() + 1;
+        layout.gutter = layout.text_width_of(&"0".repeat(digits));
+    }
+    layout.text_left = margin + layout.gutter;
+    layout.text_width = (width - margin - layout.text_left).max(layout.font_size);
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line_number = setup.line_numbers.then(|| (line_idx + 1).to_string());
+
+        if let Some((image, (width, height))) = extract_placeholder_id(line, "image")
+            .and_then(|id| ExportedImage::load(metadata, id))
+            .and_then(|image| match image.size_pt() {
+                Ok(size) => Some((image, size)),
+                Err(err) => {
+                    log::warn!("Printing an image placeholder as text: {err}");
+                    None
+                }
+            })
+        {
+            let number = layout.caption(&line_caption(line, "image"), line_number.as_deref());
+            layout.images.push(image);
+            let index = layout.images.len() - 1;
+            layout.block([width, height], number, |rect| PageItem::Image {
+                rect,
+                image: index,
+            });
+        } else if let Some(sketch) =
+            extract_placeholder_id(line, "sketch").and_then(|id| metadata.sketches.get(&id))
+        {
+            let number = layout.caption(&line_caption(line, "sketch"), line_number.as_deref());
+            let sketch = sketch.clone();
+            layout.block([sketch.width, sketch.height], number, |rect| {
+                PageItem::Sketch { rect, sketch }
+            });
+        } else {
+            layout.paragraph(line, line_number.as_deref());
+        }
+    }
+
+    let now = chrono::Local::now();
+    let date = now.format("%x").to_string();
+    let time = now.format("%X").to_string();
+    let ascent = layout.ascent();
+    let page_count = layout.pages.len();
+    for page_idx in 0..page_count {
+        let codes = HeaderCodes {
+            file_name: title,
+            date: &date,
+            time: &time,
+            page: page_idx + 1,
+            page_count,
+        };
+        let rows = [
+            (&setup.header, margin + ascent),
+            (&setup.footer, height - margin - line_height + ascent),
+        ];
+        for (template, baseline) in rows {
+            let text = expand_header(template, &codes);
+            if text.is_empty() {
+                continue;
+            }
+            let x = (width - layout.text_width_of(&text)) / 2.0;
+            let items = layout.text_items([x, baseline], &text, true);
+            layout.pages[page_idx].items.extend(items);
+        }
+    }
+
+    Ok(PageLayout {
+        width,
+        height,
+        font_size: setup.font_size,
+        ascent,
+        fonts: font_data,
+        pages: layout.pages,
+        images: layout.images,
+    })
+}
+
+/// Values substituted into header and footer templates.
+pub struct HeaderCodes<'a> {
+    pub file_name: &'a str,
+    pub date: &'a str,
+    pub time: &'a str,
+    pub page: usize,
+    pub page_count: usize,
+}
+
+/// Expands the `&f`, `&d`, `&t`, `&p` and `&n` codes of a header or footer
+/// template, like Notepad's page setup. `&&` is a literal ampersand.
+pub fn expand_header(template: &str, codes: &HeaderCodes) -> String {
+    let mut expanded = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '&' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f' | 'F') => expanded.push_str(codes.file_name),
+            Some('d' | 'D') => expanded.push_str(codes.date),
+            Some('t' | 'T') => expanded.push_str(codes.time),
+            Some('p' | 'P') => expanded.push_str(&codes.page.to_string()),
+            Some('n' | 'N') => expanded.push_str(&codes.page_count.to_string()),
+            Some(other) => expanded.push(other),
+            None => expanded.push('&'),
+        }
+    }
+    expanded.trim().to_string()
+}
+
+struct Layouter {
+    fonts: Vec<FontArc>,
+    font_size: f32,
+    line_height: f32,
+    top: f32,
+    bottom: f32,
+    /// Top of the next row on the current page.
+    y: f32,
+    pages: Vec<Page>,
+    images: Vec<ExportedImage>,
+    gutter: f32,
+    text_left: f32,
+    text_width: f32,
+}
+
+impl Layouter {
+    fn text_width_of(&self, text: &str) -> f32 {
+        text.chars().map(|c| self.char_width(c)).sum()
+    }
+
+    fn char_width(&self, c: char) -> f32 {
+        let font = &self.fonts[fonts::font_for(&self.fonts, c)];
+        let units_per_em = font.units_per_em().unwrap_or(1000.0);
+        font.h_advance_unscaled(font.glyph_id(c)) / units_per_em * self.font_size
+    }
+
+    /// The ascent of the primary font, which sets the baseline of every row.
+    fn ascent(&self) -> f32 {
+        let font = &self.fonts[0];
+        font.ascent_unscaled() / font.units_per_em().unwrap_or(1000.0) * self.font_size
+    }
+
+    /// Text items for `text` starting at `position`, one per run of
+    /// characters drawn with the same font.
+    fn text_items(&self, position: [f32; 2], text: &str, weak: bool) -> Vec<PageItem> {
+        let mut items = Vec::new();
+        let [mut x, y] = position;
+        let mut run = String::new();
+        let mut run_font = None;
+        let mut run_x = x;
+        for c in text.chars() {
+            let font = fonts::font_for(&self.fonts, c);
+            if run_font != Some(font) {
+                if let Some(font) = run_font {
+                    items.push(PageItem::Text {
+                        position: [run_x, y],
+                        text: std::mem::take(&mut run),
+                        font,
+                        weak,
+                    });
+                }
+                run_font = Some(font);
+                run_x = x;
+            }
+            run.push(c);
+            x += self.char_width(c);
+        }
+        if let Some(font) = run_font {
+            items.push(PageItem::Text {
+                position: [run_x, y],
+                text: run,
+                font,
+                weak,
+            });
+        }
+        items
+    }
+
+    fn page(&mut self) -> &mut Page {
+        self.pages.last_mut().expect("there is always a page")
+    }
+
+    fn new_page(&mut self) {
+        self.pages.push(Page::default());
+        self.y = self.top;
+    }
+
+    /// Places one row of text, with the line number in the gutter if given.
+    fn row(&mut self, text: String, line_number: Option<&str>) {
+        if self.y + self.line_height > self.bottom && self.y > self.top {
+            self.new_page();
+        }
+
+        let baseline = self.y + self.ascent();
+
+        if let Some(number) = line_number {
+            let x = self.text_left - self.gutter * 0.25 - self.text_width_of(number);
+            let items = self.text_items([x, baseline], number, true);
+            self.page().items.extend(items);
+        }
+        let items = self.text_items([self.text_left, baseline], &text, false);
+        self.page().items.extend(items);
+        self.y += self.line_height;
+    }
+
+    /// Word-wraps a source line to the text width.
+    ///
+    /// Widths are summed per character, so the row is measured as it grows
+    /// instead of again for every word.
+    fn paragraph(&mut self, line: &str, line_number: Option<&str>) {
+        let line = line.replace('\t', &" ".repeat(TAB_WIDTH));
+        let mut line_number = line_number;
+
+        let mut row = String::new();
+        let mut row_width = 0.0;
+        for word in line.split_inclusive(' ') {
+            let trimmed = word.trim_end();
+            let trimmed_width = self.text_width_of(trimmed);
+            if row_width + trimmed_width <= self.text_width {
+                row.push_str(word);
+                row_width += trimmed_width + self.text_width_of(&word[trimmed.len()..]);
+                continue;
+            }
+            if !row.is_empty() {
+                self.row(
+                    std::mem::take(&mut row).trim_end().to_string(),
+                    line_number.take(),
+                );
+                row_width = 0.0;
+            }
+
+            // Words wider than the page are broken between characters
+            for c in word.chars() {
+                let width = self.char_width(c);
+                if !row.is_empty() && row_width + width > self.text_width {
+                    self.row(std::mem::take(&mut row), line_number.take());
+                    row_width = 0.0;
+                }
+                row.push(c);
+                row_width += width;
+            }
+        }
+        self.row(row.trim_end().to_string(), line_number);
+    }
+
+    /// The text around an image placeholder, shown above the image.
+    ///
+    /// Returns the line number when there is no caption to carry it, for the
+    /// image itself to show.
+    fn caption<'a>(&mut self, caption: &str, line_number: Option<&'a str>) -> Option<&'a str> {
+        if caption.is_empty() {
+            return line_number;
+        }
+        self.paragraph(caption, line_number);
+        None
+    }
+
+    /// Places an image or sketch of `size` points, scaled down to fit the page,
+    /// with `line_number` in the gutter next to its top.
+    fn block(
+        &mut self,
+        size: [f32; 2],
+        line_number: Option<&str>,
+        item: impl FnOnce([f32; 4]) -> PageItem,
+    ) {
+        let max_height = self.bottom - self.top - 2.0 * BLOCK_SPACING;
+        let scale = (self.text_width / size[0])
+            .min(max_height / size[1])
+            .min(1.0);
+        let scale = if scale.is_finite() { scale } else { 1.0 };
+        let (width, height) = (size[0] * scale, size[1] * scale);
+
+        if self.y + BLOCK_SPACING + height > self.bottom - BLOCK_SPACING && self.y > self.top {
+            self.new_page();
+        }
+
+        let rect = [self.text_left, self.y + BLOCK_SPACING, width, height];
+        if let Some(number) = line_number {
+            let x = self.text_left - self.gutter * 0.25 - self.text_width_of(number);
+            let items = self.text_items([x, rect[1] + self.ascent()], number, true);
+            self.page().items.extend(items);
+        }
+        self.page().items.push(item(rect));
+        self.y += BLOCK_SPACING + height + BLOCK_SPACING;
+    }
+}
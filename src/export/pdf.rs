@@ -0,0 +1,203 @@
+﻿use super::page::{MM_TO_PT, PageItem, PageLayout};
+use crate::components::text_editor::decode_still;
+use crate::document::Sketch;
+use printpdf::path::{PaintMode, WindingOrder};
+use printpdf::{
+    Color, ColorBits, ColorSpace, Image, ImageTransform, ImageXObject, IndirectFontRef,
+    LineCapStyle, LineJoinStyle, Mm, PdfDocument, PdfLayerReference, Point, Polygon, Px, Rgb,
+};
+use std::io;
+
+/// Renders a laid-out document into PDF bytes, embedding the layout's fonts
+/// that its text uses.
+pub fn to_pdf(title: &str, layout: &PageLayout) -> io::Result<Vec<u8>> {
+    let (doc, first_page, first_layer) =
+        PdfDocument::new(title, mm(layout.width), mm(layout.height), "Layer 1");
+
+    // Fallback fonts can be large, so only those with text are embedded
+    let mut fonts: Vec<Option<IndirectFontRef>> = vec![None; layout.fonts.len()];
+    for page in &layout.pages {
+        for item in &page.items {
+            if let PageItem::Text { font, .. } = item
+                && fonts[*font].is_none()
+            {
+                fonts[*font] = Some(
+                    doc.add_external_font(layout.fonts[*font])
+                        .map_err(io::Error::other)?,
+                );
+            }
+        }
+    }
+
+    // Decode each image once, even if it appears on several pages
+    let images: Vec<Option<ImageXObject>> = layout
+        .images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| match decode_still(&image.bytes) {
+            Ok(rgba) => Some(image_xobject(&rgba)),
+            Err(err) => {
+                log::warn!("Leaving image {index} out of the PDF: {err}");
+                None
+            }
+        })
+        .collect();
+
+    for (page_idx, page) in layout.pages.iter().enumerate() {
+        let layer = if page_idx == 0 {
+            doc.get_page(first_page).get_layer(first_layer)
+        } else {
+            let (page, layer) = doc.add_page(mm(layout.width), mm(layout.height), "Layer 1");
+            doc.get_page(page).get_layer(layer)
+        };
+        let canvas = Canvas {
+            layer,
+            height: layout.height,
+        };
+
+        for item in &page.items {
+            match item {
+                PageItem::Text {
+                    position,
+                    text,
+                    font,
+                    weak,
+                } => {
+                    if let Some(Some(font)) = fonts.get(*font) {
+                        canvas.text(text, *position, layout.font_size, *weak, font);
+                    }
+                }
+                PageItem::Image { rect, image } => {
+                    if let Some(Some(image)) = images.get(*image) {
+                        canvas.image(image.clone(), *rect);
+                    }
+                }
+                PageItem::Sketch { rect, sketch } => canvas.sketch(sketch, *rect),
+            }
+        }
+    }
+
+    doc.save_to_bytes().map_err(io::Error::other)
+}
+
+fn mm(points: f32) -> Mm {
+    Mm(points / MM_TO_PT)
+}
+
+fn rgb(color: [u8; 3]) -> Color {
+    Color::Rgb(Rgb::new(
+        color[0] as f32 / 255.0,
+        color[1] as f32 / 255.0,
+        color[2] as f32 / 255.0,
+        None,
+    ))
+}
+
+/// PDF images have no alpha here, so transparency is composited onto white
+/// paper.
+fn image_xobject(rgba: &image::RgbaImage) -> ImageXObject {
+    let image_data = rgba
+        .pixels()
+        .flat_map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            let over_white = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+            [over_white(r), over_white(g), over_white(b)]
+        })
+        .collect();
+
+    ImageXObject {
+        width: Px(rgba.width() as usize),
+        height: Px(rgba.height() as usize),
+        color_space: ColorSpace::Rgb,
+        bits_per_component: ColorBits::Bit8,
+        interpolate: true,
+        image_data,
+        image_filter: None,
+        smask: None,
+        clipping_bbox: None,
+    }
+}
+
+/// A page layer addressed in layout points from the top-left corner.
+struct Canvas {
+    layer: PdfLayerReference,
+    height: f32,
+}
+
+impl Canvas {
+    fn point(&self, x: f32, y: f32) -> Point {
+        Point::new(mm(x), mm(self.height - y))
+    }
+
+    fn text(&self, text: &str, position: [f32; 2], size: f32, weak: bool, font: &IndirectFontRef) {
+        let color = if weak { [128; 3] } else { [0; 3] };
+        self.layer.set_fill_color(rgb(color));
+        self.layer.use_text(
+            text,
+            size,
+            mm(position[0]),
+            mm(self.height - position[1]),
+            font,
+        );
+    }
+
+    fn image(&self, image: ImageXObject, rect: [f32; 4]) {
+        let [x, y, width, height] = rect;
+        let scale_x = width / image.width.0.max(1) as f32;
+        let scale_y = height / image.height.0.max(1) as f32;
+
+        Image::from(image).add_to_layer(
+            self.layer.clone(),
+            ImageTransform {
+                translate_x: Some(mm(x)),
+                translate_y: Some(mm(self.height - y - height)),
+                scale_x: Some(scale_x),
+                scale_y: Some(scale_y),
+                // One image pixel per point before scaling
+                dpi: Some(72.0),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Draws the sketch paper and its strokes as vector paths.
+    fn sketch(&self, sketch: &Sketch, rect: [f32; 4]) {
+        let [x, y, width, height] = rect;
+        let scale = sketch.scale_to_width(width);
+
+        self.layer.set_fill_color(rgb([255; 3]));
+        self.layer.set_outline_color(rgb([200; 3]));
+        self.layer.set_outline_thickness(0.5);
+        self.layer.add_polygon(Polygon {
+            rings: vec![vec![
+                (self.point(x, y), false),
+                (self.point(x + width, y), false),
+                (self.point(x + width, y + height), false),
+                (self.point(x, y + height), false),
+            ]],
+            mode: PaintMode::FillStroke,
+            winding_order: WindingOrder::NonZero,
+        });
+
+        self.layer.set_line_cap_style(LineCapStyle::Round);
+        self.layer.set_line_join_style(LineJoinStyle::Round);
+        for stroke in &sketch.strokes {
+            let [r, g, b, _] = stroke.color;
+            self.layer.set_outline_color(rgb([r, g, b]));
+            self.layer.set_outline_thickness(stroke.width * scale);
+
+            let mut points: Vec<(Point, bool)> = stroke
+                .placed_points([x, y], scale)
+                .map(|[x, y]| (self.point(x, y), false))
+                .collect();
+            // A dot is a zero-length line with round caps
+            if let [single] = points.as_slice() {
+                points.push(*single);
+            }
+            self.layer.add_line(printpdf::Line {
+                points,
+                is_closed: false,
+            });
+        }
+    }
+}
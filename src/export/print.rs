@@ -0,0 +1,199 @@
+﻿use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+const TEMP_PREFIX: &str = "notula-print-";
+/// Spooled files older than this have been read by their print job.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Where a document is printed and how many times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintJob {
+    /// `None` for the system's default printer.
+    pub printer: Option<String>,
+    pub copies: u32,
+}
+
+impl Default for PrintJob {
+    fn default() -> Self {
+        Self {
+            printer: None,
+            copies: 1,
+        }
+    }
+}
+
+impl PrintJob {
+    /// Printer and copies settings. `printers` is `None` while the list of
+    /// installed printers is still being read.
+    pub fn ui(&mut self, ui: &mut egui::Ui, printers: Option<&[String]>) {
+        egui::Grid::new("print_job")
+            .num_columns(2)
+            .spacing([12.0, 6.0])
+            .show(ui, |ui| {
+                ui.label("Printer");
+                let selected = self.printer.as_deref().unwrap_or("Default printer");
+                egui::ComboBox::from_id_salt("printer")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.printer, None, "Default printer");
+                        match printers {
+                            Some(printers) => {
+                                for printer in printers {
+                                    ui.selectable_value(
+                                        &mut self.printer,
+                                        Some(printer.clone()),
+                                        printer,
+                                    );
+                                }
+                            }
+                            None => {
+                                ui.spinner();
+                            }
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Copies");
+                ui.add(egui::DragValue::new(&mut self.copies).range(1..=99));
+                ui.end_row();
+            });
+    }
+}
+
+/// Hands a PDF to the system print spooler.
+///
+/// The PDF is written to the temporary directory first, since every spooler
+/// takes a file: `lp` (CUPS) on Linux and macOS, the shell's print verb on
+/// Windows.
+pub fn print_pdf(pdf: &[u8], name: &str, job: &PrintJob) -> io::Result<()> {
+    remove_stale_files();
+
+    let path = std::env::temp_dir().join(format!("{TEMP_PREFIX}{name}.pdf"));
+    fs::write(&path, pdf)?;
+    let result = spool(&path, job);
+
+    // lp and lpr hand the data to the spooler before returning, the Windows
+    // print verb reads the file later, so it is swept up by a later print
+    #[cfg(not(windows))]
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Deletes files left behind by earlier print jobs.
+fn remove_stale_files() {
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let is_ours = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(TEMP_PREFIX) && name.ends_with(".pdf"));
+        let is_stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_AFTER);
+        if is_ours && is_stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Names of the installed printers. Reading them runs the spooler's tools,
+/// so call this off the UI thread.
+pub fn printers() -> Vec<String> {
+    #[cfg(windows)]
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            "Get-Printer | Select-Object -ExpandProperty Name",
+        ])
+        .output();
+    #[cfg(not(windows))]
+    let output = Command::new("lpstat").arg("-e").output();
+
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Ok(output) => {
+            log::warn!("Failed to list printers ({})", output.status);
+            Vec::new()
+        }
+        Err(err) => {
+            log::warn!("Failed to list printers: {err}");
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(windows)]
+fn spool(path: &Path, job: &PrintJob) -> io::Result<()> {
+    let quote = |text: &str| text.replace('\'', "''");
+    let path = quote(&path.display().to_string());
+    let command = match &job.printer {
+        Some(printer) => format!(
+            "Start-Process -FilePath '{path}' -Verb PrintTo -ArgumentList '\"{}\"'",
+            quote(printer)
+        ),
+        None => format!("Start-Process -FilePath '{path}' -Verb Print"),
+    };
+
+    // The print verbs have no copies option
+    for _ in 0..job.copies.max(1) {
+        run(Command::new("powershell").args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &command,
+        ]))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn spool(path: &Path, job: &PrintJob) -> io::Result<()> {
+    let copies = job.copies.max(1).to_string();
+
+    let mut lp = Command::new("lp");
+    if let Some(printer) = &job.printer {
+        lp.arg("-d").arg(printer);
+    }
+    lp.arg("-n").arg(&copies).arg(path);
+
+    // Fall back to BSD lpr where the System V lp command is missing
+    match run(&mut lp) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut lpr = Command::new("lpr");
+            if let Some(printer) = &job.printer {
+                lpr.arg("-P").arg(printer);
+            }
+            run(lpr.arg(format!("-#{copies}")).arg(path))
+        }
+        result => result,
+    }
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(io::Error::other(format!(
+        "print spooler failed ({}): {}",
+        output.status,
+        stderr.trim()
+    )))
+}
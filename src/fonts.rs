@@ -0,0 +1,105 @@
+﻿use ab_glyph::{Font, FontArc, FontRef};
+use eframe::egui::{self, FontData, FontDefinitions, FontFamily};
+use std::sync::{Arc, OnceLock};
+
+/// System fonts covering Chinese, Japanese and Korean, which egui's built-in
+/// fonts lack. The first one found is used.
+const CJK_FONTS: [&str; 12] = [
+    "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/noto-cjk/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/google-noto-cjk/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/truetype/wqy/wqy-microhei.ttc",
+    "/usr/share/fonts/truetype/droid/DroidSansFallbackFull.ttf",
+    "C:\\Windows\\Fonts\\msyh.ttc",
+    "C:\\Windows\\Fonts\\msgothic.ttc",
+    "C:\\Windows\\Fonts\\malgun.ttf",
+    "/System/Library/Fonts/PingFang.ttc",
+    "/System/Library/Fonts/Hiragino Sans GB.ttc",
+    "/System/Library/Fonts/Supplemental/Arial Unicode.ttf",
+    "/Library/Fonts/Arial Unicode.ttf",
+];
+
+static DEFINITIONS: OnceLock<FontDefinitions> = OnceLock::new();
+
+/// Installs [`definitions`] in `ctx` once they have been read on a background
+/// thread, so the CJK font file, often tens of megabytes, does not hold up the
+/// first frame.
+pub fn install_fallback_fonts(ctx: &egui::Context) {
+    let ctx = ctx.clone();
+    std::thread::spawn(move || {
+        ctx.set_fonts(definitions().clone());
+        ctx.request_repaint();
+    });
+}
+
+/// egui's fonts with a system CJK font as the last fallback of both font
+/// families, so text typed with an input method does not show up as boxes.
+/// The font file is read by the first caller, later ones wait for it.
+pub fn definitions() -> &'static FontDefinitions {
+    DEFINITIONS.get_or_init(load_definitions)
+}
+
+/// The fonts of `family` in fallback order, for drawing text outside egui
+/// the way the editor shows it.
+pub fn family_fonts(family: &FontFamily) -> Vec<FamilyFont> {
+    let definitions = definitions();
+    let Some(names) = definitions.families.get(family) else {
+        return Vec::new();
+    };
+    names
+        .iter()
+        .filter_map(|name| {
+            let data = definitions.font_data.get(name)?;
+            match FontRef::try_from_slice_and_index(&data.font, data.index) {
+                Ok(font) => Some(FamilyFont {
+                    data: &data.font,
+                    font: FontArc::new(font),
+                }),
+                Err(err) => {
+                    log::warn!("Skipping font {name}: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// One font of a family, as the file it was read from and parsed for
+/// measuring and outlining glyphs.
+pub struct FamilyFont {
+    pub data: &'static [u8],
+    pub font: FontArc,
+}
+
+/// Index of the first of `fonts` with a glyph for `c`, the first font when
+/// none has one.
+pub fn font_for(fonts: &[FontArc], c: char) -> usize {
+    fonts
+        .iter()
+        .position(|font| font.glyph_id(c).0 != 0)
+        .unwrap_or(0)
+}
+
+fn load_definitions() -> FontDefinitions {
+    let mut fonts = FontDefinitions::default();
+    let Some((path, bytes)) = CJK_FONTS
+        .iter()
+        .find_map(|path| std::fs::read(path).ok().map(|bytes| (path, bytes)))
+    else {
+        log::warn!("No CJK font found, Chinese, Japanese and Korean text will not display");
+        return fonts;
+    };
+
+    fonts
+        .font_data
+        .insert("cjk".to_string(), Arc::new(FontData::from_owned(bytes)));
+    for family in [FontFamily::Proportional, FontFamily::Monospace] {
+        fonts
+            .families
+            .entry(family)
+            .or_default()
+            .push("cjk".to_string());
+    }
+    log::info!("Using {path} for CJK text");
+    fonts
+}
@@ -1,10 +1,4 @@
-﻿macro_rules! asset_path {
-    ($path:expr) => {
-        concat!(env!("CARGO_MANIFEST_DIR"), "/assets/", $path)
-    };
-}
-
-pub struct Icons {
+﻿pub struct Icons {
     pub close: egui::ImageSource<'static>,
     pub minimize: egui::ImageSource<'static>,
     pub maximize: egui::ImageSource<'static>,
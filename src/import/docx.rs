@@ -0,0 +1,121 @@
+﻿use super::{DocumentBuilder, attribute, read_entry, xml_error};
+use crate::document::DocumentMetadata;
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use zip::ZipArchive;
+
+const DOCUMENT: &str = "word/document.xml";
+const RELATIONSHIPS: &str = "word/_rels/document.xml.rels";
+
+/// Reads the main story of a Word document. Pictures are referenced through
+/// relationship ids, resolved against the document's relationships part.
+pub fn import(archive: &mut ZipArchive<fs::File>) -> io::Result<(String, DocumentMetadata)> {
+    let relationships = read_relationships(archive)?;
+    let xml = String::from_utf8_lossy(&read_entry(archive, DOCUMENT)?).into_owned();
+
+    let mut builder = DocumentBuilder::default();
+    let mut reader = Reader::from_str(&xml);
+    let mut in_run = false;
+    let mut in_text = false;
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"p" => builder.start_paragraph(),
+                b"r" => in_run = true,
+                b"t" => in_text = true,
+                // Pictures with extension children come as start tags
+                b"blip" | b"imagedata" => {
+                    add_image(archive, &mut builder, &relationships, &element)
+                }
+                _ => {}
+            },
+            Event::End(element) => match element.local_name().as_ref() {
+                b"r" => in_run = false,
+                b"t" => in_text = false,
+                b"p" => builder.end_paragraph(),
+                _ => {}
+            },
+            Event::Empty(element) => match element.local_name().as_ref() {
+                b"p" => {
+                    builder.start_paragraph();
+                    builder.end_paragraph();
+                }
+                // Tab stops in paragraph properties are also called tab
+                b"tab" if in_run => builder.text("\t"),
+                b"br" | b"cr" if in_run => builder.line_break(),
+                b"blip" | b"imagedata" => {
+                    add_image(archive, &mut builder, &relationships, &element)
+                }
+                _ => {}
+            },
+            Event::Text(text) if in_text => {
+                builder.text(&text.unescape().map_err(xml_error)?);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+/// Adds a DrawingML picture (`a:blip`) or legacy VML image (`v:imagedata`),
+/// looking up the embedded part it references.
+fn add_image(
+    archive: &mut ZipArchive<fs::File>,
+    builder: &mut DocumentBuilder,
+    relationships: &HashMap<String, String>,
+    element: &BytesStart,
+) {
+    let id = attribute(element, b"embed").or_else(|| attribute(element, b"id"));
+    if let Some(target) = id.and_then(|id| relationships.get(&id)) {
+        match read_entry(archive, target) {
+            Ok(bytes) => builder.image(bytes, target),
+            Err(err) => log::warn!("Skipping image {target}: {err}"),
+        }
+    }
+}
+
+/// Maps relationship ids to the archive paths of embedded parts.
+fn read_relationships(archive: &mut ZipArchive<fs::File>) -> io::Result<HashMap<String, String>> {
+    let xml = match read_entry(archive, RELATIONSHIPS) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(err) => {
+            log::warn!("Word document has no relationships, images are skipped: {err}");
+            return Ok(HashMap::new());
+        }
+    };
+
+    let mut relationships = HashMap::new();
+    let mut reader = Reader::from_str(&xml);
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"Relationship" =>
+            {
+                if attribute(&element, b"TargetMode").as_deref() == Some("External") {
+                    continue;
+                }
+                let (Some(id), Some(target)) =
+                    (attribute(&element, b"Id"), attribute(&element, b"Target"))
+                else {
+                    continue;
+                };
+
+                // Targets are relative to word/ unless they start at the root
+                let target = match target.strip_prefix('/') {
+                    Some(absolute) => absolute.to_string(),
+                    None => format!("word/{target}"),
+                };
+                relationships.insert(id, target);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(relationships)
+}
@@ -0,0 +1,128 @@
+﻿mod docx;
+mod odt;
+
+use crate::document::{DocumentMetadata, content_hash, image_dimensions, is_svg};
+use quick_xml::events::BytesStart;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Extensions of the word processor formats File → Import understands.
+pub const IMPORT_EXTENSIONS: [&str; 2] = ["docx", "odt"];
+
+/// Converts a DOCX or ODT document into Notula text, with each paragraph on
+/// its own line and each embedded picture on an image line.
+pub fn import(path: &Path) -> io::Result<(String, DocumentMetadata)> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+
+    match extension.as_deref() {
+        Some("docx") => docx::import(&mut archive),
+        Some("odt") => odt::import(&mut archive),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot import {}", path.display()),
+        )),
+    }
+}
+
+/// Collects paragraphs and pictures into lines of text and image metadata.
+#[derive(Default)]
+struct DocumentBuilder {
+    lines: Vec<String>,
+    line: String,
+    /// Paragraphs nest inside text boxes and frames.
+    paragraph_depth: usize,
+    /// Whether the current paragraph already produced lines through images or
+    /// nested paragraphs, so it needs no blank line of its own.
+    paragraph_lines: bool,
+    metadata: DocumentMetadata,
+}
+
+impl DocumentBuilder {
+    fn text(&mut self, text: &str) {
+        self.line.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        self.lines.push(std::mem::take(&mut self.line));
+    }
+
+    /// Adds a picture on a line of its own, after any text before it.
+    fn image(&mut self, bytes: Vec<u8>, name: &str) {
+        let Some((width, height)) =
+            image_dimensions(&bytes).or_else(|| is_svg(&bytes).then_some((0, 0)))
+        else {
+            log::warn!("Skipping unsupported image {name}");
+            return;
+        };
+
+        if !self.line.trim().is_empty() {
+            self.line_break();
+        }
+        self.line.clear();
+
+        let id = self.metadata.images.keys().max().map_or(1, |id| id + 1);
+        self.metadata
+            .add_image(id, &content_hash(&bytes), &bytes, width, height);
+        self.lines.push(format!("[image({id})]"));
+        self.paragraph_lines = true;
+    }
+
+    /// Text before a nested paragraph stays on a line of its own.
+    fn start_paragraph(&mut self) {
+        self.paragraph_depth += 1;
+        if self.paragraph_depth > 1 && !self.line.is_empty() {
+            self.line_break();
+            self.paragraph_lines = true;
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        self.paragraph_depth = self.paragraph_depth.saturating_sub(1);
+        if self.paragraph_depth > 0 {
+            if !self.line.is_empty() {
+                self.line_break();
+                self.paragraph_lines = true;
+            }
+            return;
+        }
+
+        if !self.paragraph_lines || !self.line.trim().is_empty() {
+            self.line_break();
+        }
+        self.line.clear();
+        self.paragraph_lines = false;
+    }
+
+    fn finish(mut self) -> (String, DocumentMetadata) {
+        if !self.line.is_empty() {
+            self.line_break();
+        }
+        (self.lines.join("\n"), self.metadata)
+    }
+}
+
+fn read_entry(archive: &mut ZipArchive<fs::File>, name: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Value of the attribute with local name `name`, whatever its namespace
+/// prefix.
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+fn xml_error(err: quick_xml::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
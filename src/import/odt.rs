@@ -0,0 +1,97 @@
+﻿use super::{DocumentBuilder, attribute, read_entry, xml_error};
+use crate::document::DocumentMetadata;
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::fs;
+use std::io;
+use zip::ZipArchive;
+
+const CONTENT: &str = "content.xml";
+
+/// Reads the body of an OpenDocument text file. Pictures are stored in the
+/// archive and referenced by path from `draw:image` elements.
+pub fn import(archive: &mut ZipArchive<fs::File>) -> io::Result<(String, DocumentMetadata)> {
+    let xml = String::from_utf8_lossy(&read_entry(archive, CONTENT)?).into_owned();
+
+    let mut builder = DocumentBuilder::default();
+    let mut reader = Reader::from_str(&xml);
+    // Comments, notes and picture descriptions sit inside paragraphs but are
+    // not part of the text
+    let mut skipped_depth = 0usize;
+    // A frame lists the same picture in several formats, the first is preferred
+    let mut frame_has_image = false;
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"p" | b"h" if skipped_depth == 0 => builder.start_paragraph(),
+                b"annotation" | b"note" | b"desc" | b"title" => skipped_depth += 1,
+                b"frame" => frame_has_image = false,
+                b"image" if skipped_depth == 0 && !frame_has_image => {
+                    frame_has_image = add_image(archive, &mut builder, &element);
+                }
+                _ => {}
+            },
+            Event::End(element) => match element.local_name().as_ref() {
+                b"p" | b"h" if skipped_depth == 0 => builder.end_paragraph(),
+                b"annotation" | b"note" | b"desc" | b"title" => {
+                    skipped_depth = skipped_depth.saturating_sub(1)
+                }
+                _ => {}
+            },
+            Event::Empty(element) if skipped_depth == 0 => match element.local_name().as_ref() {
+                b"p" | b"h" => {
+                    builder.start_paragraph();
+                    builder.end_paragraph();
+                }
+                b"s" => {
+                    let count = attribute(&element, b"c")
+                        .and_then(|count| count.parse().ok())
+                        .unwrap_or(1);
+                    builder.text(&" ".repeat(count));
+                }
+                b"tab" => builder.text("\t"),
+                b"line-break" => builder.line_break(),
+                b"image" if !frame_has_image => {
+                    frame_has_image = add_image(archive, &mut builder, &element);
+                }
+                _ => {}
+            },
+            Event::Text(text) if builder.paragraph_depth > 0 && skipped_depth == 0 => {
+                builder.text(&text.unescape().map_err(xml_error)?);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+/// Adds the picture a `draw:image` element points at, returning whether it
+/// was embedded in the archive.
+fn add_image(
+    archive: &mut ZipArchive<fs::File>,
+    builder: &mut DocumentBuilder,
+    element: &BytesStart,
+) -> bool {
+    let Some(href) = attribute(element, b"href") else {
+        return false;
+    };
+    if href.contains("://") {
+        log::warn!("Skipping linked image {href}");
+        return false;
+    }
+
+    let path = href.trim_start_matches("./");
+    match read_entry(archive, path) {
+        Ok(bytes) => {
+            builder.image(bytes, path);
+            true
+        }
+        Err(err) => {
+            log::warn!("Skipping image {path}: {err}");
+            false
+        }
+    }
+}
@@ -2,6 +2,10 @@ mod app;
 mod components;
 mod icons;
 mod assets;
+mod document;
+mod export;
+mod fonts;
+mod import;
 
 use app::NotepadApp;
 use eframe::egui;
@@ -22,6 +26,7 @@ fn main() -> eframe::Result {
         options,
         Box::new(|_cc| {
             egui_extras::install_image_loaders(&_cc.egui_ctx);
+            fonts::install_fallback_fonts(&_cc.egui_ctx);
             Ok(Box::<NotepadApp>::default())
         }),
     )